@@ -0,0 +1,41 @@
+//! How long writing an object to a file takes with each --write-buffer size, written the way a
+//! compressor writes it: in lots of small pieces
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+const OBJECT: usize = 64 * 1024 * 1024;
+const CHUNK: usize = 8 * 1024;
+
+async fn write(path: &std::path::Path, data: &[u8], buffer_size: usize) {
+    let mut file = File::create(path).await.unwrap();
+    // As process_object does, so tokio doesn't split up what the buffer hands it
+    file.set_max_buf_size(buffer_size);
+    let mut writer = BufWriter::with_capacity(buffer_size, file);
+    for chunk in data.chunks(CHUNK) {
+        writer.write_all(chunk).await.unwrap();
+    }
+    writer.flush().await.unwrap();
+}
+
+fn write_buffer(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let dir = std::env::temp_dir().join(format!("write-buffer-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("object");
+    let data: Vec<u8> = (0..OBJECT).map(|i| (i % 251) as u8).collect();
+
+    let mut group = c.benchmark_group("write_buffer");
+    group.throughput(Throughput::Bytes(OBJECT as u64));
+    group.sample_size(10);
+    for size in [64 * 1024, 1024 * 1024, 8 * 1024 * 1024, 32 * 1024 * 1024] {
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter(|| runtime.block_on(write(&path, &data, size)))
+        });
+    }
+    group.finish();
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+criterion_group!(benches, write_buffer);
+criterion_main!(benches);
@@ -0,0 +1,248 @@
+use crate::Error;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use clap::ValueEnum;
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ArchiveFormat {
+    Tar,
+    Zip, // Needs a file, it can't be streamed to stdout
+}
+
+/// The --archive being written, does nothing without it. Cheap to clone
+#[derive(Clone, Default)]
+pub struct Archive(Option<Arc<Mutex<Writer>>>);
+
+enum Writer {
+    Tar(tar::Builder<Box<dyn Write + Send>>),
+    Zip(Box<zip::ZipWriter<BufWriter<File>>>),
+    Finished, // Taken to write the end of it
+}
+
+impl Archive {
+    /// Everything downloaded from here on goes into the archive rather than a file of its own. "-"
+    /// writes it to stdout
+    pub fn create(format: ArchiveFormat, path: &Path) -> Result<Self, Error> {
+        let to_stdout = path.as_os_str() == "-";
+        let archive = match format {
+            ArchiveFormat::Tar => {
+                let writer: Box<dyn Write + Send> = if to_stdout {
+                    Box::new(BufWriter::new(std::io::stdout()))
+                } else {
+                    Box::new(BufWriter::new(File::create(path)?))
+                };
+                Writer::Tar(tar::Builder::new(writer))
+            }
+            ArchiveFormat::Zip if to_stdout => {
+                return Err(Error::IOError(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "a zip archive needs --archive-file",
+                )))
+            }
+            ArchiveFormat::Zip => Writer::Zip(Box::new(zip::ZipWriter::new(BufWriter::new(
+                File::create(path)?,
+            )))),
+        };
+        Ok(Archive(Some(Arc::new(Mutex::new(archive)))))
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Adds a file at `path` in the archive, a directory if the key it's from ends in "/". Zip
+    /// entries are deflated, tar ones are left for whatever it's piped into to compress
+    pub fn add(
+        &self,
+        path: &Path,
+        data: &[u8],
+        modified: Option<SystemTime>,
+        mode: Option<u32>,
+    ) -> std::io::Result<()> {
+        let Some(archive) = &self.0 else {
+            return Ok(());
+        };
+        let mut archive = archive.lock().unwrap_or_else(|e| e.into_inner());
+        let is_dir = path.to_string_lossy().ends_with('/');
+        let mtime = modified
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        match &mut *archive {
+            Writer::Tar(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(if is_dir { 0 } else { data.len() as u64 });
+                header.set_mtime(mtime);
+                if is_dir {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(mode.unwrap_or(0o755) & 0o777);
+                    builder.append_data(&mut header, path, std::io::empty())
+                } else {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_mode(mode.unwrap_or(0o644) & 0o777);
+                    builder.append_data(&mut header, path, data)
+                }
+            }
+            Writer::Zip(writer) => {
+                // Zip entries always use "/", whatever the platform
+                let name = path.to_string_lossy().replace('\\', "/");
+                let mut options = zip::write::SimpleFileOptions::default()
+                    .compression_method(zip::CompressionMethod::Deflated)
+                    // Zip64, without which an entry can't be 4 GiB or more
+                    .large_file(data.len() as u64 >= u32::MAX as u64);
+                if let Some(modified) = modified.and_then(zip_time) {
+                    options = options.last_modified_time(modified);
+                }
+                if is_dir {
+                    writer.add_directory(name, options.unix_permissions(mode.unwrap_or(0o755)))?;
+                } else {
+                    writer.start_file(name, options.unix_permissions(mode.unwrap_or(0o644)))?;
+                    writer.write_all(data)?;
+                }
+                Ok(())
+            }
+            Writer::Finished => Err(std::io::Error::other(
+                "the archive has already been finished",
+            )),
+        }
+    }
+
+    /// Writes the end of the archive, it isn't complete without it. Only the first call does
+    /// anything
+    pub fn finish(&self) -> std::io::Result<()> {
+        let Some(archive) = &self.0 else {
+            return Ok(());
+        };
+        let mut archive = archive.lock().unwrap_or_else(|e| e.into_inner());
+        match std::mem::replace(&mut *archive, Writer::Finished) {
+            Writer::Tar(mut builder) => {
+                builder.finish().and_then(|()| builder.get_mut().flush())?
+            }
+            Writer::Zip(writer) => writer.finish().map_err(std::io::Error::from)?.flush()?,
+            Writer::Finished => return Ok(()),
+        }
+        info!("Finished writing the archive");
+        Ok(())
+    }
+}
+
+// Zip times have no time zone (UTC is used) and only go from 1980 to 2107, ones outside that
+// are left out
+fn zip_time(modified: SystemTime) -> Option<zip::DateTime> {
+    let t = DateTime::<Utc>::from(modified);
+    zip::DateTime::from_date_and_time(
+        u16::try_from(t.year()).ok()?,
+        t.month() as u8,
+        t.day() as u8,
+        t.hour() as u8,
+        t.minute() as u8,
+        t.second() as u8,
+    )
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::time::Duration;
+
+    const MODIFIED: u64 = 1700000000; // 2023-11-14 22:13:20 UTC
+
+    fn write(format: ArchiveFormat, name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("s3dl-{}-{}", std::process::id(), name));
+        let archive = Archive::create(format, &path).unwrap();
+        let modified = Some(UNIX_EPOCH + Duration::from_secs(MODIFIED));
+        archive.add(Path::new("dir/"), b"", modified, None).unwrap();
+        archive
+            .add(Path::new("dir/a.txt"), b"hello", modified, Some(0o100600))
+            .unwrap();
+        archive.add(Path::new("b.txt"), b"", None, None).unwrap();
+        archive.finish().unwrap();
+        archive.finish().unwrap();
+        assert!(archive.add(Path::new("c.txt"), b"", None, None).is_err());
+        path
+    }
+
+    #[test]
+    fn tar_round_trip() {
+        let path = write(ArchiveFormat::Tar, "archive.tar");
+        let mut tar = tar::Archive::new(File::open(&path).unwrap());
+        let mut entries = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let header = entry.header().clone();
+            let mut data = String::new();
+            entry.read_to_string(&mut data).unwrap();
+            entries.push((
+                entry.path().unwrap().to_string_lossy().into_owned(),
+                header.entry_type(),
+                header.mode().unwrap(),
+                header.mtime().unwrap(),
+                data,
+            ));
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            entries,
+            [
+                (
+                    "dir/".into(),
+                    tar::EntryType::Directory,
+                    0o755,
+                    MODIFIED,
+                    "".into()
+                ),
+                (
+                    "dir/a.txt".into(),
+                    tar::EntryType::Regular,
+                    0o600,
+                    MODIFIED,
+                    "hello".into()
+                ),
+                ("b.txt".into(), tar::EntryType::Regular, 0o644, 0, "".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        let path = write(ArchiveFormat::Zip, "archive.zip");
+        let mut zip = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(zip.len(), 3);
+        assert!(zip.by_name("dir/").unwrap().is_dir());
+        let mut file = zip.by_name("dir/a.txt").unwrap();
+        let mut data = String::new();
+        file.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "hello");
+        assert_eq!(file.unix_mode().map(|m| m & 0o777), Some(0o600));
+        let modified = file.last_modified().unwrap();
+        assert_eq!(
+            (modified.year(), modified.month(), modified.day()),
+            (2023, 11, 14)
+        );
+        assert_eq!(
+            (modified.hour(), modified.minute(), modified.second()),
+            (22, 13, 20)
+        );
+        drop(file);
+        assert_eq!(zip.by_name("b.txt").unwrap().size(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn zip_needs_a_file() {
+        assert!(Archive::create(ArchiveFormat::Zip, Path::new("-")).is_err());
+    }
+
+    #[test]
+    fn zip_times() {
+        assert!(zip_time(UNIX_EPOCH).is_none());
+        assert!(zip_time(UNIX_EPOCH + Duration::from_secs(MODIFIED)).is_some());
+    }
+}
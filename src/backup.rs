@@ -0,0 +1,49 @@
+use crate::{overwrite, DownloadArgs};
+use std::path::{Path, PathBuf};
+
+/// Moves the file at `path` out of the way before `replacement` is put there, like rsync
+/// --backup: to <file><suffix> with --backup-suffix, or to the same place under --backup-dir.
+/// Nothing happens without either, when there's no file there yet, or when it's the same size
+/// and modified time as its replacement (i.e. it's from an earlier download of the same object)
+pub async fn keep(
+    path: &Path,
+    replacement: Option<&Path>,
+    download: &DownloadArgs,
+) -> std::io::Result<()> {
+    if download.backup_suffix.is_none() && download.backup_dir.is_none() {
+        return Ok(());
+    }
+    let Some(existing) = overwrite::existing(path).await else {
+        return Ok(());
+    };
+    if let Some(replacement) = replacement {
+        let (size, modified) = overwrite::existing(replacement)
+            .await
+            .map_or((None, None), |(s, m)| (Some(s as i64), Some(m)));
+        if overwrite::same(existing, size, modified) {
+            return Ok(());
+        }
+    }
+    let backup = backup_path(path, download);
+    if let Some(parent) = backup.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    // The backup directory could be on another filesystem
+    if tokio::fs::rename(path, &backup).await.is_err() {
+        tokio::fs::copy(path, &backup).await?;
+    }
+    Ok(())
+}
+
+fn backup_path(path: &Path, download: &DownloadArgs) -> PathBuf {
+    let mut backup = match &download.backup_dir {
+        Some(dir) => dir
+            .join(path.strip_prefix(&download.download_path).unwrap_or(path))
+            .into_os_string(),
+        None => path.as_os_str().to_owned(),
+    };
+    if let Some(suffix) = &download.backup_suffix {
+        backup.push(suffix);
+    }
+    PathBuf::from(backup)
+}
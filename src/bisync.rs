@@ -0,0 +1,575 @@
+use crate::bucket::local_bucket_dir;
+use crate::client::{create_client, ConnectionArgs};
+use crate::concurrency::{Concurrency, ConcurrencyArgs};
+use crate::context::Context;
+use crate::events::{Direction, Events};
+use crate::{
+    list_prefixes, process_object, shutdown, sidecar, upload_object, verify, Cli, DownloadArgs,
+    Error, Outcome, SourceArgs, UploadOptions,
+};
+use aws_sdk_s3::Client;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, ValueEnum};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tracing::{error, info, instrument, warn, Instrument};
+
+// What each side of a rename-both conflict is kept as
+const LOCAL_CONFLICT_SUFFIX: &str = ".local-conflict";
+const BUCKET_CONFLICT_SUFFIX: &str = ".bucket-conflict";
+
+#[derive(Args)]
+pub struct BisyncArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    #[command(flatten)]
+    download: DownloadArgs,
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+    #[arg(long, value_enum, default_value_t)]
+    conflict: Resolution, // What to do about a file changed both locally and in the bucket since the last sync
+    #[arg(long)]
+    conflict_report: Option<PathBuf>, // Also write the conflicts to this CSV file
+    #[arg(long, value_name = "PERCENT", default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=100))]
+    max_delete: u8, // Stop without changing anything if more than this much of either side would be deleted, e.g. after a disk wasn't mounted
+    #[arg(long)]
+    dry_run: bool, // Only print what would be copied and deleted
+}
+
+impl BisyncArgs {
+    /// A file saved under another name or with other contents than its object's would look
+    /// like a local change, and be uploaded as one
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        // Anything left out of the listing would look deleted from the bucket
+        if self.source.limit.is_some() {
+            return Err(Cli::command().error(
+                ErrorKind::ArgumentConflict,
+                "--limit can't be used with bisync, both sides have to be listed in full",
+            ));
+        }
+        match self.download.reshaping_args().next() {
+            Some(arg) => Err(Cli::command().error(
+                ErrorKind::ArgumentConflict,
+                format!("{arg} can't be used with bisync, files are synced under their keys as they are"),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Resolution {
+    #[default]
+    NewestWins, // Keep whichever was modified last
+    SourceWins, // Keep the bucket's
+    RenameBoth, // Keep both everywhere, as <key>.local-conflict and <key>.bucket-conflict
+}
+
+impl Resolution {
+    // As it's given on the command line
+    fn name(self) -> String {
+        self.to_possible_value()
+            .map(|v| v.get_name().to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// A file as it was on both sides at the end of the last sync, to tell which side has changed it
+/// since
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Synced {
+    etag: Option<String>,
+    size: u64,
+    modified: i64, // The local file's, in seconds
+}
+
+struct Remote {
+    etag: Option<String>,
+    size: u64,
+    modified: i64,
+}
+
+struct Local {
+    size: u64,
+    modified: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Action {
+    Download,
+    Upload,
+    DeleteLocal,
+    DeleteRemote,
+    RenameBoth,
+}
+
+impl Action {
+    // For --dry-run
+    fn describe(self) -> &'static str {
+        match self {
+            Action::Download => "download",
+            Action::Upload => "upload",
+            Action::DeleteLocal => "delete locally",
+            Action::DeleteRemote => "delete from the bucket",
+            Action::RenameBoth => "keep both copies of",
+        }
+    }
+}
+
+struct Conflict {
+    key: String,
+    local_modified: i64,
+    bucket_modified: i64,
+    resolution: Resolution,
+}
+
+/// Two-way sync between the bucket and its directory under the download path. Whatever changed
+/// on one side since the last run (kept in .bisync-<bucket>.json next to the directory) is
+/// copied or deleted on the other, files changed on both are settled by `--conflict`
+#[instrument(skip_all, fields(bucket = %args.source.bucket))]
+pub async fn bisync(args: BisyncArgs, events: Events) -> Result<(), Error> {
+    let BisyncArgs {
+        source,
+        download,
+        connection,
+        concurrency,
+        conflict,
+        conflict_report,
+        max_delete,
+        dry_run,
+    } = args;
+
+    info!("Setting up AWS client...");
+    let concurrency = Concurrency::new(&concurrency);
+    let context = Context::new(download.clone(), &source, concurrency.clone(), events)?;
+    shutdown::handle_signals(concurrency.clone(), context.partial_files.clone());
+    let client = create_client(
+        source.client_region(),
+        source.profile.clone(),
+        &connection,
+        &concurrency,
+    )
+    .await;
+
+    let dir = PathBuf::from(&download.download_path).join(local_bucket_dir(&source.bucket));
+    let state_path = PathBuf::from(&download.download_path)
+        .join(format!(".bisync-{}.json", local_bucket_dir(&source.bucket)));
+    let previous = read_state(&state_path).await?;
+    if previous.is_empty() {
+        info!("No earlier sync, files that differ on the two sides count as conflicts");
+    }
+
+    let remote = list_remote(&client, &source).await?;
+    let local = list_local(&dir, &source, &download)?;
+    info!(
+        "Found {} objects and {} local files",
+        remote.len(),
+        local.len()
+    );
+
+    let (actions, conflicts) = plan(&previous, &remote, &local, conflict);
+    report_conflicts(&conflicts, conflict_report.as_deref())?;
+    let counts = |wanted: fn(&Action) -> bool| actions.iter().filter(|(_, a)| wanted(a)).count();
+    info!(
+        "{} to download, {} to upload, {} to delete locally, {} to delete from the bucket, {} conflicts",
+        counts(|a| matches!(a, Action::Download)),
+        counts(|a| matches!(a, Action::Upload)),
+        counts(|a| matches!(a, Action::DeleteLocal)),
+        counts(|a| matches!(a, Action::DeleteRemote)),
+        conflicts.len()
+    );
+    // Files that can't be seen, on a disk that isn't mounted say, would all look deleted
+    let too_many = |deletes: usize, of: usize| deletes * 100 > of * usize::from(max_delete);
+    let local_deletes = counts(|a| matches!(a, Action::DeleteLocal));
+    let remote_deletes = counts(|a| matches!(a, Action::DeleteRemote));
+    for (deletes, of, side) in [
+        (local_deletes, local.len(), "locally"),
+        (remote_deletes, remote.len(), "from the bucket"),
+    ] {
+        if too_many(deletes, of) {
+            error!(
+                "Not syncing, {} of {} files would be deleted {} which is more than --max-delete {}%",
+                deletes, of, side, max_delete
+            );
+            return Err(Error::TooManyDeletions(deletes));
+        }
+    }
+    if dry_run {
+        for (key, action) in &actions {
+            info!("Would {}: {}", action.describe(), key);
+        }
+        return Ok(());
+    }
+
+    let options = UploadOptions {
+        checksum_algorithm: None,
+        metadata_sidecars: download.metadata_sidecars,
+        preserve: download.preserve,
+        symlinks: download.symlinks,
+        compress: None,
+    };
+    let mut tasks = Vec::new();
+    for (key, action) in actions {
+        let client = client.clone();
+        let bucket = source.bucket.clone();
+        let context = context.clone();
+        let dir = dir.clone();
+        tasks.push(tokio::spawn(
+            async move {
+                // Deletes and renames are quick, they count against the limit of whichever way
+                // data goes
+                let direction = match action {
+                    Action::Upload | Action::RenameBoth => Direction::Upload,
+                    _ => Direction::Download,
+                };
+                let Some(_permit) = context.concurrency.acquire(direction).await else {
+                    return (key, false);
+                };
+                let result = apply(&client, &bucket, &key, action, &dir, options, &context).await;
+                match result {
+                    Ok(done) => (key, done),
+                    Err(e) => {
+                        error!("Got an error syncing {}: {}", key, e);
+                        (key, false)
+                    }
+                }
+            }
+            .in_current_span(),
+        ));
+    }
+    let failed: BTreeSet<String> = join_all(tasks)
+        .await
+        .into_iter()
+        .filter_map(|r| r.ok())
+        .filter(|(_, done)| !done)
+        .map(|(key, _)| key)
+        .collect();
+    context.finish();
+
+    // Both sides again, uploads have new ETags and downloads new modified times. Files outside
+    // the listing keep what they had
+    let list_options = source.list_options();
+    let remote = list_remote(&client, &source).await?;
+    let local = list_local(&dir, &source, &download)?;
+    let mut state: BTreeMap<String, Synced> = previous
+        .iter()
+        .filter(|(key, _)| !list_options.covers(&source.prefix, key))
+        .map(|(key, synced)| (key.clone(), synced.clone()))
+        .collect();
+    for (key, r) in &remote {
+        // A file that didn't make it keeps what it had, so it's tried again next time
+        if failed.contains(key) {
+            if let Some(synced) = previous.get(key) {
+                state.insert(key.clone(), synced.clone());
+            }
+            continue;
+        }
+        if let Some(l) = local.get(key).filter(|l| l.size == r.size) {
+            state.insert(
+                key.clone(),
+                Synced {
+                    etag: r.etag.clone(),
+                    size: l.size,
+                    modified: l.modified,
+                },
+            );
+        }
+    }
+    write_state(&state_path, &state).await?;
+
+    if concurrency.stopped() {
+        return Err(Error::Stopped);
+    }
+    info!("Synced {} files, {} couldn't be", state.len(), failed.len());
+    Ok(())
+}
+
+/// What to do with each key that changed on either side since `previous`, and the conflicts
+/// among them
+fn plan(
+    previous: &BTreeMap<String, Synced>,
+    remote: &BTreeMap<String, Remote>,
+    local: &BTreeMap<String, Local>,
+    conflict: Resolution,
+) -> (Vec<(String, Action)>, Vec<Conflict>) {
+    let mut actions = Vec::new();
+    let mut conflicts = Vec::new();
+    let keys: BTreeSet<&String> = remote.keys().chain(local.keys()).collect();
+    for key in keys {
+        let (synced, remote, local) = (previous.get(key), remote.get(key), local.get(key));
+        // Downloads are given the object's modified time, so this is what one would look like
+        if let (Some(r), Some(l)) = (remote, local) {
+            if r.size == l.size && r.modified == l.modified {
+                continue;
+            }
+        }
+        let remote_changed = match (synced, remote) {
+            (Some(s), Some(r)) => s.etag != r.etag || s.size != r.size,
+            (None, None) => false,
+            _ => true,
+        };
+        let local_changed = match (synced, local) {
+            (Some(s), Some(l)) => s.size != l.size || s.modified != l.modified,
+            (None, None) => false,
+            _ => true,
+        };
+        let action = match (remote_changed, local_changed, remote, local) {
+            (false, false, _, _) | (_, _, None, None) => continue,
+            (true, false, Some(_), _) => Action::Download,
+            (true, false, None, _) => Action::DeleteLocal,
+            (false, true, _, Some(_)) => Action::Upload,
+            (false, true, _, None) => Action::DeleteRemote,
+            // Changed on one side and deleted on the other, the change is kept whatever --conflict says
+            (true, true, Some(_), None) => Action::Download,
+            (true, true, None, Some(_)) => Action::Upload,
+            (true, true, Some(r), Some(l)) => {
+                conflicts.push(Conflict {
+                    key: key.clone(),
+                    local_modified: l.modified,
+                    bucket_modified: r.modified,
+                    resolution: conflict,
+                });
+                match conflict {
+                    Resolution::NewestWins if l.modified > r.modified => Action::Upload,
+                    Resolution::NewestWins | Resolution::SourceWins => Action::Download,
+                    Resolution::RenameBoth => Action::RenameBoth,
+                }
+            }
+        };
+        actions.push((key.clone(), action));
+    }
+    (actions, conflicts)
+}
+
+/// Whether it was done, errors that have already been printed count as not done
+async fn apply(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    action: Action,
+    dir: &Path,
+    options: UploadOptions,
+    context: &Context,
+) -> Result<bool, Error> {
+    let path = dir.join(key);
+    match action {
+        Action::Download => {
+            let outcome = process_object(client, bucket, key, None, key, None, context).await?;
+            Ok(outcome == Outcome::Downloaded)
+        }
+        Action::Upload => {
+            upload_object(client, bucket, key, path, options, context).await?;
+            Ok(true)
+        }
+        Action::DeleteLocal => {
+            tokio::fs::remove_file(&path).await?;
+            if context.download.metadata_sidecars {
+                let _ = tokio::fs::remove_file(sidecar::path_for(&path)).await;
+            }
+            info!("Deleted locally: {}", key);
+            Ok(true)
+        }
+        Action::DeleteRemote => {
+            client
+                .delete_object()
+                .bucket(bucket)
+                .key(key)
+                .send()
+                .await?;
+            info!("Deleted from the bucket: {}", key);
+            Ok(true)
+        }
+        Action::RenameBoth => {
+            let local_key = format!("{key}{LOCAL_CONFLICT_SUFFIX}");
+            let bucket_key = format!("{key}{BUCKET_CONFLICT_SUFFIX}");
+            tokio::fs::rename(&path, dir.join(&local_key)).await?;
+            let local_path = dir.join(&local_key);
+            upload_object(client, bucket, &local_key, local_path, options, context).await?;
+            let outcome =
+                process_object(client, bucket, key, None, &bucket_key, None, context).await?;
+            if outcome != Outcome::Downloaded {
+                return Ok(false);
+            }
+            let bucket_path = dir.join(&bucket_key);
+            upload_object(client, bucket, &bucket_key, bucket_path, options, context).await?;
+            client
+                .delete_object()
+                .bucket(bucket)
+                .key(key)
+                .send()
+                .await?;
+            info!("Kept both as {} and {}", local_key, bucket_key);
+            Ok(true)
+        }
+    }
+}
+
+async fn list_remote(
+    client: &Client,
+    source: &SourceArgs,
+) -> Result<BTreeMap<String, Remote>, Error> {
+    info!("Obtaining list of {} objects...", source.bucket);
+    let objects = list_prefixes(
+        client,
+        &source.bucket,
+        &source.prefix,
+        source.list_options(),
+    )
+    .await?;
+    Ok(objects
+        .into_iter()
+        .filter_map(|o| {
+            let key = o.key?;
+            // Directory markers have no file to sync with
+            (!key.ends_with('/')).then(|| {
+                let remote = Remote {
+                    etag: o.e_tag,
+                    size: o.size.unwrap_or_default().max(0) as u64,
+                    modified: o.last_modified.map(|t| t.secs()).unwrap_or_default(),
+                };
+                (key, remote)
+            })
+        })
+        .collect())
+}
+
+// Only what the listing covers, a file deeper than --max-depth would look deleted from the bucket
+fn list_local(
+    dir: &Path,
+    source: &SourceArgs,
+    download: &DownloadArgs,
+) -> Result<BTreeMap<String, Local>, Error> {
+    let files = verify::local_files(dir, &source.prefix, source.list_options(), download)?;
+    Ok(files
+        .into_iter()
+        // Downloads in progress, or left by one that didn't finish
+        .filter(|(key, _)| !shutdown::is_part_file(Path::new(key)))
+        .filter_map(|(key, size)| {
+            let path = dir.join(&key);
+            let metadata = if download.symlinks {
+                path.symlink_metadata()
+            } else {
+                path.metadata()
+            };
+            let modified = metadata
+                .ok()?
+                .modified()
+                .ok()?
+                .duration_since(UNIX_EPOCH)
+                .ok()?
+                .as_secs() as i64;
+            Some((key, Local { size, modified }))
+        })
+        .collect())
+}
+
+async fn read_state(path: &Path) -> Result<BTreeMap<String, Synced>, Error> {
+    match tokio::fs::read(path).await {
+        Ok(json) => {
+            Ok(serde_json::from_slice(&json)
+                .map_err(|e| Error::IOError(std::io::Error::other(e)))?)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Replaced in one go, a half written state would make files look changed
+async fn write_state(path: &Path, state: &BTreeMap<String, Synced>) -> Result<(), Error> {
+    let json =
+        serde_json::to_vec_pretty(state).map_err(|e| Error::IOError(std::io::Error::other(e)))?;
+    let temp = path.with_extension("tmp");
+    tokio::fs::write(&temp, json).await?;
+    tokio::fs::rename(&temp, path).await?;
+    Ok(())
+}
+
+fn report_conflicts(conflicts: &[Conflict], report: Option<&Path>) -> Result<(), Error> {
+    let time = |secs: i64| {
+        DateTime::<Utc>::from_timestamp(secs, 0)
+            .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true))
+            .unwrap_or_default()
+    };
+    for c in conflicts {
+        warn!(
+            "Conflict: {} (modified {} locally, {} in the bucket), {}",
+            c.key,
+            time(c.local_modified),
+            time(c.bucket_modified),
+            c.resolution.name()
+        );
+    }
+    let Some(report) = report else {
+        return Ok(());
+    };
+    let mut writer = csv::Writer::from_path(report)?;
+    writer.write_record(["key", "local_modified", "bucket_modified", "resolution"])?;
+    for c in conflicts {
+        writer.write_record([
+            c.key.as_str(),
+            &time(c.local_modified),
+            &time(c.bucket_modified),
+            &c.resolution.name(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        source: SourceArgs,
+        #[command(flatten)]
+        download: DownloadArgs,
+    }
+
+    #[test]
+    fn files_below_a_top_level_listing_are_kept() {
+        let dir = std::env::temp_dir().join(format!("s3dl-bisync-{}", std::process::id()));
+        for path in ["a.txt", "sub/b.txt"] {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "a").unwrap();
+        }
+        let cli = Cli::parse_from(["s3dl", "-b", "bucket", "--no-recursive"]);
+        let local = list_local(&dir, &cli.source, &cli.download);
+        let _ = std::fs::remove_dir_all(&dir);
+        let local = local.unwrap();
+        assert_eq!(local.keys().collect::<Vec<_>>(), ["a.txt"]);
+
+        // Both were synced last time, only a.txt is listed with --no-recursive
+        let modified = local["a.txt"].modified;
+        let synced = Synced {
+            etag: Some("\"etag\"".to_string()),
+            size: 1,
+            modified,
+        };
+        let previous: BTreeMap<String, Synced> = ["a.txt", "sub/b.txt"]
+            .map(|key| (key.to_string(), synced.clone()))
+            .into();
+        let remote = BTreeMap::from([(
+            "a.txt".to_string(),
+            Remote {
+                etag: synced.etag.clone(),
+                size: 1,
+                modified,
+            },
+        )]);
+        let (actions, conflicts) = plan(&previous, &remote, &local, Resolution::NewestWins);
+        assert!(actions.is_empty());
+        assert!(conflicts.is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+use clap::Args;
+use std::collections::{HashMap, VecDeque};
+use std::io::IsTerminal;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, watch};
+use tracing::warn;
+
+// How many of the most recent requests the failure rate is worked out over
+const WINDOW: usize = 50;
+
+#[derive(Args, Clone)]
+pub struct BreakerArgs {
+    #[arg(long, default_value_t = 0.5, value_parser = parse_rate)]
+    max_failure_rate: f64, // Stop starting transfers once this fraction of recent requests failed, 1 to never stop
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) && rate > 0.0 => Ok(rate),
+        Ok(_) => Err("must be greater than 0 and at most 1".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Tripped, // Waiting to hear whether to carry on
+    Stopped,
+}
+
+/// Stops new transfers when most requests are failing for the same reason, e.g. credentials
+/// expiring or a bucket policy changing part way through a run
+#[derive(Clone, Debug)]
+pub struct Breaker {
+    max_failure_rate: f64,
+    recent: Arc<Mutex<VecDeque<Option<String>>>>, // None for a success, otherwise what went wrong
+    state: watch::Sender<State>,
+}
+
+impl Breaker {
+    pub fn new(args: &BreakerArgs) -> Self {
+        Breaker {
+            max_failure_rate: args.max_failure_rate,
+            recent: Arc::new(Mutex::new(VecDeque::with_capacity(WINDOW))),
+            state: watch::Sender::new(State::Running),
+        }
+    }
+
+    pub fn record(&self, failure: Option<String>) {
+        if self.max_failure_rate >= 1.0 || *self.state.borrow() != State::Running {
+            return;
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == WINDOW {
+            recent.pop_front();
+        }
+        recent.push_back(failure);
+        if recent.len() < WINDOW {
+            return;
+        }
+
+        let mut causes: HashMap<&str, usize> = HashMap::new();
+        for cause in recent.iter().flatten() {
+            *causes.entry(cause).or_default() += 1;
+        }
+        let failures: usize = causes.values().sum();
+        if (failures as f64) < self.max_failure_rate * WINDOW as f64 {
+            return;
+        }
+        let cause = causes
+            .into_iter()
+            .max_by_key(|(_, count)| *count)
+            .map(|(cause, _)| cause.to_string())
+            .unwrap_or_default();
+        recent.clear();
+        self.state.send_replace(State::Tripped);
+
+        warn!("!!! {} of the last {} requests failed", failures, WINDOW);
+        warn!("!!! Most of them with: {}", cause);
+        warn!("!!! No new transfers will be started");
+        let state = self.state.clone();
+        // On a thread of its own rather than a blocking task, which the runtime waits for when
+        // shutting down, so a Ctrl-C while asking would hang until enter was pressed
+        let (answer, answered) = oneshot::channel();
+        std::thread::spawn(move || {
+            let _ = answer.send(ask_to_carry_on());
+        });
+        tokio::spawn(async move {
+            let mut stopped = state.subscribe();
+            let carry_on = tokio::select! {
+                carry_on = answered => carry_on.unwrap_or(false),
+                _ = stopped.wait_for(|s| *s == State::Stopped) => return,
+            };
+            // Unless the run was stopped some other way in the meantime
+            state.send_if_modified(|s| {
+                if *s != State::Tripped {
+                    return false;
+                }
+                *s = if carry_on {
+                    State::Running
+                } else {
+                    State::Stopped
+                };
+                true
+            });
+        });
+    }
+
+    /// Waits out a trip, false once the run has been stopped
+    pub async fn wait(&self) -> bool {
+        let mut state = self.state.subscribe();
+        let running = match state.wait_for(|s| *s != State::Tripped).await {
+            Ok(s) => *s == State::Running,
+            Err(_) => false,
+        };
+        running
+    }
+
+    pub fn stop(&self) {
+        self.state.send_replace(State::Stopped);
+    }
+
+    pub async fn wait_until_stopped(&self) {
+        let mut state = self.state.subscribe();
+        let _ = state.wait_for(|s| *s == State::Stopped).await;
+    }
+
+    pub fn stopped(&self) -> bool {
+        *self.state.borrow() == State::Stopped
+    }
+}
+
+// Only asks when someone is there to answer, otherwise stopping is the safe choice
+fn ask_to_carry_on() -> bool {
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+    // On stderr so it never gets mixed into --output json
+    eprintln!("!!! Fix the problem and press enter to carry on, or type q and enter to stop");
+    let mut answer = String::new();
+    match std::io::stdin().read_line(&mut answer) {
+        Ok(_) => !answer.trim().eq_ignore_ascii_case("q"),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(max_failure_rate: f64) -> Breaker {
+        Breaker::new(&BreakerArgs { max_failure_rate })
+    }
+
+    fn record(breaker: &Breaker, failures: usize) {
+        for i in 0..WINDOW {
+            breaker.record((i < failures).then(|| "403 AccessDenied: ".to_string()));
+        }
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(parse_rate("0.2"), Ok(0.2));
+        assert_eq!(parse_rate("1"), Ok(1.0));
+        for rate in ["0", "1.5", "-0.1", "half"] {
+            assert!(parse_rate(rate).is_err(), "{rate}");
+        }
+    }
+
+    #[tokio::test]
+    async fn trips_once_too_many_requests_fail() {
+        let breaker = breaker(0.5);
+        record(&breaker, WINDOW / 2 - 1);
+        assert_eq!(*breaker.state.borrow(), State::Running);
+        record(&breaker, WINDOW / 2);
+        assert_eq!(*breaker.state.borrow(), State::Tripped);
+        assert!(!breaker.stopped());
+
+        breaker.stop();
+        assert!(!breaker.wait().await);
+    }
+
+    #[tokio::test]
+    async fn a_rate_of_one_never_trips() {
+        let breaker = breaker(1.0);
+        record(&breaker, WINDOW);
+        assert!(breaker.wait().await);
+    }
+}
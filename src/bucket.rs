@@ -0,0 +1,169 @@
+use std::str::FromStr;
+
+/// An `s3://<bucket>/<key>` argument, the key can be a prefix or left off. The bucket can be an
+/// access point ARN, e.g. `s3://arn:aws:s3:us-west-2:123456789012:accesspoint/my-ap/reports/`
+#[derive(Debug, Clone)]
+pub struct S3Url {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl FromStr for S3Url {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let Some(rest) = url.strip_prefix("s3://") else {
+            return Err(format!("expected s3://<bucket>/<key>, got {url}"));
+        };
+        // An access point ARN has a "/" of its own before the key, Outposts ones more than one
+        let skip = match rest.find("accesspoint/") {
+            Some(i) if is_arn(rest) => i + "accesspoint/".len(),
+            _ => 0,
+        };
+        let (bucket, key) = match rest[skip..].find('/') {
+            Some(i) => (&rest[..skip + i], &rest[skip + i + 1..]),
+            None => (rest, ""),
+        };
+        if bucket.is_empty() {
+            return Err(format!("no bucket in {url}"));
+        }
+        Ok(S3Url {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        })
+    }
+}
+
+/// S3 Express One Zone directory buckets are named `<name>--<az id>--x-s3`. The SDK takes care
+/// of their session based auth and zonal endpoints, but they can't be listed quite the same way
+/// and don't support versioning
+pub fn is_directory_bucket(bucket: &str) -> bool {
+    bucket.ends_with("--x-s3")
+}
+
+/// Access point ARNs, e.g. `arn:aws:s3:us-west-2:123456789012:accesspoint/my-ap`, can be used
+/// anywhere a bucket name can and the SDK routes the requests to the access point
+pub fn is_arn(bucket: &str) -> bool {
+    bucket.starts_with("arn:")
+}
+
+/// Multi-Region Access Point ARNs have no region, e.g.
+/// `arn:aws:s3::123456789012:accesspoint/mfzwi23gnjvgw.mrap`. Requests to them are signed with
+/// SigV4A and S3 routes each one to the closest bucket behind the access point
+pub fn is_multi_region_access_point(bucket: &str) -> bool {
+    is_arn(bucket) && arn_region(bucket).is_none() && bucket.ends_with(".mrap")
+}
+
+/// Region an access point ARN lives in, requests have to be made from that region
+pub fn arn_region(bucket: &str) -> Option<String> {
+    let region = bucket.strip_prefix("arn:")?.split(':').nth(2)?;
+    (!region.is_empty()).then(|| region.to_string())
+}
+
+/// Directory downloads from `bucket` are saved under, the access point name for an ARN as the
+/// whole thing is full of characters that don't belong in a path
+pub fn local_bucket_dir(bucket: &str) -> &str {
+    if is_arn(bucket) {
+        bucket.rsplit(['/', ':']).next().unwrap_or(bucket)
+    } else {
+        bucket
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACCESS_POINT: &str = "arn:aws:s3:us-west-2:123456789012:accesspoint/my-ap";
+    const MRAP: &str = "arn:aws:s3::123456789012:accesspoint/mfzwi23gnjvgw.mrap";
+    const OUTPOSTS: &str =
+        "arn:aws:s3-outposts:us-east-1:123456789012:outpost/op-01ac5d28a6a232904/accesspoint/my-ap";
+
+    #[test]
+    fn urls() {
+        let ap_url = format!("s3://{ACCESS_POINT}/reports/a.csv");
+        let ap_only = format!("s3://{ACCESS_POINT}");
+        let mrap_url = format!("s3://{MRAP}/");
+        let outposts_url = format!("s3://{OUTPOSTS}/a/b");
+        let cases = [
+            ("s3://my-bucket", "my-bucket", ""),
+            ("s3://my-bucket/", "my-bucket", ""),
+            ("s3://my-bucket/logs/", "my-bucket", "logs/"),
+            ("s3://my-bucket/logs/a.txt", "my-bucket", "logs/a.txt"),
+            (
+                "s3://my-bucket--usw2-az1--x-s3/a",
+                "my-bucket--usw2-az1--x-s3",
+                "a",
+            ),
+            (ap_url.as_str(), ACCESS_POINT, "reports/a.csv"),
+            (ap_only.as_str(), ACCESS_POINT, ""),
+            (mrap_url.as_str(), MRAP, ""),
+            (outposts_url.as_str(), OUTPOSTS, "a/b"),
+        ];
+        for (url, bucket, key) in cases {
+            let parsed: S3Url = url.parse().unwrap();
+            assert_eq!(
+                (parsed.bucket.as_str(), parsed.key.as_str()),
+                (bucket, key),
+                "{url}"
+            );
+        }
+        for url in [
+            "my-bucket/key",
+            "s3:/my-bucket",
+            "https://my-bucket/key",
+            "s3://",
+            "s3:///key",
+        ] {
+            assert!(url.parse::<S3Url>().is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn regions() {
+        let cases = [
+            (ACCESS_POINT, Some("us-west-2")),
+            (OUTPOSTS, Some("us-east-1")),
+            (MRAP, None),
+            ("my-bucket", None),
+            ("arn:aws:s3", None),
+            ("arn:", None),
+        ];
+        for (bucket, region) in cases {
+            assert_eq!(arn_region(bucket).as_deref(), region, "{bucket}");
+        }
+    }
+
+    #[test]
+    fn multi_region_access_points() {
+        let cases = [
+            (MRAP, true),
+            (ACCESS_POINT, false),
+            (OUTPOSTS, false),
+            ("my-bucket.mrap", false),
+            // A regional access point named like one
+            (
+                "arn:aws:s3:us-west-2:123456789012:accesspoint/a.mrap",
+                false,
+            ),
+        ];
+        for (bucket, mrap) in cases {
+            assert_eq!(is_multi_region_access_point(bucket), mrap, "{bucket}");
+        }
+    }
+
+    #[test]
+    fn local_dirs() {
+        let cases = [
+            ("my-bucket", "my-bucket"),
+            ("my-bucket--usw2-az1--x-s3", "my-bucket--usw2-az1--x-s3"),
+            (ACCESS_POINT, "my-ap"),
+            (MRAP, "mfzwi23gnjvgw.mrap"),
+            (OUTPOSTS, "my-ap"),
+            ("arn:aws:s3:::my-bucket", "my-bucket"),
+        ];
+        for (bucket, dir) in cases {
+            assert_eq!(local_bucket_dir(bucket), dir, "{bucket}");
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use crate::shutdown::{PartialFile, PartialFiles};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+/// With --cas the contents of every object go in objects/<sha256> under the download path,
+/// once however many keys (in however many buckets) have them. Which key has which is kept in
+/// the SHA256SUMS manifest, returns the digest for it
+pub async fn store(
+    download_path: &str,
+    data: &[u8],
+    sync: bool,
+    partial_files: &PartialFiles,
+) -> std::io::Result<String> {
+    let sum = format!("{:x}", Sha256::digest(data));
+    let path = PathBuf::from(download_path).join("objects").join(&sum);
+    if tokio::fs::try_exists(&path).await? {
+        return Ok(sum);
+    }
+    tokio::fs::create_dir_all(PathBuf::from(download_path).join("objects")).await?;
+    let partial = PartialFile::new(&path, partial_files);
+    let mut file = File::create(partial.part_path()).await?;
+    file.write_all(data).await?;
+    file.flush().await?;
+    if sync {
+        file.sync_all().await?;
+    }
+    drop(file);
+    partial.finish(sync).await?;
+    Ok(sum)
+}
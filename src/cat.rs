@@ -0,0 +1,52 @@
+use crate::bucket::{arn_region, S3Url};
+use crate::client::{create_client, ConnectionArgs};
+use crate::concurrency::{Concurrency, ConcurrencyArgs};
+use crate::{parse_range, Error};
+use clap::Args;
+use tokio::io::AsyncWriteExt;
+
+#[derive(Args)]
+pub struct CatArgs {
+    #[arg(required = true)]
+    urls: Vec<S3Url>, // s3://<bucket>/<key>, more than one are written one after the other
+    #[arg(long, value_parser = parse_range, allow_hyphen_values = true)]
+    range: Option<String>, // Only these bytes of each, e.g. 0-1023 to peek at the start of a big object
+    #[arg(short, long)]
+    profile: Option<String>,
+    #[arg(short, long)]
+    region: Option<String>,
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+}
+
+/// Writes the objects to stdout as they arrive, nothing is kept in memory or on disk
+pub async fn cat(args: CatArgs) -> Result<(), Error> {
+    let concurrency = Concurrency::new(&args.concurrency);
+    let region = args.region.or_else(|| arn_region(&args.urls[0].bucket));
+    let client = create_client(region, args.profile, &args.connection, &concurrency).await;
+    let mut stdout = tokio::io::stdout();
+
+    for url in &args.urls {
+        let mut body = client
+            .get_object()
+            .bucket(&url.bucket)
+            .key(&url.key)
+            .set_range(args.range.clone())
+            .send()
+            .await?
+            .body;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(std::io::Error::other)?;
+            match stdout.write_all(&chunk).await {
+                Ok(()) => {}
+                // Whatever it's piped into has seen enough, e.g. head
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    stdout.flush().await?;
+    Ok(())
+}
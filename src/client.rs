@@ -0,0 +1,393 @@
+use crate::concurrency::Concurrency;
+use crate::Error;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::profile::ProfileFileCredentialsProvider;
+use aws_config::timeout::TimeoutConfig;
+use aws_config::Region;
+use aws_sdk_s3::primitives::SdkBody;
+use aws_sdk_s3::Client;
+use aws_smithy_http_client::proxy::ProxyConfig;
+use aws_smithy_http_client::tls::{TlsContext, TrustStore};
+use aws_smithy_http_client::{tls, Builder, ConnectorBuilder};
+use aws_smithy_runtime_api::client::http::{
+    HttpClient, HttpConnector, HttpConnectorFuture, HttpConnectorSettings, SharedHttpClient,
+    SharedHttpConnector,
+};
+use aws_smithy_runtime_api::client::orchestrator::{HttpRequest, HttpResponse};
+use aws_smithy_runtime_api::client::result::ConnectorError;
+use aws_smithy_runtime_api::client::runtime_components::RuntimeComponents;
+use clap::Args;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// How to reach S3, shared by the download and upload clients
+#[derive(Args, Clone)]
+pub struct ConnectionArgs {
+    #[arg(long)]
+    accelerate: bool, // Use the bucket's transfer acceleration endpoint, it has to be enabled on the bucket
+    #[arg(long)]
+    use_dualstack: bool, // IPv4/IPv6 endpoints, for IPv6 only networks
+    #[arg(long)]
+    use_fips: bool, // FIPS 140-2 validated endpoints, e.g. in GovCloud
+    #[arg(long, value_parser = parse_proxy)]
+    proxy: Option<Proxy>, // Overrides HTTPS_PROXY/HTTP_PROXY, NO_PROXY is still honoured
+    #[arg(long, value_name = "PATH", value_parser = read_ca_bundle)]
+    ca_bundle: Option<CaBundle>, // PEM file of extra CAs to trust, e.g. for a self-hosted S3 compatible endpoint
+    #[arg(long)]
+    no_verify_ssl: bool, // Don't check certificates at all, only for testing against endpoints you control
+    #[arg(long, value_name = "SECONDS")]
+    connect_timeout: Option<u64>, // Establishing the TCP/TLS connection, the SDK defaults to 3.1s
+    #[arg(long, value_name = "SECONDS")]
+    read_timeout: Option<u64>, // Waiting for the first byte of a response
+    #[arg(long, value_name = "SECONDS")]
+    operation_timeout: Option<u64>, // A whole request including retries, but not streaming the object body
+    #[arg(long, value_name = "CONNECTIONS")]
+    pool_max_idle_per_host: Option<usize>, // Connections kept open between requests, raise it when running lots of transfers at once
+    #[arg(long, value_name = "SECONDS")]
+    pool_idle_timeout: Option<u64>, // How long an unused connection is kept alive, the default is 90s
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_requests_per_second: Option<u32>, // Per client, counting the SDK's retries, to stay clear of S3's per prefix limits
+}
+
+/// --proxy for the S3 clients and for the requests we send ourselves
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    sdk: ProxyConfig,
+    reqwest: reqwest::Proxy,
+}
+
+/// --ca-bundle, read once for both
+#[derive(Clone, Debug)]
+pub struct CaBundle {
+    tls_context: TlsContext,
+    certificates: Vec<reqwest::Certificate>,
+}
+
+fn parse_proxy(url: &str) -> Result<Proxy, String> {
+    let sdk = ProxyConfig::all(url).map_err(|e| e.to_string())?;
+    let sdk = match std::env::var("NO_PROXY").or_else(|_| std::env::var("no_proxy")) {
+        Ok(no_proxy) => sdk.no_proxy(no_proxy),
+        Err(_) => sdk,
+    };
+    let reqwest = reqwest::Proxy::all(url)
+        .map_err(|e| e.to_string())?
+        .no_proxy(reqwest::NoProxy::from_env());
+    Ok(Proxy { sdk, reqwest })
+}
+
+pub async fn create_client(
+    region: Option<String>,
+    profile_name: Option<String>,
+    connection: &ConnectionArgs,
+    concurrency: &Concurrency,
+) -> Client {
+    let region = get_region(region).await;
+    info!("Using region: {}", region);
+    let credentials_provider = create_credentials_provider(profile_name).await;
+    let mut loader = aws_config::from_env()
+        .credentials_provider(credentials_provider)
+        .region(region)
+        .http_client(rate_limited(http_client(connection), connection));
+    // Leave these alone unless asked so AWS_USE_FIPS_ENDPOINT etc. and profile settings still work
+    if connection.use_dualstack {
+        loader = loader.use_dual_stack(true);
+    }
+    if connection.use_fips {
+        loader = loader.use_fips(true);
+    }
+    // Anything not given keeps the SDK default
+    let mut timeouts = TimeoutConfig::builder();
+    timeouts
+        .set_connect_timeout(connection.connect_timeout.map(Duration::from_secs))
+        .set_read_timeout(connection.read_timeout.map(Duration::from_secs))
+        .set_operation_timeout(connection.operation_timeout.map(Duration::from_secs));
+    loader = loader.timeout_config(timeouts.build());
+    let config = loader.load().await;
+    let s3_config = aws_sdk_s3::config::Builder::from(&config)
+        .accelerate(connection.accelerate)
+        .interceptor(concurrency.interceptor())
+        .build();
+    Client::from_conf(s3_config)
+}
+
+fn read_ca_bundle(path: &str) -> Result<CaBundle, String> {
+    let pem = std::fs::read(path).map_err(|e| e.to_string())?;
+    let certificates = reqwest::Certificate::from_pem_bundle(&pem).map_err(|e| e.to_string())?;
+    if certificates.is_empty() {
+        return Err(format!("no PEM certificates in {path}"));
+    }
+    // Keep the system roots so real AWS endpoints still work alongside the private ones
+    let tls_context = TlsContext::builder()
+        .with_trust_store(TrustStore::default().with_pem_certificate(pem))
+        .build()
+        .map_err(|e| e.to_string())?;
+    Ok(CaBundle {
+        tls_context,
+        certificates,
+    })
+}
+
+/// For the requests we send ourselves (SQS, SNS and webhooks), with the same proxy, CA bundle,
+/// certificate checks, connect timeout and connection pool as the S3 clients. Not the read or
+/// operation timeouts, SQS holds a long poll open for longer than S3 takes to answer, or
+/// --max-requests-per-second, which only counts S3 requests
+pub fn reqwest_client(connection: &ConnectionArgs) -> Result<reqwest::Client, Error> {
+    let mut builder = reqwest::Client::builder()
+        .danger_accept_invalid_certs(connection.no_verify_ssl)
+        .pool_idle_timeout(connection.pool_idle_timeout.map(Duration::from_secs));
+    if let Some(proxy) = &connection.proxy {
+        builder = builder.proxy(proxy.reqwest.clone());
+    }
+    for certificate in connection.ca_bundle.iter().flat_map(|c| &c.certificates) {
+        builder = builder.add_root_certificate(certificate.clone());
+    }
+    if let Some(seconds) = connection.connect_timeout {
+        builder = builder.connect_timeout(Duration::from_secs(seconds));
+    }
+    if let Some(max_idle) = connection.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    Ok(builder.build()?)
+}
+
+// Same rustls client the SDK builds by default, but with our proxy and TLS settings
+fn http_client(connection: &ConnectionArgs) -> SharedHttpClient {
+    if connection.no_verify_ssl {
+        warn!("Warning: SSL certificates will not be verified");
+        let client = reqwest_client(connection).expect("the same settings as the S3 clients");
+        return SharedHttpClient::new(ReqwestHttpClient { client });
+    }
+    let proxy = connection
+        .proxy
+        .as_ref()
+        .map_or_else(ProxyConfig::from_env, |p| p.sdk.clone());
+    let tls_context = connection
+        .ca_bundle
+        .as_ref()
+        .map(|c| c.tls_context.clone())
+        .unwrap_or_default();
+    let pool_max_idle_per_host = connection.pool_max_idle_per_host;
+    let pool_idle_timeout = connection.pool_idle_timeout.map(Duration::from_secs);
+    Builder::new().build_with_connector_fn(move |settings, runtime_components| {
+        let mut builder = ConnectorBuilder::default().tls_provider(tls::Provider::Rustls(
+            tls::rustls_provider::CryptoMode::AwsLc,
+        ));
+        builder.set_connector_settings(settings.cloned());
+        if let Some(components) = runtime_components {
+            builder.set_sleep_impl(components.sleep_impl());
+        }
+        builder.set_proxy_config(Some(proxy.clone()));
+        builder.set_pool_max_idle_per_host(pool_max_idle_per_host);
+        builder.set_pool_idle_timeout(pool_idle_timeout.map(Some));
+        builder.tls_context(tls_context.clone()).build()
+    })
+}
+
+fn rate_limited(client: SharedHttpClient, connection: &ConnectionArgs) -> SharedHttpClient {
+    match connection.max_requests_per_second {
+        Some(rate) => SharedHttpClient::new(RateLimitedClient {
+            inner: client,
+            interval: Duration::from_secs(1) / rate,
+            next_slot: Arc::new(Mutex::new(Instant::now())),
+        }),
+        None => client,
+    }
+}
+
+/// Spaces requests out evenly so there are never more than the given number in any second
+#[derive(Debug, Clone)]
+struct RateLimitedClient {
+    inner: SharedHttpClient,
+    interval: Duration,
+    next_slot: Arc<Mutex<Instant>>, // Shared by every connector the client hands out
+}
+
+impl HttpClient for RateLimitedClient {
+    fn http_connector(
+        &self,
+        settings: &HttpConnectorSettings,
+        components: &RuntimeComponents,
+    ) -> SharedHttpConnector {
+        SharedHttpConnector::new(RateLimitedConnector {
+            inner: self.inner.http_connector(settings, components),
+            limiter: self.clone(),
+        })
+    }
+}
+
+impl RateLimitedClient {
+    async fn wait_for_slot(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[derive(Debug)]
+struct RateLimitedConnector {
+    inner: SharedHttpConnector,
+    limiter: RateLimitedClient,
+}
+
+impl HttpConnector for RateLimitedConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let inner = self.inner.clone();
+        let limiter = self.limiter.clone();
+        HttpConnectorFuture::new(async move {
+            limiter.wait_for_slot().await;
+            inner.call(request).await
+        })
+    }
+}
+
+/// --no-verify-ssl. The SDK's connector has no way to skip certificate checks, so S3 requests
+/// go through the same reqwest client as SQS, SNS and webhooks, with its proxy and pool settings
+#[derive(Debug, Clone)]
+struct ReqwestHttpClient {
+    client: reqwest::Client,
+}
+
+impl HttpClient for ReqwestHttpClient {
+    fn http_connector(
+        &self,
+        settings: &HttpConnectorSettings,
+        _components: &RuntimeComponents,
+    ) -> SharedHttpConnector {
+        SharedHttpConnector::new(ReqwestConnector {
+            client: self.client.clone(),
+            read_timeout: settings.read_timeout(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct ReqwestConnector {
+    client: reqwest::Client,
+    read_timeout: Option<Duration>, // Until the response starts, like the SDK's own
+}
+
+impl HttpConnector for ReqwestConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let client = self.client.clone();
+        let read_timeout = self.read_timeout;
+        HttpConnectorFuture::new(async move {
+            let request = request
+                .try_into_http1x()
+                .map_err(|e| ConnectorError::user(e.into()))?
+                .map(reqwest::Body::wrap);
+            let request =
+                reqwest::Request::try_from(request).map_err(|e| ConnectorError::user(e.into()))?;
+            let response = match read_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, client.execute(request))
+                    .await
+                    .map_err(|e| ConnectorError::timeout(e.into()))?,
+                None => client.execute(request).await,
+            };
+            let response = response.map_err(|e| match e {
+                e if e.is_timeout() => ConnectorError::timeout(e.into()),
+                e if e.is_connect() => ConnectorError::io(e.into()),
+                e => ConnectorError::other(e.into(), None),
+            })?;
+            let response = http::Response::from(response).map(SdkBody::from_body_1_x);
+            HttpResponse::try_from(response).map_err(|e| ConnectorError::other(e.into(), None))
+        })
+    }
+}
+
+pub async fn get_region(region: Option<String>) -> Region {
+    let default_region = RegionProviderChain::default_provider()
+        .region()
+        .await
+        .unwrap()
+        .to_string();
+    let region_str = region.unwrap_or(default_region);
+    Region::new(region_str)
+}
+
+pub async fn create_credentials_provider(
+    profile_name: Option<String>,
+) -> ProfileFileCredentialsProvider {
+    match profile_name {
+        Some(profile_name) => ProfileFileCredentialsProvider::builder()
+            .profile_name(profile_name)
+            .build(),
+        None => ProfileFileCredentialsProvider::builder().build(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_s3::primitives::ByteStream;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn requests_are_spaced_out() {
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            connection: ConnectionArgs,
+        }
+        let connection = Cli::parse_from(["s3dl"]).connection;
+        let limiter = RateLimitedClient {
+            inner: http_client(&connection),
+            interval: Duration::from_millis(10),
+            next_slot: Arc::new(Mutex::new(Instant::now())),
+        };
+        let start = Instant::now();
+        for _ in 0..11 {
+            limiter.wait_for_slot().await;
+        }
+        // The first goes straight away
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn no_verify_ssl_still_takes_the_proxy() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Stands in for the proxy, answering whatever it's asked itself
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let read = stream.read(&mut request).await.unwrap();
+            request.truncate(read);
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello";
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            connection: ConnectionArgs,
+        }
+        let args = ["s3dl", "--no-verify-ssl", "--proxy", &proxy];
+        let connection = Cli::parse_from(args).connection;
+        let connector = ReqwestConnector {
+            client: reqwest_client(&connection).unwrap(),
+            read_timeout: Some(Duration::from_secs(5)),
+        };
+        let request = HttpRequest::get("http://bucket.s3.example.com/key").unwrap();
+        let response = connector.call(request).await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        let body = ByteStream::new(response.into_body())
+            .collect()
+            .await
+            .unwrap();
+        assert_eq!(body.into_bytes(), "hello");
+        let request = server.await.unwrap();
+        assert!(
+            request.starts_with("GET http://bucket.s3.example.com/key "),
+            "{request}"
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use async_compression::tokio::{bufread, write::GzipEncoder, write::ZstdEncoder};
+use clap::ValueEnum;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// How downloads are compressed with --compress, each into a file of its own, and uploads with
+/// --compress-upload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    Zstd, // Saved as <key>.zst
+    Gzip, // Saved as <key>.gz
+}
+
+impl Compression {
+    /// Where the compressed file for what would have been saved at `path` goes
+    pub fn path(self, path: PathBuf) -> PathBuf {
+        let mut path = path.into_os_string();
+        path.push(match self {
+            Compression::Zstd => ".zst",
+            Compression::Gzip => ".gz",
+        });
+        PathBuf::from(path)
+    }
+
+    /// The Content-Encoding of an object uploaded compressed, which tells clients to decompress it
+    pub fn content_encoding(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    /// `input` compressed as it's read, for a stream with no end known up front
+    pub fn reader<R: AsyncRead + Unpin + Send + 'static>(
+        self,
+        input: R,
+    ) -> Box<dyn AsyncRead + Unpin + Send> {
+        let input = BufReader::new(input);
+        match self {
+            Compression::Zstd => Box::new(bufread::ZstdEncoder::new(input)),
+            Compression::Gzip => Box::new(bufread::GzipEncoder::new(input)),
+        }
+    }
+
+    /// Writes `data` compressed to `writer` as it goes, handing it back once it's all written
+    pub async fn write<W: AsyncWrite + Unpin>(self, writer: W, data: &[u8]) -> std::io::Result<W> {
+        // Shutting an encoder down writes out the end of the stream and flushes what's under it
+        match self {
+            Compression::Zstd => {
+                let mut encoder = ZstdEncoder::new(writer);
+                encoder.write_all(data).await?;
+                encoder.shutdown().await?;
+                Ok(encoder.into_inner())
+            }
+            Compression::Gzip => {
+                let mut encoder = GzipEncoder::new(writer);
+                encoder.write_all(data).await?;
+                encoder.shutdown().await?;
+                Ok(encoder.into_inner())
+            }
+        }
+    }
+}
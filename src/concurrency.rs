@@ -0,0 +1,470 @@
+use crate::breaker::{Breaker, BreakerArgs};
+use crate::events::Direction;
+use crate::memory::Memory;
+use crate::{stats, MAX_CONCURRENT_OPERATIONS};
+use aws_sdk_s3::config::interceptors::FinalizerInterceptorContextRef;
+use aws_sdk_s3::config::{ConfigBag, Intercept, RuntimeComponents};
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata};
+use aws_smithy_runtime_api::box_error::BoxError;
+use aws_smithy_runtime_api::client::result::SdkError;
+use aws_smithy_runtime_api::http::Response;
+use bytesize::ByteSize;
+use clap::Args;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+// Requests already in flight when S3 starts throttling will keep getting throttled for a
+// moment, only cut once for the whole burst
+const DECREASE_COOLDOWN: Duration = Duration::from_secs(2);
+
+// Once the SDK's own (sub second) retries give up on a slow down we keep trying for a couple of
+// minutes, S3 needs time to scale up the prefix
+const SLOW_DOWN_RETRIES: u32 = 6;
+const SLOW_DOWN_BASE_DELAY: Duration = Duration::from_secs(2);
+const SLOW_DOWN_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How many transfers run at once, it goes up while S3 keeps up and is halved when it throttles
+#[derive(Args, Clone)]
+pub struct ConcurrencyArgs {
+    #[arg(long, default_value_t = MAX_CONCURRENT_OPERATIONS)]
+    concurrency: usize, // Where to start
+    #[arg(long, default_value_t = 256)]
+    max_concurrency: usize, // Use the same value as --concurrency to never go above it
+    #[arg(long)]
+    download_concurrency: Option<usize>, // At most this many downloads at once, however high the overall limit goes
+    #[arg(long)]
+    upload_concurrency: Option<usize>, // At most this many uploads at once, however high the overall limit goes
+    #[arg(long)]
+    io_concurrency: Option<usize>, // At most this many files being written at once, for disks that slow down with more
+    #[arg(long, value_name = "SIZE")]
+    max_memory: Option<ByteSize>, // e.g. 1GiB, transfers wait for others to finish rather than hold more of their objects in memory than this between them
+    #[command(flatten)]
+    breaker: BreakerArgs,
+}
+
+/// AIMD controller handing out transfer permits, cheap to clone
+#[derive(Clone, Debug)]
+pub struct Concurrency {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    semaphore: Arc<Semaphore>,
+    downloads: Option<Arc<Semaphore>>,
+    uploads: Option<Arc<Semaphore>>,
+    max: usize,
+    state: Mutex<State>,
+    breaker: Breaker,
+    paused: watch::Sender<bool>,
+    memory: Memory,
+    io: Option<Arc<Semaphore>>, // Only with --io-concurrency
+}
+
+#[derive(Debug)]
+struct State {
+    limit: usize,
+    debt: usize, // Permits to take back from running transfers as they finish after a cut
+    successes: usize, // Since the limit last changed
+    last_decrease: Option<Instant>,
+}
+
+/// Held for the duration of a transfer
+pub struct Permit {
+    permit: Option<OwnedSemaphorePermit>,
+    _stage: Option<OwnedSemaphorePermit>,
+    concurrency: Concurrency,
+}
+
+impl Concurrency {
+    pub fn new(args: &ConcurrencyArgs) -> Self {
+        let limit = args.concurrency.max(1);
+        let stage = |limit: Option<usize>| limit.map(|l| Arc::new(Semaphore::new(l.max(1))));
+        Concurrency {
+            inner: Arc::new(Inner {
+                semaphore: Arc::new(Semaphore::new(limit)),
+                downloads: stage(args.download_concurrency),
+                uploads: stage(args.upload_concurrency),
+                max: args.max_concurrency.max(limit),
+                state: Mutex::new(State {
+                    limit,
+                    debt: 0,
+                    successes: 0,
+                    last_decrease: None,
+                }),
+                breaker: Breaker::new(&args.breaker),
+                paused: watch::Sender::new(false),
+                memory: Memory::new(args.max_memory),
+                io: stage(args.io_concurrency),
+            }),
+        }
+    }
+
+    /// None once the run has been stopped
+    pub async fn acquire(&self, direction: Direction) -> Option<Permit> {
+        if !self.ready().await {
+            return None;
+        }
+        let stage = match direction {
+            Direction::Download => &self.inner.downloads,
+            Direction::Upload => &self.inner.uploads,
+        };
+        // Before the overall permit, so waiting on a full stage doesn't keep one from another
+        let stage = match stage {
+            Some(stage) => Some(
+                stage
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("the semaphore is never closed"),
+            ),
+            None => None,
+        };
+        let permit = self
+            .inner
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        stats::transfer_started();
+        let permit = Permit {
+            permit: Some(permit),
+            _stage: stage,
+            concurrency: self.clone(),
+        };
+        // It could have been paused or stopped while we were waiting for a permit
+        if !self.ready().await {
+            return None;
+        }
+        Some(permit)
+    }
+
+    async fn ready(&self) -> bool {
+        let mut paused = self.inner.paused.subscribe();
+        // Only fails if the sender is gone, and we're holding it
+        let _ = paused.wait_for(|p| !p).await;
+        self.inner.breaker.wait().await
+    }
+
+    /// Running transfers carry on but no new ones start until `resume`
+    pub fn pause(&self) {
+        self.inner.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.inner.paused.send_replace(false);
+    }
+
+    /// Lets running transfers finish but doesn't start any more
+    pub fn stop(&self) {
+        self.inner.breaker.stop();
+        // Anything waiting on a pause needs to wake up to find out
+        self.resume();
+    }
+
+    /// Waits until `bytes` more of an object can be held in memory, see [`Memory::reserve`]
+    pub async fn reserve_memory(&self, bytes: u64) -> Option<OwnedSemaphorePermit> {
+        self.inner.memory.reserve(bytes).await
+    }
+
+    /// Held while a file is written, None unless --io-concurrency was given
+    pub async fn io_permit(&self) -> Option<OwnedSemaphorePermit> {
+        self.inner.io.clone()?.acquire_owned().await.ok()
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.inner.breaker.stopped()
+    }
+
+    /// How many transfers are allowed to run at once right now
+    pub fn limit(&self) -> usize {
+        self.inner.state.lock().unwrap().limit
+    }
+
+    pub async fn wait_until_stopped(&self) {
+        self.inner.breaker.wait_until_stopped().await
+    }
+
+    /// Hooks every request attempt made by a client, including the SDK's own retries, up to this
+    pub fn interceptor(&self) -> ThrottleSignal {
+        ThrottleSignal(self.clone())
+    }
+
+    fn succeeded(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        // Only grow when the limit is what's holding things back
+        if state.limit >= self.inner.max || self.inner.semaphore.available_permits() > 0 {
+            return;
+        }
+        state.successes += 1;
+        // Additive increase, about one more transfer per round of `limit` requests
+        if state.successes >= state.limit {
+            state.successes = 0;
+            state.limit += 1;
+            if state.debt > 0 {
+                state.debt -= 1;
+            } else {
+                self.inner.semaphore.add_permits(1);
+            }
+        }
+    }
+
+    fn throttled(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        if state
+            .last_decrease
+            .is_some_and(|t| t.elapsed() < DECREASE_COOLDOWN)
+        {
+            return;
+        }
+        let limit = (state.limit / 2).max(1);
+        let cut = state.limit - limit;
+        if cut == 0 {
+            return;
+        }
+        state.limit = limit;
+        state.successes = 0;
+        state.last_decrease = Some(Instant::now());
+        let forgotten = self.inner.semaphore.forget_permits(cut);
+        state.debt += cut - forgotten;
+        warn!(
+            "S3 is throttling requests, dropping to {} concurrent transfers",
+            limit
+        );
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        stats::transfer_finished();
+        let mut state = self.concurrency.inner.state.lock().unwrap();
+        if state.debt > 0 {
+            state.debt -= 1;
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ThrottleSignal(Concurrency);
+
+impl Intercept for ThrottleSignal {
+    fn name(&self) -> &'static str {
+        "ThrottleSignal"
+    }
+
+    fn read_after_attempt(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let Some(response) = context.response() else {
+            return Ok(());
+        };
+        let status = response.status().as_u16();
+        // Error bodies have been read into memory by now, HEAD responses don't have one
+        let code = response.body().bytes().and_then(|b| xml_value(b, "Code"));
+        if is_slow_down(status, code.as_deref()) {
+            self.0.throttled();
+        } else if status < 500 {
+            self.0.succeeded();
+        }
+        Ok(())
+    }
+
+    fn read_after_execution(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let failure = match (context.response(), context.output_or_error()) {
+            (Some(response), _) => {
+                let status = response.status().as_u16();
+                let body = response.body().bytes();
+                let code = body.and_then(|b| xml_value(b, "Code"));
+                // Slow downs have their own back off and a missing key is an answer, not a failure
+                if status < 400 || status == 404 || is_slow_down(status, code.as_deref()) {
+                    None
+                } else {
+                    let message = body.and_then(|b| xml_value(b, "Message"));
+                    Some(format!(
+                        "{} {}: {}",
+                        status,
+                        code.unwrap_or_default(),
+                        message.unwrap_or_default()
+                    ))
+                }
+            }
+            // Never got a response, e.g. the connection was refused or timed out
+            (None, Some(Err(e))) => Some(DisplayErrorContext(e).to_string()),
+            (None, _) => None,
+        };
+        self.0.inner.breaker.record(failure);
+        Ok(())
+    }
+}
+
+/// S3 telling us to back off, as opposed to a failure that's worth retrying straight away
+fn is_slow_down(status: u16, code: Option<&str>) -> bool {
+    match code {
+        Some(code) => matches!(code, "SlowDown" | "RequestLimitExceeded" | "Throttling"),
+        None => status == 503 || status == 429,
+    }
+}
+
+// Pulls e.g. the <Code> out of an S3 error response
+pub fn xml_value(body: &[u8], tag: &str) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?;
+    let open = format!("<{}>", tag);
+    let start = body.find(&open)? + open.len();
+    let end = start + body[start..].find(&format!("</{}>", tag))?;
+    Some(body[start..end].to_string())
+}
+
+/// Sends a request, and while S3 keeps asking to slow down after the SDK has given up, waits
+/// an exponentially growing, jittered delay before sending it again
+pub async fn retry_slow_down<T, E, F, Fut>(mut send: F) -> Result<T, SdkError<E, Response>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SdkError<E, Response>>>,
+    E: ProvideErrorMetadata,
+{
+    let mut attempt = 0;
+    loop {
+        match send().await {
+            Err(e) if attempt < SLOW_DOWN_RETRIES && is_slow_down_error(&e) => {
+                let ceiling = SLOW_DOWN_BASE_DELAY
+                    .saturating_mul(1 << attempt)
+                    .min(SLOW_DOWN_MAX_DELAY);
+                // Full jitter so everything that got throttled together doesn't come back together
+                tokio::time::sleep(ceiling.mul_f64(fastrand::f64())).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_slow_down_error<E: ProvideErrorMetadata>(err: &SdkError<E, Response>) -> bool {
+    match err.raw_response() {
+        Some(response) => is_slow_down(response.status().as_u16(), err.code()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn concurrency(args: &[&str]) -> Concurrency {
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            concurrency: ConcurrencyArgs,
+        }
+        let cli = Cli::parse_from(std::iter::once("s3dl").chain(args.iter().copied()));
+        Concurrency::new(&cli.concurrency)
+    }
+
+    #[tokio::test]
+    async fn limit_is_halved_when_throttled_and_grows_back() {
+        let concurrency = concurrency(&["--concurrency", "4", "--max-concurrency", "8"]);
+        concurrency.throttled();
+        assert_eq!(concurrency.limit(), 2);
+        // The rest of the same burst
+        concurrency.throttled();
+        assert_eq!(concurrency.limit(), 2);
+
+        // Only once the limit is what's holding transfers back
+        concurrency.succeeded();
+        let _first = concurrency.acquire(Direction::Download).await.unwrap();
+        let _second = concurrency.acquire(Direction::Download).await.unwrap();
+        concurrency.succeeded();
+        assert_eq!(concurrency.limit(), 2);
+        concurrency.succeeded();
+        assert_eq!(concurrency.limit(), 3);
+        assert_eq!(concurrency.inner.semaphore.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn running_transfers_give_back_what_was_cut() {
+        let concurrency = concurrency(&["--concurrency", "4"]);
+        let mut permits = Vec::new();
+        for _ in 0..4 {
+            permits.push(concurrency.acquire(Direction::Download).await.unwrap());
+        }
+        concurrency.throttled();
+        assert_eq!(concurrency.limit(), 2);
+        drop(permits);
+        assert_eq!(concurrency.inner.semaphore.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn never_past_the_max() {
+        let concurrency = concurrency(&["--concurrency", "2", "--max-concurrency", "2"]);
+        let _first = concurrency.acquire(Direction::Download).await.unwrap();
+        let _second = concurrency.acquire(Direction::Download).await.unwrap();
+        for _ in 0..10 {
+            concurrency.succeeded();
+        }
+        assert_eq!(concurrency.limit(), 2);
+    }
+
+    #[test]
+    fn slow_downs() {
+        assert!(is_slow_down(503, Some("SlowDown")));
+        assert!(is_slow_down(400, Some("Throttling")));
+        // HEAD responses have no body to say
+        assert!(is_slow_down(503, None));
+        assert!(is_slow_down(429, None));
+        assert!(!is_slow_down(503, Some("ServiceUnavailable")));
+        assert!(!is_slow_down(500, None));
+    }
+
+    #[test]
+    fn error_codes() {
+        let body = b"<Error><Code>SlowDown</Code><Message>Please reduce your request rate.</Message></Error>";
+        assert_eq!(xml_value(body, "Code").as_deref(), Some("SlowDown"));
+        assert_eq!(
+            xml_value(body, "Message").as_deref(),
+            Some("Please reduce your request rate.")
+        );
+        assert_eq!(xml_value(body, "RequestId"), None);
+        assert_eq!(xml_value(b"<Code>SlowDown", "Code"), None);
+    }
+
+    #[tokio::test]
+    async fn paused_transfers_wait_to_start() {
+        let concurrency = concurrency(&[]);
+        concurrency.pause();
+        let waiting = tokio::spawn({
+            let concurrency = concurrency.clone();
+            async move { concurrency.acquire(Direction::Upload).await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        concurrency.resume();
+        assert!(waiting.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn stopping_wakes_paused_transfers() {
+        let concurrency = concurrency(&[]);
+        concurrency.pause();
+        let waiting = tokio::spawn({
+            let concurrency = concurrency.clone();
+            async move { concurrency.acquire(Direction::Download).await.is_some() }
+        });
+        concurrency.stop();
+        assert!(!waiting.await.unwrap());
+    }
+}
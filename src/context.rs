@@ -0,0 +1,69 @@
+use crate::archive::Archive;
+use crate::concurrency::Concurrency;
+use crate::delete_source::DeleteSource;
+use crate::events::Events;
+use crate::layout::Paths;
+use crate::manifest::Manifest;
+use crate::rename::Rules;
+use crate::sanitize::Sanitizer;
+use crate::shutdown::PartialFiles;
+use crate::{DownloadArgs, Error, SourceArgs};
+use tracing::error;
+
+/// Everything downloading or uploading an object needs besides the object, built once for a run
+/// from its arguments and handed down to each transfer. Cheap to clone
+#[derive(Clone)]
+pub struct Context {
+    pub download: DownloadArgs,
+    pub concurrency: Concurrency,
+    pub events: Events,
+    pub paths: Paths,
+    pub archive: Archive,
+    pub manifest: Manifest,
+    pub delete_source: DeleteSource, // Only with --delete-source
+    pub rename: Rules,               // Only when uploading
+    pub partial_files: PartialFiles,
+}
+
+impl Context {
+    /// Opens the manifest, key map and archive `download` asks for, which are only complete once
+    /// `finish` has been called
+    pub fn new(
+        download: DownloadArgs,
+        source: &SourceArgs,
+        concurrency: Concurrency,
+        events: Events,
+    ) -> Result<Self, Error> {
+        let manifest = match download.sha256sums_path() {
+            Some(path) => Manifest::open(path, &download.download_path)?,
+            None => Manifest::default(),
+        };
+        let sanitizer = Sanitizer::new(download.sanitize, &download.download_path, &source.bucket)?;
+        let paths = Paths::new(download.layout.as_ref(), &source.prefix, sanitizer);
+        let archive = match download.archive {
+            Some(format) => Archive::create(format, &download.archive_file)?,
+            None => Archive::default(),
+        };
+        Ok(Context {
+            download,
+            concurrency,
+            events,
+            paths,
+            archive,
+            manifest,
+            delete_source: DeleteSource::default(),
+            rename: Rules::default(),
+            partial_files: PartialFiles::default(),
+        })
+    }
+
+    /// Writes out everything downloaded so far, called at the end of each run
+    pub fn finish(&self) {
+        self.manifest.write();
+        self.paths.sanitizer().write();
+        // Already done unless the run failed part way through
+        if let Err(e) = self.archive.finish() {
+            error!("Got an error finishing the archive: {}", e);
+        }
+    }
+}
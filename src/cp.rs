@@ -0,0 +1,123 @@
+use crate::bucket::{arn_region, S3Url};
+use crate::client::{create_client, ConnectionArgs};
+use crate::concurrency::{Concurrency, ConcurrencyArgs};
+use crate::shutdown::{PartialFile, PartialFiles};
+use crate::{put_file, stats, Error, UploadOptions};
+use aws_sdk_s3::types::ChecksumMode;
+use aws_sdk_s3::Client;
+use clap::Args;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+#[derive(Args)]
+pub struct CpArgs {
+    source: String,      // s3://<bucket>/<key> to download, or a file to upload
+    destination: String, // The file (or directory) to download to, or s3://<bucket>/<key> to upload to
+    #[arg(short, long)]
+    profile: Option<String>,
+    #[arg(short, long)]
+    region: Option<String>,
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+}
+
+/// Copies one object to or from a file of any name, without listing anything. A destination
+/// that's a directory (or ends in "/") keeps the source's name
+pub async fn cp(args: CpArgs) -> Result<(), Error> {
+    let (from, to) = (
+        args.source.parse::<S3Url>(),
+        args.destination.parse::<S3Url>(),
+    );
+    let bucket = match (&from, &to) {
+        (Ok(url), Err(_)) | (Err(_), Ok(url)) => url.bucket.clone(),
+        _ => {
+            return Err(Error::IOError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "one of the source and destination has to be an s3:// URL and the other a file",
+            )))
+        }
+    };
+    let concurrency = Concurrency::new(&args.concurrency);
+    let region = args.region.or_else(|| arn_region(&bucket));
+    let client = create_client(region, args.profile, &args.connection, &concurrency).await;
+
+    match (from, to) {
+        (Ok(from), _) => download(&client, &from, Path::new(&args.destination)).await,
+        (_, Ok(to)) => {
+            let path = PathBuf::from(&args.source);
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let key = if to.key.is_empty() || to.key.ends_with('/') {
+                format!("{}{}", to.key, name)
+            } else {
+                to.key.clone()
+            };
+            let options = UploadOptions::default();
+            let (bytes, _) =
+                put_file(&client, &to.bucket, &key, &path, options, &concurrency).await?;
+            stats::uploaded(bytes);
+            info!(
+                "Uploaded {} to s3://{}/{} ({} bytes)",
+                path.display(),
+                to.bucket,
+                key,
+                bytes
+            );
+            Ok(())
+        }
+        _ => unreachable!("checked above"),
+    }
+}
+
+async fn download(client: &Client, from: &S3Url, destination: &Path) -> Result<(), Error> {
+    let path = if destination.is_dir() || destination.to_string_lossy().ends_with('/') {
+        destination.join(from.key.rsplit('/').next().unwrap_or_default())
+    } else {
+        destination.to_path_buf()
+    };
+    let resp = client
+        .get_object()
+        .bucket(&from.bucket)
+        .key(&from.key)
+        .checksum_mode(ChecksumMode::Enabled)
+        .send()
+        .await?;
+    let last_modified = resp
+        .last_modified()
+        .and_then(|t| SystemTime::try_from(*t).ok());
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    // Written as it arrives, the same as any other download only renamed into place once
+    // complete. There's no Ctrl-C handling to remove it early, dropping it does
+    let partial = PartialFile::new(&path, &PartialFiles::default());
+    let mut file = File::create(partial.part_path()).await?;
+    let mut body = resp.body;
+    let mut bytes = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(std::io::Error::other)?;
+        file.write_all(&chunk).await?;
+        bytes += chunk.len();
+    }
+    file.flush().await?;
+    if let Some(modified) = last_modified {
+        file.into_std().await.set_modified(modified)?;
+    }
+    partial.finish(false).await?;
+    info!(
+        "Downloaded s3://{}/{} to {} ({} bytes)",
+        from.bucket,
+        from.key,
+        path.display(),
+        bytes
+    );
+    Ok(())
+}
@@ -0,0 +1,146 @@
+use crate::context::Context;
+use crate::events::Direction;
+use crate::shutdown::{PartialFile, PartialFiles};
+use crate::{backup, layout, symlink};
+use crate::{process_object, stats, Error, Outcome};
+use aws_sdk_s3::types::Object;
+use aws_sdk_s3::Client;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Keys whose objects have the same ETag and size as another in the run, with --dedup. The
+/// first of each to be downloaded is kept, the rest are hardlinked to it
+pub struct Duplicates {
+    groups: HashMap<String, Arc<Mutex<Option<PathBuf>>>>, // Key -> the copy shared by its group
+}
+
+impl Duplicates {
+    pub fn find(objects: &[Object], enabled: bool) -> Arc<Self> {
+        let mut by_content: HashMap<(&str, i64), Vec<&str>> = HashMap::new();
+        if enabled {
+            for object in objects {
+                // Empty objects, directory markers among them, have nothing to save
+                let (Some(key), Some(etag), Some(size @ 1..)) =
+                    (object.key(), object.e_tag(), object.size())
+                else {
+                    continue;
+                };
+                by_content.entry((etag, size)).or_default().push(key);
+            }
+        }
+        let mut groups = HashMap::new();
+        for keys in by_content.into_values().filter(|keys| keys.len() > 1) {
+            let group = Arc::new(Mutex::new(None));
+            for key in keys {
+                groups.insert(key.to_string(), group.clone());
+            }
+        }
+        if !groups.is_empty() {
+            info!(
+                "Found {} objects with the same contents as another",
+                groups.len()
+            );
+        }
+        Arc::new(Duplicates { groups })
+    }
+
+    /// Downloads `object` unless another with the same contents already has been, in which case
+    /// it's linked to that instead. Only one of a group is downloaded at a time, so the others
+    /// wait for it rather than fetching the same thing in parallel
+    pub async fn download(
+        &self,
+        client: &Client,
+        bucket: &str,
+        object: &Object,
+        local_key: &str,
+        context: &Context,
+    ) -> Result<Outcome, Error> {
+        let (download, events) = (&context.download, &context.events);
+        let key = object.key().unwrap_or_default();
+        let group = self.groups.get(key);
+        let mut first = match group {
+            Some(group) => Some(group.lock().await),
+            None => None,
+        };
+        let relative_path = context.paths.path(bucket, local_key);
+        let local_path = PathBuf::from(format!("{}/", download.download_path)).join(&relative_path);
+        // Left to process_object to turn down when it's outside the download path, or through a
+        // link to outside it
+        let through_link = download.symlinks
+            && symlink::through_link(Path::new(&download.download_path), &local_path)
+                .await
+                .unwrap_or(true);
+        let original = first.as_ref().and_then(|f| f.as_ref());
+        if let Some(original) =
+            original.filter(|_| !layout::escapes(&relative_path) && !through_link)
+        {
+            events.object_started(Direction::Download, bucket, key);
+            let linked = match backup::keep(&local_path, Some(original), download).await {
+                Ok(()) => {
+                    link(
+                        original,
+                        &local_path,
+                        download.fsync,
+                        &context.partial_files,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            match linked {
+                Ok(()) => {
+                    info!("Linked {} to {}", key, original.display());
+                    context.manifest.add_copy(&local_path, original);
+                    context.delete_source.linked(client, bucket, object).await;
+                    stats::skipped(1);
+                    events.object_skipped(Direction::Download, bucket, key, "duplicate");
+                    return Ok(Outcome::Downloaded);
+                }
+                // Downloading it is still an option
+                Err(e) => warn!(
+                    "Couldn't link {} to {}, downloading it instead: {}",
+                    key,
+                    original.display(),
+                    e
+                ),
+            }
+        }
+
+        let Some(_permit) = context.concurrency.acquire(Direction::Download).await else {
+            return Ok(Outcome::NotAttempted);
+        };
+        let outcome =
+            process_object(client, bucket, key, None, local_key, Some(object), context).await?;
+        if outcome == Outcome::Downloaded {
+            context.delete_source.downloaded(key);
+        }
+        if let Some(first) = first.as_mut().filter(|_| outcome == Outcome::Downloaded) {
+            first.get_or_insert(local_path);
+        }
+        Ok(outcome)
+    }
+}
+
+/// Puts a hardlink to `original` at `path`, or a copy where the filesystem has no hardlinks
+pub async fn link(
+    original: &Path,
+    path: &Path,
+    sync: bool,
+    partial_files: &PartialFiles,
+) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let partial = PartialFile::new(path, partial_files);
+    let _ = tokio::fs::remove_file(partial.part_path()).await;
+    if tokio::fs::hard_link(original, partial.part_path())
+        .await
+        .is_err()
+    {
+        tokio::fs::copy(original, partial.part_path()).await?;
+    }
+    partial.finish(sync).await
+}
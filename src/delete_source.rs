@@ -0,0 +1,201 @@
+use crate::{stats, Error};
+use aws_sdk_s3::types::{Delete, Object, ObjectIdentifier};
+use aws_sdk_s3::Client;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tracing::{error, info, warn};
+
+// DeleteObjects takes at most this many keys at once
+const DELETE_BATCH_SIZE: usize = 1000;
+
+/// Deletes source objects once they've been transferred with --delete-source, does nothing
+/// without it. Cheap to clone
+#[derive(Clone, Default)]
+pub struct DeleteSource(Option<Arc<Mutex<Pending>>>);
+
+/// Source objects that have been transferred this run, waiting to be deleted. Each by the
+/// version that was downloaded, so one written over in the meantime isn't deleted unseen
+#[derive(Default)]
+struct Pending {
+    uploading: bool, // Downloaded isn't enough, they have to have been uploaded too
+    fetched: HashMap<String, Option<String>>, // Version each GET returned, until it's been saved
+    downloaded: HashMap<String, Option<String>>,
+    uploaded: HashSet<String>,
+}
+
+impl DeleteSource {
+    pub fn new(uploading: bool) -> Self {
+        DeleteSource(Some(Arc::new(Mutex::new(Pending {
+            uploading,
+            ..Default::default()
+        }))))
+    }
+
+    /// The GET for `key` returned `version_id`, None when the bucket isn't versioned
+    pub fn fetched(&self, key: &str, version_id: Option<&str>) {
+        if let Some(pending) = &self.0 {
+            let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+            pending
+                .fetched
+                .insert(key.to_string(), version_id.map(str::to_string));
+        }
+    }
+
+    /// `key` has been downloaded, and its contents checked if it has a checksum
+    pub fn downloaded(&self, key: &str) {
+        if let Some(pending) = &self.0 {
+            let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+            let version_id = pending.fetched.remove(key).flatten();
+            pending.downloaded.insert(key.to_string(), version_id);
+        }
+    }
+
+    /// `object` was linked to a file with the same contents rather than downloaded. Its version
+    /// is looked up, as long as it still has the ETag it was listed with
+    pub async fn linked(&self, client: &Client, bucket: &str, object: &Object) {
+        if self.0.is_none() {
+            return;
+        }
+        let key = object.key().unwrap_or_default();
+        let head = client
+            .head_object()
+            .bucket(bucket)
+            .key(key)
+            .set_if_match(object.e_tag.clone())
+            .send()
+            .await;
+        match head {
+            Ok(head) => {
+                self.fetched(key, head.version_id());
+                self.downloaded(key);
+            }
+            Err(e) => warn!(
+                "Not deleting {}, it may have changed since it was listed: {}",
+                key,
+                Error::from(e)
+            ),
+        }
+    }
+
+    /// `key` has been uploaded, S3 having checked it against its Content-MD5
+    pub fn uploaded(&self, key: &str) {
+        if let Some(pending) = &self.0 {
+            let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+            pending.uploaded.insert(key.to_string());
+        }
+    }
+
+    /// Deletes everything transferred so far from the source bucket. Only objects downloaded by
+    /// this run count, a file left from an earlier one could be older than what's in the bucket
+    pub async fn flush(&self, client: &Client, bucket: &str) -> Result<(), Error> {
+        let keys = self.take_ready();
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        info!(
+            "Deleting {} transferred objects from {}...",
+            keys.len(),
+            bucket
+        );
+        let deleted = delete_keys(client, bucket, &keys).await?;
+        info!("Deleted {} objects from {}", deleted, bucket);
+        Ok(())
+    }
+
+    // What's ready to be deleted, by key and version, no longer pending
+    fn take_ready(&self) -> Vec<(String, Option<String>)> {
+        let Some(pending) = &self.0 else {
+            return Vec::new();
+        };
+        let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+        let ready: Vec<String> = pending
+            .downloaded
+            .keys()
+            .filter(|key| !pending.uploading || pending.uploaded.contains(*key))
+            .cloned()
+            .collect();
+        let mut keys = Vec::new();
+        for key in ready {
+            pending.uploaded.remove(&key);
+            if let Some(version_id) = pending.downloaded.remove(&key) {
+                keys.push((key, version_id));
+            }
+        }
+        keys
+    }
+}
+
+/// Deletes `keys` a batch at a time, each the given version or the latest, logging the ones
+/// that couldn't be and returning how many were
+pub async fn delete_keys(
+    client: &Client,
+    bucket: &str,
+    keys: &[(String, Option<String>)],
+) -> Result<usize, Error> {
+    let mut deleted = 0;
+    for batch in keys.chunks(DELETE_BATCH_SIZE) {
+        let objects = batch
+            .iter()
+            .map(|(key, version_id)| {
+                ObjectIdentifier::builder()
+                    .key(key)
+                    .set_version_id(version_id.clone())
+                    .build()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let delete = Delete::builder()
+            .set_objects(Some(objects))
+            .quiet(true)
+            .build()?;
+        let output = client
+            .delete_objects()
+            .bucket(bucket)
+            .delete(delete)
+            .send()
+            .await?;
+        // Quiet mode only lists the ones that failed
+        for e in output.errors() {
+            error!(
+                "Got an error deleting {}: {}",
+                e.key().unwrap_or_default(),
+                e.message().unwrap_or_default()
+            );
+            stats::failed(1);
+        }
+        deleted += batch.len() - output.errors().len();
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_version_downloaded_is_deleted() {
+        let delete_source = DeleteSource::new(false);
+        delete_source.fetched("a", Some("v1"));
+        delete_source.downloaded("a");
+        // Fetched again, but not saved yet
+        delete_source.fetched("a", Some("v2"));
+        delete_source.fetched("b", None);
+        delete_source.downloaded("b");
+        let mut ready = delete_source.take_ready();
+        ready.sort();
+        let v1 = Some("v1".to_string());
+        assert_eq!(ready, [("a".to_string(), v1), ("b".to_string(), None)]);
+        assert!(delete_source.take_ready().is_empty());
+    }
+
+    #[test]
+    fn uploads_have_to_finish_first() {
+        let delete_source = DeleteSource::new(true);
+        delete_source.fetched("a", Some("v1"));
+        delete_source.downloaded("a");
+        assert!(delete_source.take_ready().is_empty());
+        delete_source.uploaded("a");
+        let ready = delete_source.take_ready();
+        assert_eq!(ready, [("a".to_string(), Some("v1".to_string()))]);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::client::{create_client, ConnectionArgs};
+use crate::concurrency::{Concurrency, ConcurrencyArgs};
+use crate::events::{Events, OutputFormat};
+use crate::report::Report;
+use crate::{find_missing_items, inventory, Error, ListOptions, SourceArgs};
+use aws_sdk_s3::types::Object;
+use clap::Args;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+#[derive(Args)]
+pub struct DiffArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    #[arg(long)]
+    other_bucket: String, // The bucket to compare with, under the same prefixes
+    #[arg(long)]
+    other_profile: Option<String>,
+    #[arg(long)]
+    other_region: Option<String>,
+    #[arg(long, value_name = "URL")]
+    inventory_manifest: Option<String>, // s3://.../manifest.json of the bucket's S3 Inventory report, to compare instead of listing it
+    #[arg(long, value_name = "URL")]
+    other_inventory_manifest: Option<String>, // And of the other bucket's
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+    #[arg(long)]
+    size_only: bool, // Ignore ETags, which differ for the same contents uploaded in different sized parts
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat, // JSON prints one object per difference on stdout
+}
+
+/// Nothing is transferred, the keys only in one bucket or the other and the ones that differ are
+/// printed and it fails if there are any
+pub async fn diff(args: DiffArgs) -> Result<(), Error> {
+    let DiffArgs {
+        source,
+        other_bucket,
+        other_profile,
+        other_region,
+        inventory_manifest,
+        other_inventory_manifest,
+        connection,
+        concurrency,
+        size_only,
+        output,
+    } = args;
+    let events = Events::new(output, Report::default());
+
+    info!("Setting up AWS clients...");
+    let concurrency = Concurrency::new(&concurrency);
+    let client = create_client(
+        source.client_region(),
+        source.profile.clone(),
+        &connection,
+        &concurrency,
+    )
+    .await;
+    let other_client = create_client(other_region, other_profile, &connection, &concurrency).await;
+
+    info!("Obtaining list of {} objects...", source.bucket);
+    let objects = inventory::objects(
+        &client,
+        &source.bucket,
+        &source.prefix,
+        source.list_options(),
+        inventory_manifest.as_deref(),
+    )
+    .await?;
+    info!("Found {} objects", objects.len());
+    info!("Obtaining list of {} objects...", other_bucket);
+    // The limit only applies to the first bucket, the whole of the other is needed to diff against
+    let other_list_options = ListOptions {
+        limit: None,
+        ..source.list_options()
+    };
+    let other_objects = inventory::objects(
+        &other_client,
+        &other_bucket,
+        &source.prefix,
+        other_list_options,
+        other_inventory_manifest.as_deref(),
+    )
+    .await?;
+    info!("Found {} objects", other_objects.len());
+
+    info!("Diffing the results...");
+    let mut only_in_source: Vec<String> = find_missing_items(&objects, &other_objects)
+        .await
+        .into_iter()
+        .collect();
+    only_in_source.sort();
+    let mut only_in_other: Vec<String> = find_missing_items(&other_objects, &objects)
+        .await
+        .into_iter()
+        .collect();
+    only_in_other.sort();
+    let other: HashMap<&str, &Object> = other_objects
+        .iter()
+        .filter_map(|o| Some((o.key()?, o)))
+        .collect();
+    let mut differing = Vec::new();
+    for object in &objects {
+        let (Some(key), Some(other)) = (object.key(), object.key().and_then(|k| other.get(k)))
+        else {
+            continue;
+        };
+        if object.size() != other.size() {
+            let detail = format!(
+                "{} bytes, {} bytes",
+                object.size().unwrap_or_default(),
+                other.size().unwrap_or_default()
+            );
+            differing.push((key, detail));
+        } else if !size_only && object.e_tag() != other.e_tag() {
+            let detail = format!(
+                "ETag {}, ETag {}",
+                object.e_tag().unwrap_or_default(),
+                other.e_tag().unwrap_or_default()
+            );
+            differing.push((key, detail));
+        }
+    }
+
+    for key in &only_in_source {
+        events.difference("only_in_source", &source.bucket, key, None);
+        if !events.json() {
+            warn!("Only in {}: {}", source.bucket, key);
+        }
+    }
+    for key in &only_in_other {
+        events.difference("only_in_other", &other_bucket, key, None);
+        if !events.json() {
+            warn!("Only in {}: {}", other_bucket, key);
+        }
+    }
+    for (key, detail) in &differing {
+        events.difference("differs", &source.bucket, key, Some(detail));
+        if !events.json() {
+            warn!("Differs: {} ({})", key, detail);
+        }
+    }
+
+    info!(
+        "{} only in {}, {} only in {}, {} differ",
+        only_in_source.len(),
+        source.bucket,
+        only_in_other.len(),
+        other_bucket,
+        differing.len()
+    );
+    match only_in_source.len() + only_in_other.len() + differing.len() {
+        0 => Ok(()),
+        differences => Err(Error::BucketsDiffer(differences)),
+    }
+}
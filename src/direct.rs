@@ -0,0 +1,91 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Once;
+use tracing::warn;
+
+// What O_DIRECT writes have to line up with, covers disks with 512 byte and 4 KiB sectors
+const ALIGN: usize = 4096;
+
+static FALLBACK: Once = Once::new();
+
+/// Writes `data` to a new file at `path` without it going through the page cache, so a big sync
+/// doesn't push everything else out of it. Handed back along with the file, as it's written from
+/// a blocking thread
+pub async fn write(
+    path: &Path,
+    data: Vec<u8>,
+    chunk_size: usize,
+) -> (Vec<u8>, std::io::Result<tokio::fs::File>) {
+    let path = path.to_path_buf();
+    match tokio::task::spawn_blocking(move || {
+        let written = write_blocking(&path, &data, chunk_size);
+        (data, written)
+    })
+    .await
+    {
+        Ok((data, written)) => (data, written.map(tokio::fs::File::from_std)),
+        // Only if the thread panicked, which took the data with it
+        Err(e) => (Vec::new(), Err(std::io::Error::other(e))),
+    }
+}
+
+fn write_blocking(path: &Path, data: &[u8], chunk_size: usize) -> std::io::Result<File> {
+    let mut file = match open(path) {
+        Ok(file) => file,
+        // tmpfs and some network filesystems have no O_DIRECT, they get a normal write
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            FALLBACK.call_once(|| {
+                warn!("The filesystem doesn't support direct I/O, writing through the page cache")
+            });
+            let mut file = File::create(path)?;
+            file.write_all(data)?;
+            return Ok(file);
+        }
+        Err(e) => return Err(e),
+    };
+    // Each write comes from an aligned buffer and is a whole number of blocks, the end is cut
+    // off again afterwards
+    let chunk_size = chunk_size.next_multiple_of(ALIGN).max(ALIGN);
+    let mut raw = vec![0; chunk_size + ALIGN];
+    let offset = raw.as_ptr().align_offset(ALIGN);
+    let buffer = &mut raw[offset..offset + chunk_size];
+    for chunk in data.chunks(chunk_size) {
+        let padded = chunk.len().next_multiple_of(ALIGN);
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        buffer[chunk.len()..padded].fill(0);
+        file.write_all(&buffer[..padded])?;
+    }
+    file.set_len(data.len() as u64)?;
+    Ok(file)
+}
+
+#[cfg(target_os = "linux")]
+fn open(path: &Path) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+#[cfg(target_os = "macos")]
+fn open(path: &Path) -> std::io::Result<File> {
+    use std::os::fd::AsRawFd;
+    let file = File::create(path)?;
+    // macOS has no O_DIRECT, F_NOCACHE does the same for a file that's already open
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn open(_path: &Path) -> std::io::Result<File> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "direct I/O is only supported on Linux and macOS",
+    ))
+}
@@ -0,0 +1,70 @@
+use crate::bucket::{arn_region, S3Url};
+use crate::client::{create_client, ConnectionArgs};
+use crate::concurrency::{Concurrency, ConcurrencyArgs};
+use crate::notification::format_bytes;
+use crate::{list_prefixes, Error, ListOptions};
+use clap::Args;
+use std::collections::BTreeMap;
+
+#[derive(Args)]
+pub struct DuArgs {
+    url: S3Url, // s3://<bucket>/<prefix>, everything under the prefix is counted
+    #[arg(short, long)]
+    profile: Option<String>,
+    #[arg(short, long)]
+    region: Option<String>,
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    depth: u32, // How many levels of prefixes under the one given to total up separately
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+}
+
+/// One line per prefix on stdout with its total size and object count, then the total of them
+/// all. Objects less deep than --depth count towards the prefix they're in
+pub async fn du(args: DuArgs) -> Result<(), Error> {
+    let concurrency = Concurrency::new(&args.concurrency);
+    let region = args.region.or_else(|| arn_region(&args.url.bucket));
+    let client = create_client(region, args.profile, &args.connection, &concurrency).await;
+    let prefixes: Vec<String> = [args.url.key.clone()]
+        .into_iter()
+        .filter(|k| !k.is_empty())
+        .collect();
+    let objects =
+        list_prefixes(&client, &args.url.bucket, &prefixes, ListOptions::default()).await?;
+
+    let mut totals: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    for object in &objects {
+        let key = object.key().unwrap_or_default();
+        let relative = &key[args.url.key.len().min(key.len())..];
+        // Everything up to the depth'th "/", or the directory the key is in when it's not as deep
+        let end = relative
+            .match_indices('/')
+            .nth(args.depth as usize - 1)
+            .map(|(i, _)| i)
+            .or_else(|| relative.rfind('/'))
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let total = totals
+            .entry(format!("{}{}", args.url.key, &relative[..end]))
+            .or_default();
+        total.0 += object.size().unwrap_or_default().max(0) as u64;
+        total.1 += 1;
+    }
+
+    let mut all = (0, 0);
+    for (prefix, (bytes, count)) in &totals {
+        println!(
+            "{:>10}  {:>10} objects  s3://{}/{}",
+            format_bytes(*bytes),
+            count,
+            args.url.bucket,
+            prefix
+        );
+        all.0 += bytes;
+        all.1 += count;
+    }
+    println!("{:>10}  {:>10} objects  total", format_bytes(all.0), all.1);
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use crate::Error;
+use aws_sdk_s3::Client;
+use md5::{Digest, Md5};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// How many parts an object was uploaded in, from the "-N" on the end of its ETag, None if it
+/// was uploaded in one go
+pub fn parts(etag: &str) -> Option<u64> {
+    etag.trim_matches('"').rsplit_once('-')?.1.parse().ok()
+}
+
+/// Every part but the last is the same size, which is the size of part 1. Asking for it is the
+/// only way to know, different tools use different part sizes
+pub async fn part_size(client: &Client, bucket: &str, key: &str) -> Result<u64, Error> {
+    let head = client
+        .head_object()
+        .bucket(bucket)
+        .key(key)
+        .part_number(1)
+        .send()
+        .await?;
+    Ok(head.content_length().unwrap_or_default().max(0) as u64)
+}
+
+/// The ETag S3 gives the file's contents: the MD5 when uploaded in one go, or when uploaded in
+/// `part_size` parts the MD5 of all the parts' MD5s followed by "-" and how many parts there
+/// were. Objects encrypted with SSE-KMS or SSE-C have ETags that aren't either
+pub async fn local_etag(path: &Path, part_size: Option<u64>) -> std::io::Result<String> {
+    let mut file = File::open(path).await?;
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+    let Some(part_size) = part_size.filter(|s| *s > 0) else {
+        let mut hasher = Md5::new();
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        return Ok(format!("{:x}", hasher.finalize()));
+    };
+
+    let mut part_digests = Md5::new();
+    let mut parts = 0;
+    loop {
+        let mut part = Md5::new();
+        let mut remaining = part_size;
+        while remaining > 0 {
+            let want = buffer.len().min(remaining as usize);
+            let read = file.read(&mut buffer[..want]).await?;
+            if read == 0 {
+                break;
+            }
+            part.update(&buffer[..read]);
+            remaining -= read as u64;
+        }
+        if remaining == part_size {
+            break;
+        }
+        part_digests.update(part.finalize());
+        parts += 1;
+        if remaining > 0 {
+            break;
+        }
+    }
+    Ok(format!("{:x}-{}", part_digests.finalize(), parts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn part_counts() {
+        assert_eq!(parts("\"61e3716e3a7767581863b67c4e785584-3\""), Some(3));
+        assert_eq!(parts("781e5e245d69b566979b86e28d23f2c7"), None);
+        assert_eq!(parts("abc-def"), None);
+    }
+
+    #[tokio::test]
+    async fn local_etags() {
+        let path = std::env::temp_dir().join(format!("s3dl-etag-{}", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+        let single = local_etag(&path, None).await.unwrap();
+        let multipart = local_etag(&path, Some(4)).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(single, "781e5e245d69b566979b86e28d23f2c7");
+        assert_eq!(multipart, "61e3716e3a7767581863b67c4e785584-3");
+    }
+}
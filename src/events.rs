@@ -0,0 +1,134 @@
+use crate::notification::RunSummary;
+use crate::report::{Report, Status};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text, // Progress messages for people
+    Json, // One JSON object per event on stdout, messages move to stderr
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::Download => "download",
+            Direction::Upload => "upload",
+        }
+    }
+}
+
+/// Where what happens to each object goes, JSON on stdout with --output json and the CSV
+/// report with --report-csv. Cheap to clone
+#[derive(Clone, Default)]
+pub struct Events {
+    json: bool,
+    report: Report,
+}
+
+impl Events {
+    pub fn new(format: OutputFormat, report: Report) -> Self {
+        Events {
+            json: format == OutputFormat::Json,
+            report,
+        }
+    }
+
+    pub fn json(&self) -> bool {
+        self.json
+    }
+
+    pub fn object_started(&self, direction: Direction, bucket: &str, key: &str) {
+        self.report.started(direction, bucket, key);
+        self.emit(json!({
+            "event": "object_started",
+            "direction": direction.as_str(),
+            "bucket": bucket,
+            "key": key,
+        }));
+    }
+
+    pub fn object_completed(
+        &self,
+        direction: Direction,
+        bucket: &str,
+        key: &str,
+        bytes: u64,
+        etag: Option<&str>,
+    ) {
+        self.report
+            .finished(direction, bucket, key, Status::Completed(bytes, etag));
+        self.emit(json!({
+            "event": "object_completed",
+            "direction": direction.as_str(),
+            "bucket": bucket,
+            "key": key,
+            "bytes": bytes,
+            "etag": etag,
+        }));
+    }
+
+    /// Nothing was transferred but that's not a failure, e.g. the key's latest version is a
+    /// delete marker
+    pub fn object_skipped(&self, direction: Direction, bucket: &str, key: &str, reason: &str) {
+        self.report
+            .finished(direction, bucket, key, Status::Skipped(reason));
+        self.emit(json!({
+            "event": "object_skipped",
+            "direction": direction.as_str(),
+            "bucket": bucket,
+            "key": key,
+            "reason": reason,
+        }));
+    }
+
+    pub fn object_failed(
+        &self,
+        direction: Direction,
+        bucket: &str,
+        key: &str,
+        error: &dyn std::fmt::Display,
+    ) {
+        let error = error.to_string();
+        self.report
+            .finished(direction, bucket, key, Status::Failed(&error));
+        self.emit(json!({
+            "event": "object_failed",
+            "direction": direction.as_str(),
+            "bucket": bucket,
+            "key": key,
+            "error": error,
+        }));
+    }
+
+    /// A key that's only in one of the buckets being diffed, or that differs between them
+    pub fn difference(&self, kind: &str, bucket: &str, key: &str, detail: Option<&str>) {
+        self.emit(json!({
+            "event": "difference",
+            "difference": kind,
+            "bucket": bucket,
+            "key": key,
+            "detail": detail,
+        }));
+    }
+
+    pub fn run_summary(&self, summary: &RunSummary) {
+        let mut event = json!({ "event": "run_summary" });
+        if let (Some(event), Value::Object(summary)) = (event.as_object_mut(), summary.to_json()) {
+            event.extend(summary);
+        }
+        self.emit(event);
+    }
+
+    fn emit(&self, event: Value) {
+        if self.json {
+            println!("{}", event);
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use crate::concurrency::Concurrency;
+use crate::events::Direction;
+use crate::{stats, Error};
+use aws_sdk_s3::error::ProvideErrorMetadata;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use aws_sdk_s3::types::{
+    GlacierJobParameters, Object, ObjectStorageClass, RestoreRequest, StorageClass, Tier,
+};
+use aws_sdk_s3::Client;
+use clap::{Args, ValueEnum};
+use futures::future::join_all;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+#[derive(Args)]
+pub struct GlacierArgs {
+    #[arg(long)]
+    restore: bool, // Request restores for archived objects before downloading
+    #[arg(long, default_value_t = 1, requires = "restore")]
+    restore_days: i32,
+    #[arg(long, value_enum, default_value_t = RestoreTier::Standard, requires = "restore")]
+    restore_tier: RestoreTier,
+    #[arg(long, requires = "restore")]
+    wait_for_restore: bool, // Otherwise objects still being restored are skipped
+    #[arg(long, default_value_t = 300, requires = "wait_for_restore")]
+    restore_poll_seconds: u64,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum RestoreTier {
+    Expedited,
+    Standard,
+    Bulk,
+}
+
+impl From<RestoreTier> for Tier {
+    fn from(tier: RestoreTier) -> Self {
+        match tier {
+            RestoreTier::Expedited => Tier::Expedited,
+            RestoreTier::Standard => Tier::Standard,
+            RestoreTier::Bulk => Tier::Bulk,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RestoreState {
+    NotRestored,
+    InProgress,
+    Restored,
+}
+
+fn is_archived(object: &Object) -> bool {
+    matches!(
+        object.storage_class(),
+        Some(ObjectStorageClass::Glacier) | Some(ObjectStorageClass::DeepArchive)
+    )
+}
+
+fn is_intelligent_tiering(object: &Object) -> bool {
+    object.storage_class() == Some(&ObjectStorageClass::IntelligentTiering)
+}
+
+fn object_key(object: &Object) -> String {
+    object.key().unwrap_or_default().to_string()
+}
+
+/// With `--restore`, requests restores of the objects that can't be downloaded until they're
+/// restored, either because they're in GLACIER/DEEP_ARCHIVE or INTELLIGENT_TIERING has moved them
+/// to one of its archive access tiers. Returns the objects that are ready to download. Without it
+/// nothing is sent, those still archived are skipped when their download is turned down. The
+/// requests share the downloads' concurrency limits
+pub async fn restore_archived(
+    client: &Client,
+    bucket: &str,
+    objects: Vec<Object>,
+    args: &GlacierArgs,
+    concurrency: &Concurrency,
+) -> Result<Vec<Object>, Error> {
+    if !args.restore {
+        let archived = objects.iter().filter(|o| is_archived(o)).count();
+        if archived > 0 {
+            warn!(
+                "{} objects are in GLACIER/DEEP_ARCHIVE and will be skipped unless they have already been restored (see --restore)",
+                archived
+            );
+        }
+        return Ok(objects);
+    }
+    let (archived, ready): (Vec<_>, Vec<_>) = objects.into_iter().partition(is_archived);
+    let (tiered, mut ready): (Vec<_>, Vec<_>) = ready.into_iter().partition(is_intelligent_tiering);
+
+    // Only a HeadObject can tell us which tier an INTELLIGENT_TIERING object is currently in
+    let mut tiered_archived = Vec::new();
+    if !tiered.is_empty() {
+        info!(
+            "Checking the access tier of {} INTELLIGENT_TIERING objects...",
+            tiered.len()
+        );
+        let keys = tiered.iter().map(object_key).collect();
+        let states = restore_states(client, bucket, keys, concurrency).await;
+        for object in tiered {
+            match states.get(object.key().unwrap_or_default()) {
+                Some(RestoreState::Restored) | None => ready.push(object),
+                Some(_) => tiered_archived.push(object),
+            }
+        }
+    }
+    if archived.is_empty() && tiered_archived.is_empty() {
+        return Ok(ready);
+    }
+
+    let job_parameters = GlacierJobParameters::builder()
+        .tier(args.restore_tier.into())
+        .build()
+        .expect("tier is set");
+    let mut pending = HashSet::new();
+    if !archived.is_empty() {
+        info!(
+            "Requesting restores for {} GLACIER/DEEP_ARCHIVE objects...",
+            archived.len()
+        );
+        let request = RestoreRequest::builder()
+            .days(args.restore_days)
+            .glacier_job_parameters(job_parameters.clone())
+            .build();
+        pending.extend(
+            restore_pending(
+                client,
+                bucket,
+                archived.iter().map(object_key).collect(),
+                request,
+                concurrency,
+            )
+            .await,
+        );
+    }
+    if !tiered_archived.is_empty() {
+        info!(
+            "Requesting restores for {} archived INTELLIGENT_TIERING objects...",
+            tiered_archived.len()
+        );
+        // These go back to the frequent access tier rather than being a temporary copy, so S3
+        // rejects the request if it has a number of days
+        let request = RestoreRequest::builder()
+            .glacier_job_parameters(job_parameters)
+            .build();
+        let keys = tiered_archived.iter().map(object_key).collect();
+        pending.extend(restore_pending(client, bucket, keys, request, concurrency).await);
+    }
+
+    while args.wait_for_restore && !pending.is_empty() {
+        info!(
+            "Waiting for {} restores to complete, checking again in {}s...",
+            pending.len(),
+            args.restore_poll_seconds
+        );
+        tokio::time::sleep(Duration::from_secs(args.restore_poll_seconds)).await;
+        pending = restore_states(client, bucket, pending.into_iter().collect(), concurrency)
+            .await
+            .into_iter()
+            .filter(|(_, state)| *state != RestoreState::Restored)
+            .map(|(key, _)| key)
+            .collect();
+    }
+
+    if !pending.is_empty() {
+        warn!(
+            "Skipping {} objects that are still being restored, run again once they're available",
+            pending.len()
+        );
+        stats::skipped(pending.len() as u64);
+    }
+    ready.extend(
+        archived
+            .into_iter()
+            .chain(tiered_archived)
+            .filter(|o| !pending.contains(o.key().unwrap_or_default())),
+    );
+    Ok(ready)
+}
+
+/// Requests a restore of any of the keys that haven't been already. Returns the keys that aren't
+/// available yet
+async fn restore_pending(
+    client: &Client,
+    bucket: &str,
+    keys: Vec<String>,
+    request: RestoreRequest,
+    concurrency: &Concurrency,
+) -> HashSet<String> {
+    let mut tasks = Vec::new();
+
+    for (key, state) in restore_states(client, bucket, keys, concurrency).await {
+        let client = client.clone();
+        let bucket = bucket.to_string();
+        let concurrency = concurrency.clone();
+        let request = request.clone();
+
+        tasks.push(tokio::spawn(async move {
+            match state {
+                RestoreState::Restored => None,
+                RestoreState::InProgress => Some(key),
+                RestoreState::NotRestored => {
+                    let _permit = concurrency.acquire(Direction::Download).await?;
+                    match request_restore(&client, &bucket, &key, request).await {
+                        Ok(()) => Some(key),
+                        Err(e) => {
+                            error!("Got an error requesting a restore of {}: {}", key, e);
+                            None
+                        }
+                    }
+                }
+            }
+        }));
+    }
+
+    join_all(tasks)
+        .await
+        .into_iter()
+        .filter_map(|r| r.ok().flatten())
+        .collect()
+}
+
+/// Restore state of each key, keys that couldn't be checked (or weren't, once stopped) are left out
+async fn restore_states(
+    client: &Client,
+    bucket: &str,
+    keys: Vec<String>,
+    concurrency: &Concurrency,
+) -> HashMap<String, RestoreState> {
+    let mut tasks = Vec::new();
+
+    for key in keys {
+        let client = client.clone();
+        let bucket = bucket.to_string();
+        let concurrency = concurrency.clone();
+
+        tasks.push(tokio::spawn(async move {
+            let _permit = concurrency.acquire(Direction::Download).await?;
+            match restore_state(&client, &bucket, &key).await {
+                Ok(state) => Some((key, state)),
+                Err(e) => {
+                    error!("Got an error checking the restore status of {}: {}", key, e);
+                    None
+                }
+            }
+        }));
+    }
+
+    join_all(tasks)
+        .await
+        .into_iter()
+        .filter_map(|r| r.ok().flatten())
+        .collect()
+}
+
+async fn restore_state(client: &Client, bucket: &str, key: &str) -> Result<RestoreState, Error> {
+    let head = client.head_object().bucket(bucket).key(key).send().await?;
+    Ok(RestoreState::from(&head))
+}
+
+impl From<&HeadObjectOutput> for RestoreState {
+    fn from(head: &HeadObjectOutput) -> Self {
+        // e.g. `ongoing-request="false", expiry-date="Fri, 21 Dec 2012 00:00:00 GMT"`
+        match head.restore() {
+            Some(r) if r.contains("ongoing-request=\"true\"") => RestoreState::InProgress,
+            Some(_) => RestoreState::Restored,
+            // INTELLIGENT_TIERING objects are only unavailable while in an archive access tier
+            None if head.storage_class() == Some(&StorageClass::IntelligentTiering) => {
+                match head.archive_status() {
+                    Some(_) => RestoreState::NotRestored,
+                    None => RestoreState::Restored,
+                }
+            }
+            None => RestoreState::NotRestored,
+        }
+    }
+}
+
+async fn request_restore(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    request: RestoreRequest,
+) -> Result<(), Error> {
+    match client
+        .restore_object()
+        .bucket(bucket)
+        .key(key)
+        .restore_request(request)
+        .send()
+        .await
+    {
+        Ok(_) => Ok(()),
+        // Someone else got in first, which is just as good
+        Err(e) if e.code() == Some("RestoreAlreadyInProgress") => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_s3::types::ArchiveStatus;
+
+    #[test]
+    fn states_from_head() {
+        let head = |restore: Option<&str>, class: Option<StorageClass>, archived: bool| {
+            HeadObjectOutput::builder()
+                .set_restore(restore.map(str::to_string))
+                .set_storage_class(class)
+                .set_archive_status(archived.then_some(ArchiveStatus::DeepArchiveAccess))
+                .build()
+        };
+        let state = |head: HeadObjectOutput| RestoreState::from(&head);
+
+        let glacier = Some(StorageClass::Glacier);
+        assert_eq!(
+            state(head(None, glacier.clone(), false)),
+            RestoreState::NotRestored
+        );
+        let ongoing = Some("ongoing-request=\"true\"");
+        assert_eq!(
+            state(head(ongoing, glacier.clone(), false)),
+            RestoreState::InProgress
+        );
+        let done = Some("ongoing-request=\"false\", expiry-date=\"Fri, 21 Dec 2012 00:00:00 GMT\"");
+        assert_eq!(state(head(done, glacier, false)), RestoreState::Restored);
+
+        let tiered = Some(StorageClass::IntelligentTiering);
+        assert_eq!(
+            state(head(None, tiered.clone(), false)),
+            RestoreState::Restored
+        );
+        assert_eq!(
+            state(head(None, tiered.clone(), true)),
+            RestoreState::NotRestored
+        );
+        assert_eq!(state(head(ongoing, tiered, true)), RestoreState::InProgress);
+    }
+}
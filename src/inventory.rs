@@ -0,0 +1,303 @@
+use crate::bucket::S3Url;
+use crate::{list_prefixes, Error, ListOptions};
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
+use aws_sdk_s3::types::{Object, ObjectStorageClass};
+use aws_sdk_s3::Client;
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use tracing::{info, instrument, warn};
+
+// Data files fetched at once, each is up to a few hundred MB
+const CONCURRENT_FILES: usize = 8;
+
+/// The manifest.json S3 Inventory writes alongside each report
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    source_bucket: String,
+    destination_bucket: String, // arn:aws:s3:::<bucket>, where the data files are
+    file_format: String,
+    #[serde(default)]
+    file_schema: String, // The CSV columns in order, Parquet files name their own
+    files: Vec<DataFile>,
+}
+
+#[derive(Deserialize)]
+struct DataFile {
+    key: String,
+}
+
+/// One row of a report, whichever format it's in
+#[derive(Default)]
+struct Entry {
+    key: Option<String>,
+    size: Option<i64>,
+    etag: Option<String>,
+    last_modified: Option<DateTime>,
+    storage_class: Option<String>,
+    is_latest: Option<bool>, // Only there when the report includes every version
+    is_delete_marker: Option<bool>,
+}
+
+impl Entry {
+    fn set_text(&mut self, column: &str, value: &str) {
+        if value.is_empty() {
+            return;
+        }
+        match column {
+            // CSV keys are URL encoded, with spaces as "+"
+            "key" => {
+                let key = value.replace('+', " ");
+                self.key = Some(percent_decode_str(&key).decode_utf8_lossy().to_string());
+            }
+            "size" => self.size = value.parse().ok(),
+            "etag" => self.etag = Some(value.to_string()),
+            "lastmodifieddate" => {
+                self.last_modified = DateTime::from_str(value, DateTimeFormat::DateTime).ok()
+            }
+            "storageclass" => self.storage_class = Some(value.to_string()),
+            "islatest" => self.is_latest = value.parse().ok(),
+            "isdeletemarker" => self.is_delete_marker = value.parse().ok(),
+            _ => {}
+        }
+    }
+
+    fn set_field(&mut self, column: &str, value: &Field) {
+        match (column, value) {
+            ("key", Field::Str(key)) => self.key = Some(key.clone()),
+            ("size", Field::Long(size)) => self.size = Some(*size),
+            ("lastmodifieddate", Field::TimestampMillis(millis)) => {
+                self.last_modified = Some(DateTime::from_millis(*millis))
+            }
+            ("islatest", Field::Bool(latest)) => self.is_latest = Some(*latest),
+            ("isdeletemarker", Field::Bool(marker)) => self.is_delete_marker = Some(*marker),
+            (_, Field::Str(value)) => self.set_text(column, value),
+            _ => {}
+        }
+    }
+
+    /// The object as ListObjectsV2 would have returned it, None for older versions and delete
+    /// markers
+    fn into_object(self) -> Option<Object> {
+        if self.is_latest == Some(false) || self.is_delete_marker == Some(true) {
+            return None;
+        }
+        Some(
+            Object::builder()
+                .key(self.key?)
+                .set_size(self.size)
+                // Listings quote the ETag, the report doesn't
+                .set_e_tag(self.etag.map(|etag| format!("\"{etag}\"")))
+                .set_last_modified(self.last_modified)
+                .set_storage_class(self.storage_class.as_deref().map(ObjectStorageClass::from))
+                .build(),
+        )
+    }
+}
+
+// "LastModifiedDate" in a CSV schema and "last_modified_date" in Parquet are the same column
+fn column_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// The objects under `prefixes`, read from the inventory report if there is one and listed
+/// otherwise
+pub async fn objects(
+    client: &Client,
+    bucket: &str,
+    prefixes: &[String],
+    options: ListOptions,
+    manifest_url: Option<&str>,
+) -> Result<Vec<Object>, Error> {
+    match manifest_url {
+        Some(manifest_url) => list(client, manifest_url, bucket, prefixes, options).await,
+        None => list_prefixes(client, bucket, prefixes, options).await,
+    }
+}
+
+/// Lists the objects in an S3 Inventory report instead of the bucket, given the s3:// URL of its
+/// manifest.json. Reports of every version only give the latest of each key
+#[instrument(skip_all, fields(manifest = %manifest_url))]
+pub async fn list(
+    client: &Client,
+    manifest_url: &str,
+    bucket: &str,
+    prefixes: &[String],
+    options: ListOptions,
+) -> Result<Vec<Object>, Error> {
+    let manifest_location: S3Url = manifest_url.parse().map_err(Error::InventoryError)?;
+    let manifest_bucket = manifest_location.bucket.as_str();
+    let json = get(client, manifest_bucket, &manifest_location.key).await?;
+    let manifest: Manifest = serde_json::from_slice(&json)
+        .map_err(|e| Error::InventoryError(format!("{manifest_url}: {e}")))?;
+    if manifest.source_bucket != bucket {
+        warn!(
+            "{} is an inventory of {}, not {}",
+            manifest_url, manifest.source_bucket, bucket
+        );
+    }
+    let data_bucket = manifest
+        .destination_bucket
+        .rsplit(':')
+        .next()
+        .unwrap_or(manifest_bucket);
+    let schema: Vec<String> = manifest.file_schema.split(',').map(column_name).collect();
+    info!(
+        "Reading {} {} files from {}...",
+        manifest.files.len(),
+        manifest.file_format,
+        data_bucket
+    );
+
+    let reports: Vec<Vec<Object>> = stream::iter(&manifest.files)
+        .map(|file| {
+            let (format, schema) = (manifest.file_format.clone(), schema.clone());
+            async move {
+                let data = get(client, data_bucket, &file.key).await?;
+                tokio::task::spawn_blocking(move || read(&format, &schema, data))
+                    .await
+                    .map_err(|e| Error::InventoryError(e.to_string()))?
+                    .map_err(|e| Error::InventoryError(format!("{}: {}", file.key, e)))
+            }
+        })
+        .buffered(CONCURRENT_FILES)
+        .try_collect()
+        .await?;
+
+    let mut objects: Vec<Object> = reports
+        .into_iter()
+        .flatten()
+        .filter(|object| {
+            let key = object.key().unwrap_or_default();
+            // What a "/" delimiter would have left out of a listing
+            let under = |prefix: &str| {
+                key.strip_prefix(prefix)
+                    .is_some_and(|rest| !options.top_level || !rest.contains('/'))
+                    && options.within_depth(prefix, key)
+            };
+            match prefixes.is_empty() {
+                true => under(""),
+                false => prefixes.iter().any(|p| under(p)),
+            }
+        })
+        .collect();
+    if let Some(limit) = options.limit {
+        objects.truncate(limit);
+    }
+    Ok(objects)
+}
+
+async fn get(client: &Client, bucket: &str, key: &str) -> Result<Bytes, Error> {
+    let resp = client.get_object().bucket(bucket).key(key).send().await?;
+    Ok(resp
+        .body
+        .collect()
+        .await
+        .map_err(|e| Error::InventoryError(format!("s3://{bucket}/{key}: {e}")))?
+        .into_bytes())
+}
+
+fn read(format: &str, schema: &[String], data: Bytes) -> Result<Vec<Object>, String> {
+    let mut objects = Vec::new();
+    match format {
+        "CSV" => {
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .from_reader(GzDecoder::new(&data[..]));
+            for record in reader.records() {
+                let record = record.map_err(|e| e.to_string())?;
+                let mut entry = Entry::default();
+                for (column, value) in schema.iter().zip(record.iter()) {
+                    entry.set_text(column, value);
+                }
+                objects.extend(entry.into_object());
+            }
+        }
+        "Parquet" => {
+            let reader = SerializedFileReader::new(data).map_err(|e| e.to_string())?;
+            for row in reader.get_row_iter(None).map_err(|e| e.to_string())? {
+                let row = row.map_err(|e| e.to_string())?;
+                let mut entry = Entry::default();
+                for (column, value) in row.get_column_iter() {
+                    entry.set_field(&column_name(column), value);
+                }
+                objects.extend(entry.into_object());
+            }
+        }
+        other => {
+            return Err(format!(
+                "{other} inventories aren't supported, only CSV and Parquet"
+            ))
+        }
+    }
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gzip(csv: &str) -> Bytes {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(csv.as_bytes()).unwrap();
+        encoder.finish().unwrap().into()
+    }
+
+    #[test]
+    fn csv_reports() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+                "sourceBucket": "source",
+                "destinationBucket": "arn:aws:s3:::reports",
+                "version": "2016-11-30",
+                "fileFormat": "CSV",
+                "fileSchema": "Bucket, Key, VersionId, IsLatest, IsDeleteMarker, Size, LastModifiedDate, ETag, StorageClass",
+                "files": [{"key": "source/all/data/1.csv.gz", "size": 100, "MD5checksum": "x"}]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(manifest.source_bucket, "source");
+        assert_eq!(manifest.files[0].key, "source/all/data/1.csv.gz");
+        let schema: Vec<String> = manifest.file_schema.split(',').map(column_name).collect();
+        assert_eq!(schema[1], "key");
+        assert_eq!(schema[6], "lastmodifieddate");
+
+        let data = gzip(concat!(
+            "\"source\",\"a/file+name%C3%A9.txt\",\"v2\",\"true\",\"false\",\"12\",\"2024-01-02T03:04:05.000Z\",\"abc\",\"STANDARD\"\n",
+            "\"source\",\"a/file+name%C3%A9.txt\",\"v1\",\"false\",\"false\",\"10\",\"2024-01-01T03:04:05.000Z\",\"def\",\"STANDARD\"\n",
+            "\"source\",\"gone.txt\",\"v3\",\"true\",\"true\",\"\",\"2024-01-02T03:04:05.000Z\",\"\",\"\"\n",
+            "\"source\",\"b.bin\",\"v4\",\"true\",\"false\",\"5\",\"2024-01-03T00:00:00.000Z\",\"123\",\"GLACIER\"\n",
+        ));
+        let objects = read(&manifest.file_format, &schema, data).unwrap();
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[0].key(), Some("a/file nameé.txt"));
+        assert_eq!(objects[0].size(), Some(12));
+        assert_eq!(objects[0].e_tag(), Some("\"abc\""));
+        assert_eq!(
+            objects[0].last_modified(),
+            Some(&DateTime::from_secs(1704164645))
+        );
+        assert_eq!(objects[1].key(), Some("b.bin"));
+        assert_eq!(
+            objects[1].storage_class(),
+            Some(&ObjectStorageClass::Glacier)
+        );
+    }
+
+    #[test]
+    fn unsupported_formats() {
+        assert!(read("ORC", &[], Bytes::new()).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::bucket::local_bucket_dir;
+use crate::sanitize::Sanitizer;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+const PLACEHOLDERS: [&str; 3] = ["bucket", "key", "prefix_stripped_key"];
+
+/// Where under the download path each object is saved with --layout, e.g. "{key}" or
+/// "{bucket}/{prefix_stripped_key}". {prefix_stripped_key} is the key without the --prefix it
+/// was listed under
+#[derive(Debug, Clone)]
+pub struct Layout {
+    template: String,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut rest = template;
+        let mut has_key = false;
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                return Err(format!("unclosed {{ in {template}"));
+            };
+            let name = &rest[start + 1..start + end];
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(format!(
+                    "unknown {{{name}}}, expected one of {{{}}}",
+                    PLACEHOLDERS.join("}, {")
+                ));
+            }
+            has_key |= name != "bucket";
+            rest = &rest[start + end + 1..];
+        }
+        // Otherwise every object would be saved over the last
+        if !has_key {
+            return Err("needs {key} or {prefix_stripped_key} in it".to_string());
+        }
+        Ok(Layout {
+            template: template.to_string(),
+        })
+    }
+}
+
+/// Where objects are saved under the download path, going by --layout and --sanitize. Cheap to
+/// clone
+#[derive(Clone, Default)]
+pub struct Paths {
+    layout: Option<Arc<(Layout, Vec<String>)>>, // And the prefixes it strips
+    sanitizer: Sanitizer,
+}
+
+impl Paths {
+    pub fn new(layout: Option<&Layout>, prefixes: &[String], sanitizer: Sanitizer) -> Self {
+        Paths {
+            layout: layout.map(|layout| Arc::new((layout.clone(), prefixes.to_vec()))),
+            sanitizer,
+        }
+    }
+
+    pub fn sanitizer(&self) -> &Sanitizer {
+        &self.sanitizer
+    }
+
+    /// Where `key` is saved relative to the download path, <bucket>/<key> without --layout
+    pub fn path(&self, bucket: &str, key: &str) -> PathBuf {
+        let key = self.sanitizer.local_key(key);
+        let key = key.as_ref();
+        let Some((layout, prefixes)) = self.layout.as_deref() else {
+            return PathBuf::from(local_bucket_dir(bucket)).join(key);
+        };
+        // The longest, should they overlap
+        let stripped = prefixes
+            .iter()
+            .filter_map(|prefix| key.strip_prefix(self.sanitizer.local_key(prefix).as_ref()))
+            .min_by_key(|rest| rest.len())
+            .unwrap_or(key);
+        // In one pass, so a key with "{bucket}" in it is left alone
+        let mut path = String::new();
+        let mut rest = layout.template.as_str();
+        while let Some(start) = rest.find('{') {
+            path.push_str(&rest[..start]);
+            let end = start + rest[start..].find('}').unwrap_or_default();
+            path.push_str(match &rest[start + 1..end] {
+                "bucket" => local_bucket_dir(bucket),
+                "prefix_stripped_key" => stripped,
+                _ => key,
+            });
+            rest = &rest[end + 1..];
+        }
+        path.push_str(rest);
+        PathBuf::from(path)
+    }
+}
+
+/// Whether a path from `path` is absolute or goes up with "..", so could end up outside the
+/// download path. S3 keys are only strings, "../../etc/cron.d/x" and "/etc/passwd" among them
+pub fn escapes(path: &Path) -> bool {
+    !path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert!("{key}".parse::<Layout>().is_ok());
+        assert!("{bucket}/{prefix_stripped_key}".parse::<Layout>().is_ok());
+        assert!("{bucket}".parse::<Layout>().is_err());
+        assert!("{bucket}/{name}".parse::<Layout>().is_err());
+        assert!("{bucket}/{key".parse::<Layout>().is_err());
+    }
+
+    #[test]
+    fn escaping_paths() {
+        for path in ["../x", "a/../../x", "/etc/passwd"] {
+            assert!(escapes(Path::new(path)), "{path}");
+        }
+        for path in ["a/b", "./a/b", "a..b/c"] {
+            assert!(!escapes(Path::new(path)), "{path}");
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use crate::bucket::local_bucket_dir;
+use crate::concurrency::Concurrency;
+use crate::{list_prefixes_from, Error, ListOptions};
+use aws_sdk_s3::primitives::DateTime;
+use aws_sdk_s3::types::{Object, ObjectStorageClass};
+use aws_sdk_s3::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// What a listing was of, a cached one is only used for the same again
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Listing {
+    prefixes: Vec<String>,
+    limit: Option<usize>,
+    #[serde(default)]
+    top_level: bool,
+    #[serde(default)]
+    max_depth: Option<usize>,
+    inventory_manifest: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Cache {
+    listed_at: u64, // Seconds since the epoch
+    #[serde(flatten)]
+    listing: Listing,
+    objects: Vec<Cached>,
+}
+
+/// The parts of an object the rest of a run looks at
+#[derive(Serialize, Deserialize)]
+struct Cached {
+    key: String,
+    size: Option<i64>,
+    etag: Option<String>,
+    last_modified: Option<(i64, u32)>, // Seconds and nanoseconds
+    storage_class: Option<String>,
+}
+
+impl From<&Object> for Cached {
+    fn from(object: &Object) -> Self {
+        Cached {
+            key: object.key().unwrap_or_default().to_string(),
+            size: object.size(),
+            etag: object.e_tag().map(str::to_string),
+            last_modified: object.last_modified().map(|t| (t.secs(), t.subsec_nanos())),
+            storage_class: object.storage_class().map(|c| c.as_str().to_string()),
+        }
+    }
+}
+
+impl From<Cached> for Object {
+    fn from(cached: Cached) -> Self {
+        Object::builder()
+            .key(cached.key)
+            .set_size(cached.size)
+            .set_e_tag(cached.etag)
+            .set_last_modified(
+                cached
+                    .last_modified
+                    .map(|(secs, nanos)| DateTime::from_secs_and_nanos(secs, nanos)),
+            )
+            .set_storage_class(
+                cached
+                    .storage_class
+                    .as_deref()
+                    .map(ObjectStorageClass::from),
+            )
+            .build()
+    }
+}
+
+/// The bucket's listing kept in .list-cache-<bucket>.json in the download path with
+/// --list-cache, so runs soon after one another don't each have to list it again
+pub struct ListCache {
+    path: PathBuf,
+    ttl: Duration,
+    listing: Listing,
+}
+
+impl ListCache {
+    pub fn new(
+        download_path: &str,
+        bucket: &str,
+        prefixes: &[String],
+        options: ListOptions,
+        inventory_manifest: Option<&str>,
+        ttl: Duration,
+    ) -> Self {
+        ListCache {
+            path: PathBuf::from(download_path)
+                .join(format!(".list-cache-{}.json", local_bucket_dir(bucket))),
+            ttl,
+            listing: Listing {
+                prefixes: prefixes.to_vec(),
+                limit: options.limit,
+                top_level: options.top_level,
+                max_depth: options.max_depth,
+                inventory_manifest: inventory_manifest.map(str::to_string),
+            },
+        }
+    }
+
+    /// The cached listing if it's of the same thing and hasn't expired
+    pub async fn load(&self) -> Option<Vec<Object>> {
+        let json = match tokio::fs::read(&self.path).await {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!(
+                    "Couldn't read {}, listing again: {}",
+                    self.path.display(),
+                    e
+                );
+                return None;
+            }
+        };
+        let cache: Cache = match serde_json::from_slice(&json) {
+            Ok(cache) => cache,
+            Err(e) => {
+                warn!(
+                    "Couldn't read {}, listing again: {}",
+                    self.path.display(),
+                    e
+                );
+                return None;
+            }
+        };
+        let age = Duration::from_secs(now().saturating_sub(cache.listed_at));
+        if cache.listing != self.listing || age > self.ttl {
+            return None;
+        }
+        info!(
+            "Using the listing cached {} ago in {}",
+            humantime::format_duration(age),
+            self.path.display()
+        );
+        Some(cache.objects.into_iter().map(Object::from).collect())
+    }
+
+    /// Replaces the cached listing, failing to only means the next run lists again
+    pub async fn save(&self, objects: &[Object]) {
+        let cache = Cache {
+            listed_at: now(),
+            listing: self.listing.clone(),
+            objects: objects.iter().map(Cached::from).collect(),
+        };
+        if let Err(e) = write(&self.path, &cache).await {
+            warn!(
+                "Couldn't cache the listing in {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// How far listing has got, so one that's interrupted can carry on from the last key listed
+/// rather than start again
+#[derive(Debug)]
+pub struct ListProgress {
+    pub objects: Vec<Object>,
+    pub prefix: usize, // Which of the prefixes is being listed, the ones before it are done
+    pub start_after: Option<String>, // The last key listed under it
+    seen: Option<HashSet<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedProgress {
+    #[serde(flatten)]
+    listing: Listing,
+    prefix: usize,
+    start_after: Option<String>,
+    objects: Vec<Cached>,
+}
+
+impl ListProgress {
+    pub fn new(objects: Vec<Object>, prefix: usize, start_after: Option<String>) -> Self {
+        ListProgress {
+            objects,
+            prefix,
+            start_after,
+            seen: None,
+        }
+    }
+
+    pub fn push(&mut self, object: Object) {
+        // Overlapping prefixes would otherwise list (and download) the same key twice, only a
+        // later prefix can overlap with one before it
+        if self.prefix > 0 {
+            let seen = self
+                .seen
+                .get_or_insert_with(|| self.objects.iter().filter_map(|o| o.key.clone()).collect());
+            if !seen.insert(object.key().unwrap_or_default().to_string()) {
+                return;
+            }
+        }
+        self.objects.push(object);
+    }
+}
+
+/// Lists the bucket, carrying on from where the last run got to if it was stopped (or failed)
+/// part way through listing the same prefixes. How far it got is kept in
+/// .list-progress-<bucket>.json in the download path until a listing finishes
+pub async fn list_resuming(
+    client: &Client,
+    bucket: &str,
+    prefixes: &[String],
+    options: ListOptions,
+    download_path: &str,
+    concurrency: &Concurrency,
+) -> Result<Vec<Object>, Error> {
+    let path = PathBuf::from(download_path)
+        .join(format!(".list-progress-{}.json", local_bucket_dir(bucket)));
+    let listing = Listing {
+        prefixes: prefixes.to_vec(),
+        limit: options.limit,
+        top_level: options.top_level,
+        max_depth: options.max_depth,
+        inventory_manifest: None,
+    };
+    let mut progress = match read_progress(&path).await {
+        Some(saved) if saved.listing == listing => {
+            info!(
+                "Carrying on listing after {} ({} objects listed already)",
+                saved.start_after.as_deref().unwrap_or_default(),
+                saved.objects.len()
+            );
+            let objects = saved.objects.into_iter().map(Object::from).collect();
+            ListProgress::new(objects, saved.prefix, saved.start_after)
+        }
+        _ => ListProgress::new(Vec::new(), 0, None),
+    };
+
+    match list_prefixes_from(
+        client,
+        bucket,
+        prefixes,
+        options,
+        &mut progress,
+        Some(concurrency),
+    )
+    .await
+    {
+        Ok(()) => {
+            let _ = tokio::fs::remove_file(&path).await;
+            Ok(progress.objects)
+        }
+        Err(e) => {
+            let saved = SavedProgress {
+                listing,
+                prefix: progress.prefix,
+                start_after: progress.start_after,
+                objects: progress.objects.iter().map(Cached::from).collect(),
+            };
+            match write(&path, &saved).await {
+                Ok(()) => warn!(
+                    "Listing stopped after {} objects, run again to carry on from {}",
+                    saved.objects.len(),
+                    saved.start_after.as_deref().unwrap_or("the start")
+                ),
+                Err(write_error) => warn!(
+                    "Couldn't save how far listing got to {}: {}",
+                    path.display(),
+                    write_error
+                ),
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn read_progress(path: &Path) -> Option<SavedProgress> {
+    let json = tokio::fs::read(path).await.ok()?;
+    match serde_json::from_slice(&json) {
+        Ok(saved) => Some(saved),
+        Err(e) => {
+            warn!(
+                "Couldn't read {}, listing from the start: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+// Replaced in one go, so a run stopped part way leaves the last one
+async fn write<T: Serialize>(path: &Path, value: &T) -> std::io::Result<()> {
+    let json = serde_json::to_vec(value).map_err(std::io::Error::other)?;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let temp = path.with_extension("tmp");
+    tokio::fs::write(&temp, json).await?;
+    tokio::fs::rename(&temp, path).await
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("s3dl-{}-{}", name, std::process::id()))
+    }
+
+    fn cache(dir: &Path, prefixes: &[&str], options: ListOptions, ttl_secs: u64) -> ListCache {
+        let prefixes: Vec<String> = prefixes.iter().map(|p| p.to_string()).collect();
+        let ttl = Duration::from_secs(ttl_secs);
+        ListCache::new(
+            &dir.to_string_lossy(),
+            "bucket",
+            &prefixes,
+            options,
+            None,
+            ttl,
+        )
+    }
+
+    fn objects() -> Vec<Object> {
+        vec![Object::builder()
+            .key("logs/a.txt")
+            .size(10)
+            .e_tag("\"abc\"")
+            .last_modified(DateTime::from_secs_and_nanos(1_700_000_000, 500))
+            .storage_class(ObjectStorageClass::Glacier)
+            .build()]
+    }
+
+    #[tokio::test]
+    async fn listings_round_trip() {
+        let dir = dir("list-cache");
+        let cache = cache(&dir, &["logs/"], ListOptions::default(), 60);
+        cache.save(&objects()).await;
+        let loaded = cache.load().await;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(loaded, Some(objects()));
+    }
+
+    #[tokio::test]
+    async fn expired_listings_are_ignored() {
+        let dir = dir("list-cache-expired");
+        let cache = cache(&dir, &["logs/"], ListOptions::default(), 60);
+        let listed = |age: u64| Cache {
+            listed_at: now() - age,
+            listing: cache.listing.clone(),
+            objects: objects().iter().map(Cached::from).collect(),
+        };
+        write(&cache.path, &listed(30)).await.unwrap();
+        let fresh = cache.load().await;
+        write(&cache.path, &listed(120)).await.unwrap();
+        let expired = cache.load().await;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(fresh.is_some());
+        assert!(expired.is_none());
+    }
+
+    #[tokio::test]
+    async fn listings_of_something_else_are_ignored() {
+        let dir = dir("list-cache-other");
+        cache(&dir, &["logs/"], ListOptions::default(), 60)
+            .save(&objects())
+            .await;
+        let options = |options: ListOptions| cache(&dir, &["logs/"], options, 60);
+        let others = [
+            cache(&dir, &[], ListOptions::default(), 60),
+            cache(&dir, &["logs/", "other/"], ListOptions::default(), 60),
+            options(ListOptions {
+                limit: Some(1),
+                ..ListOptions::default()
+            }),
+            options(ListOptions {
+                top_level: true,
+                ..ListOptions::default()
+            }),
+            options(ListOptions {
+                max_depth: Some(2),
+                ..ListOptions::default()
+            }),
+        ];
+        let mut loaded = Vec::new();
+        for other in &others {
+            loaded.push(other.load().await);
+        }
+        // Only how the listing is paged, not what's in it
+        let paged = options(ListOptions {
+            max_keys: Some(10),
+            ..ListOptions::default()
+        })
+        .load()
+        .await;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(loaded.iter().all(Option::is_none));
+        assert!(paged.is_some());
+    }
+}
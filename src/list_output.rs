@@ -0,0 +1,67 @@
+use crate::Error;
+use aws_sdk_s3::primitives::DateTimeFormat;
+use aws_sdk_s3::types::Object;
+use clap::ValueEnum;
+use serde_json::json;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ListFormat {
+    #[default]
+    Ndjson, // One JSON object per line
+    Csv,
+}
+
+/// Writes a line per object (key, size, etag, last_modified, storage_class), replacing the file
+/// if it exists
+pub fn write(path: &Path, format: ListFormat, objects: &[Object]) -> Result<(), Error> {
+    let rows = objects.iter().map(|object| {
+        // S3 quotes them, which is just noise to anything reading the file
+        let etag = object.e_tag().map(|e| e.trim_matches('"'));
+        let last_modified = object
+            .last_modified()
+            .and_then(|t| t.fmt(DateTimeFormat::DateTime).ok());
+        (
+            object.key().unwrap_or_default(),
+            object.size(),
+            etag,
+            last_modified,
+            object.storage_class().map(|c| c.as_str()),
+        )
+    });
+    match format {
+        ListFormat::Ndjson => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            for (key, size, etag, last_modified, storage_class) in rows {
+                let line = json!({
+                    "key": key,
+                    "size": size,
+                    "etag": etag,
+                    "last_modified": last_modified,
+                    "storage_class": storage_class,
+                });
+                writeln!(writer, "{line}")?;
+            }
+            writer.flush()?;
+        }
+        ListFormat::Csv => {
+            let mut writer = csv::Writer::from_path(path)?;
+            writer.write_record(["key", "size", "etag", "last_modified", "storage_class"])?;
+            for (key, size, etag, last_modified, storage_class) in rows {
+                writer.write_record([
+                    key,
+                    &size.map(|s| s.to_string()).unwrap_or_default(),
+                    etag.unwrap_or_default(),
+                    last_modified.as_deref().unwrap_or_default(),
+                    storage_class.unwrap_or_default(),
+                ])?;
+            }
+            writer.flush()?;
+        }
+    }
+    info!("Wrote {} objects to {}", objects.len(), path.display());
+    Ok(())
+}
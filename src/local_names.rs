@@ -0,0 +1,331 @@
+use crate::Error;
+use aws_sdk_s3::types::Object;
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use unicode_normalization::UnicodeNormalization;
+
+/// What each key of a run is saved as under the bucket's directory, when that isn't the key
+/// itself. Worked out from the whole listing, as whether a name is taken depends on the others.
+/// Besides --flatten, that's keys only told apart by their Unicode normalization (é as one code
+/// point or as e and a combining accent), which macOS saves as the same file. Uploading,
+/// --watch-local, --delete-extra and verify all go through these to tell which key a file is for
+pub struct LocalNames {
+    names: HashMap<String, String>,
+    keys: HashMap<String, String>, // The other way round, to upload a renamed file under its key
+    skipped: HashSet<String>,
+    flatten: bool,
+}
+
+/// What to do about keys only told apart by case, Data/File and data/file, which are the same
+/// file on Windows and macOS so one would be saved over the other
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CaseCollisions {
+    #[default]
+    Warn, // Say which keys they are and download them as they are
+    Rename, // Save all but the first under their name with part of the hash of their key added, still uploaded under their key
+    Error,  // Stop before downloading anything
+}
+
+/// What to do about an object with the same name as a directory other keys are in, a/b and
+/// a/b/c, as a/b can't be both a file and a directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum PrefixConflicts {
+    #[default]
+    Suffix, // Save the file under its name with part of the hash of its key added
+    Skip,  // Leave the file out
+    Error, // Stop before downloading anything
+}
+
+impl LocalNames {
+    pub fn find(
+        objects: &[Object],
+        flatten: bool,
+        case: CaseCollisions,
+        prefix: PrefixConflicts,
+    ) -> Result<Arc<Self>, Error> {
+        let mut names = HashMap::new();
+        if flatten {
+            let mut by_name: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+            for key in objects.iter().filter_map(|o| o.key()) {
+                let name = key.rsplit('/').next().unwrap_or(key);
+                if !name.is_empty() {
+                    by_name.entry(name).or_default().push(key);
+                }
+            }
+            let mut renamed = 0;
+            for (name, mut keys) in by_name {
+                // The first key in order keeps the name, so it's the same from one run to the
+                // next for as long as that key is there
+                keys.sort_unstable();
+                for (i, key) in keys.into_iter().enumerate() {
+                    let local = match i {
+                        0 => name.to_string(),
+                        _ => {
+                            renamed += 1;
+                            disambiguate(name, key)
+                        }
+                    };
+                    names.insert(key.to_string(), local);
+                }
+            }
+            if renamed > 0 {
+                info!(
+                    "{} objects have the same name as another, they're saved with part of the hash of their key added",
+                    renamed
+                );
+            }
+        }
+
+        let mut renamed = 0;
+        for keys in collisions(objects, &names, |local| local.nfc().collect()) {
+            for (key, local) in keys.into_iter().skip(1) {
+                renamed += 1;
+                names.insert(key.to_string(), disambiguate(&local, key));
+            }
+        }
+        if renamed > 0 {
+            info!(
+                "{} objects have a key that only differs from another's in its Unicode normalization, they're saved with part of the hash of their key added",
+                renamed
+            );
+        }
+
+        let mut colliding = 0;
+        for keys in collisions(objects, &names, |local| local.to_lowercase()) {
+            let first = keys[0].0;
+            for (key, local) in keys.into_iter().skip(1) {
+                colliding += 1;
+                match case {
+                    CaseCollisions::Warn => warn!(
+                        "{} and {} only differ in case, on Windows or macOS one will be saved over the other",
+                        first, key
+                    ),
+                    CaseCollisions::Rename => {
+                        names.insert(key.to_string(), disambiguate(&local, key));
+                    }
+                    CaseCollisions::Error => error!(
+                        "{} and {} only differ in case, see --case-collisions",
+                        first, key
+                    ),
+                }
+            }
+        }
+        if colliding > 0 && case == CaseCollisions::Error {
+            return Err(Error::CaseCollisions(colliding));
+        }
+        if colliding > 0 && case == CaseCollisions::Rename {
+            info!(
+                "{} objects have a key that only differs from another's in case, they're saved with part of the hash of their key added",
+                colliding
+            );
+        }
+
+        let local_names = || {
+            objects
+                .iter()
+                .filter_map(|o| o.key())
+                .map(|key| (key, names.get(key).map_or(key, String::as_str)))
+        };
+        let mut directories = HashSet::new();
+        // Directory markers aren't saved when flattening
+        for (_, local) in local_names().filter(|(key, _)| !(flatten && key.ends_with('/'))) {
+            let mut parts: Vec<&str> = local.split('/').collect();
+            parts.pop();
+            for i in 1..=parts.len() {
+                directories.insert(parts[..i].join("/"));
+            }
+        }
+        let conflicting: Vec<(&str, String)> = local_names()
+            .filter(|(_, local)| directories.contains(*local))
+            .map(|(key, local)| (key, local.to_string()))
+            .collect();
+        let mut skipped = HashSet::new();
+        for (key, local) in &conflicting {
+            match prefix {
+                PrefixConflicts::Suffix => {
+                    names.insert(key.to_string(), disambiguate(local, key));
+                }
+                PrefixConflicts::Skip => {
+                    warn!(
+                        "Not downloading {}, there are keys under {}/ and it can't be both a file and a directory",
+                        key, local
+                    );
+                    skipped.insert(key.to_string());
+                }
+                PrefixConflicts::Error => error!(
+                    "{} is a key and there are keys under {}/, see --prefix-conflicts",
+                    key, local
+                ),
+            }
+        }
+        if !conflicting.is_empty() && prefix == PrefixConflicts::Error {
+            return Err(Error::PrefixConflicts(conflicting.len()));
+        }
+        if !conflicting.is_empty() && prefix == PrefixConflicts::Suffix {
+            info!(
+                "{} objects have the same name as a directory other keys are in, they're saved with part of the hash of their key added",
+                conflicting.len()
+            );
+        }
+        let keys = names
+            .iter()
+            .map(|(key, local)| (local.clone(), key.clone()))
+            .collect();
+        Ok(Arc::new(LocalNames {
+            names,
+            keys,
+            skipped,
+            flatten,
+        }))
+    }
+
+    /// The path under the bucket's directory to save `key` at, None for a directory marker when
+    /// there are no directories or a file left out with --prefix-conflicts skip
+    pub fn get<'a>(&'a self, key: &'a str) -> Option<&'a str> {
+        if self.skipped.contains(key) {
+            return None;
+        }
+        match self.names.get(key) {
+            Some(name) => Some(name),
+            None if self.flatten && key.ends_with('/') => None,
+            None => Some(key),
+        }
+    }
+
+    /// The key of what's saved at `local` under the bucket's directory, which is `local` itself
+    /// unless it was renamed
+    pub fn key<'a>(&'a self, local: &'a str) -> &'a str {
+        self.keys.get(local).map_or(local, String::as_str)
+    }
+}
+
+// Keys whose local names are the same once `fold`ed, in order in each group so the first keeps
+// its name from one run to the next for as long as that key is there
+fn collisions<'a>(
+    objects: &'a [Object],
+    names: &HashMap<String, String>,
+    fold: impl Fn(&str) -> String,
+) -> Vec<Vec<(&'a str, String)>> {
+    let mut by_folded: BTreeMap<String, Vec<(&str, String)>> = BTreeMap::new();
+    for key in objects.iter().filter_map(|o| o.key()) {
+        let local = names.get(key).map_or(key, String::as_str);
+        // Directories that come out the same are merged, which is harmless
+        if !local.is_empty() && !local.ends_with('/') {
+            let entry = by_folded.entry(fold(local)).or_default();
+            entry.push((key, local.to_string()));
+        }
+    }
+    by_folded
+        .into_values()
+        .filter(|keys| keys.len() > 1)
+        .map(|mut keys| {
+            keys.sort_unstable();
+            keys
+        })
+        .collect()
+}
+
+/// logs/a/app.log -> logs/a/app-<8 hex digits>.log, the hash of `key` going in the last part
+/// of `path` so it's still in the same directory
+pub fn disambiguate(path: &str, key: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(key.as_bytes()));
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (&path[..dir.len() + 1], name),
+        None => ("", path),
+    };
+    match name.rsplit_once('.').filter(|(stem, _)| !stem.is_empty()) {
+        Some((stem, extension)) => format!("{}{}-{}.{}", dir, stem, &hash[..8], extension),
+        None => format!("{}{}-{}", dir, name, &hash[..8]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn objects(keys: &[&str]) -> Vec<Object> {
+        keys.iter()
+            .map(|k| Object::builder().key(*k).build())
+            .collect()
+    }
+
+    fn find(keys: &[&str], flatten: bool, case: CaseCollisions) -> Result<Arc<LocalNames>, Error> {
+        LocalNames::find(&objects(keys), flatten, case, PrefixConflicts::Suffix)
+    }
+
+    #[test]
+    fn keys_are_their_own_names() {
+        let names = find(&["a/b.txt", "c/"], false, CaseCollisions::Warn).unwrap();
+        assert_eq!(names.get("a/b.txt"), Some("a/b.txt"));
+        assert_eq!(names.get("c/"), Some("c/"));
+        assert_eq!(names.key("a/b.txt"), "a/b.txt");
+    }
+
+    #[test]
+    fn flatten_keeps_the_first_key_in_order() {
+        let names = find(&["b/x.txt", "a/x.txt", "dir/"], true, CaseCollisions::Warn).unwrap();
+        let renamed = disambiguate("x.txt", "b/x.txt");
+        assert_eq!(names.get("a/x.txt"), Some("x.txt"));
+        assert_eq!(names.get("b/x.txt"), Some(renamed.as_str()));
+        assert_eq!(names.get("dir/"), None);
+        assert_eq!(names.key(&renamed), "b/x.txt");
+        assert_eq!(names.key("x.txt"), "a/x.txt");
+    }
+
+    #[test]
+    fn disambiguate_keeps_the_directory_and_extension() {
+        let renamed = disambiguate("logs/a/app.log", "logs/a/app.log");
+        assert!(renamed.starts_with("logs/a/app-"));
+        assert!(renamed.ends_with(".log"));
+        assert_eq!(renamed.len(), "logs/a/app-12345678.log".len());
+        assert_eq!(renamed, disambiguate("logs/a/app.log", "logs/a/app.log"));
+        assert_ne!(renamed, disambiguate("logs/a/app.log", "logs/b/app.log"));
+        // A dot at the start isn't an extension
+        assert!(disambiguate(".env", "x/.env").starts_with(".env-"));
+    }
+
+    #[test]
+    fn unicode_normalization_collisions_are_renamed() {
+        let (composed, decomposed) = ("caf\u{e9}.txt", "cafe\u{301}.txt");
+        let names = find(&[composed, decomposed], false, CaseCollisions::Warn).unwrap();
+        assert_eq!(names.get(decomposed), Some(decomposed));
+        let renamed = disambiguate(composed, composed);
+        assert_eq!(names.get(composed), Some(renamed.as_str()));
+        assert_eq!(names.key(&renamed), composed);
+    }
+
+    #[test]
+    fn case_collisions() {
+        let keys = ["data/file", "Data/File"];
+        let names = find(&keys, false, CaseCollisions::Warn).unwrap();
+        assert_eq!(names.get("data/file"), Some("data/file"));
+
+        let names = find(&keys, false, CaseCollisions::Rename).unwrap();
+        let renamed = disambiguate("data/file", "data/file");
+        assert_eq!(names.get("Data/File"), Some("Data/File"));
+        assert_eq!(names.get("data/file"), Some(renamed.as_str()));
+        assert_eq!(names.key(&renamed), "data/file");
+
+        let result = find(&keys, false, CaseCollisions::Error);
+        assert!(matches!(result, Err(Error::CaseCollisions(1))));
+    }
+
+    #[test]
+    fn prefix_conflicts() {
+        let keys = objects(&["a/b", "a/b/c"]);
+        let case = CaseCollisions::Warn;
+        let names = LocalNames::find(&keys, false, case, PrefixConflicts::Suffix).unwrap();
+        assert_eq!(names.get("a/b"), Some(disambiguate("a/b", "a/b").as_str()));
+        assert_eq!(names.get("a/b/c"), Some("a/b/c"));
+
+        let names = LocalNames::find(&keys, false, case, PrefixConflicts::Skip).unwrap();
+        assert_eq!(names.get("a/b"), None);
+        assert_eq!(names.get("a/b/c"), Some("a/b/c"));
+
+        let result = LocalNames::find(&keys, false, case, PrefixConflicts::Error);
+        assert!(matches!(result, Err(Error::PrefixConflicts(1))));
+    }
+}
@@ -0,0 +1,160 @@
+use crate::context::Context;
+use crate::events::Direction;
+use crate::local_names::LocalNames;
+use crate::{shutdown, sidecar, stats, upload_object, Error, UploadOptions};
+use aws_sdk_s3::Client;
+use notify_debouncer_mini::notify::RecursiveMode;
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{error, info, Instrument};
+
+// Files usually get written in several goes, wait for them to go quiet before uploading
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Uploads files as they're added to or changed in `dir`, until the run is stopped. Those saved
+/// under another name than their key (see `LocalNames`) go back to their key
+pub async fn upload_changes(
+    client: &Client,
+    bucket: &str,
+    dir: String,
+    names: &LocalNames,
+    options: UploadOptions,
+    context: &Context,
+) -> Result<(), Error> {
+    tokio::fs::create_dir_all(&dir).await?;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut debouncer = new_debouncer(
+        SETTLE_TIME,
+        move |result: DebounceEventResult| match result {
+            Ok(events) => {
+                for event in events {
+                    let _ = tx.send(event.path);
+                }
+            }
+            Err(e) => error!("Got an error watching for changes: {}", e),
+        },
+    )?;
+    debouncer
+        .watcher()
+        .watch(Path::new(&dir), RecursiveMode::Recursive)?;
+    info!(
+        "Watching {} for new or changed files to upload to {} (Ctrl-C to stop)...",
+        dir, bucket
+    );
+
+    let root = tokio::fs::canonicalize(&dir).await?;
+    let mut uploads = JoinSet::new();
+    // Reading a file to upload it counts as an event too, so remember what was last uploaded
+    let mut uploaded: HashMap<PathBuf, (SystemTime, u64)> = HashMap::new();
+    loop {
+        let path = tokio::select! {
+            path = rx.recv() => path,
+            _ = context.concurrency.wait_until_stopped() => None,
+        };
+        let Some(path) = path else {
+            break;
+        };
+        // Deleted files and directories show up too
+        let metadata = if options.symlinks {
+            tokio::fs::symlink_metadata(&path).await
+        } else {
+            tokio::fs::metadata(&path).await
+        };
+        let Ok(metadata) = metadata else {
+            continue;
+        };
+        // Downloads still being written show up as part files, then again once renamed
+        if !(metadata.is_file() || metadata.is_symlink())
+            || shutdown::is_part_file(&path)
+            || (options.metadata_sidecars && sidecar::is_sidecar(&path))
+        {
+            continue;
+        }
+        let modified = match metadata.modified() {
+            Ok(modified) => modified,
+            Err(e) => {
+                error!(
+                    "Got an error reading when {} changed: {}",
+                    path.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        let version = (modified, metadata.len());
+        if uploaded.get(&path) == Some(&version) {
+            continue;
+        }
+        uploaded.insert(path.clone(), version);
+        let key = match relative_path(&root, &path).await {
+            Some(k) => names
+                .key(&context.paths.sanitizer().original(&k.to_string_lossy()))
+                .to_string(),
+            None => {
+                error!("Error getting key name from path: {}", path.display());
+                continue;
+            }
+        };
+
+        let client = client.clone();
+        let bucket = bucket.to_string();
+        let context = context.clone();
+        uploads.spawn(
+            async move {
+                let Some(_permit) = context.concurrency.acquire(Direction::Upload).await else {
+                    return;
+                };
+                let uploaded = upload_object(&client, &bucket, &key, path, options, &context);
+                if let Err(e) = uploaded.await {
+                    error!("Got an error uploading {}: {}", key, e);
+                    stats::failed(1);
+                }
+            }
+            .in_current_span(),
+        );
+        while uploads.try_join_next().is_some() {}
+    }
+
+    while uploads.join_next().await.is_some() {}
+    Ok(())
+}
+
+// Where `path` is under `root`, which is canonical. Events come with the watched directory as it
+// was given (made absolute, ".." and all) joined with the file's path under it
+async fn relative_path(root: &Path, path: &Path) -> Option<PathBuf> {
+    let parent = tokio::fs::canonicalize(path.parent()?).await.ok()?;
+    let relative = parent.strip_prefix(root).ok()?;
+    Some(relative.join(path.file_name()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn paths_under_a_relative_dir() {
+        let dir = std::env::temp_dir().join(format!("s3dl-local-watch-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        // e.g. ../../tmp/s3dl-local-watch-1, up to the root from where the tests run
+        let cwd = std::env::current_dir().unwrap();
+        let up = "../".repeat(cwd.components().count() - 1);
+        let relative = PathBuf::from(up).join(dir.strip_prefix("/").unwrap());
+        assert!(relative.is_relative());
+
+        let root = tokio::fs::canonicalize(&relative).await.unwrap();
+        // How the watcher reports a file in the directory
+        let event = |name: &str| cwd.join(&relative).join(name);
+        let file = relative_path(&root, &event("a.txt")).await;
+        let nested = relative_path(&root, &event("sub/b.txt")).await;
+        let outside = relative_path(&root, &cwd.join("Cargo.toml")).await;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(file, Some(PathBuf::from("a.txt")));
+        assert_eq!(nested, Some(PathBuf::from("sub/b.txt")));
+        assert_eq!(outside, None);
+    }
+}
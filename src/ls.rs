@@ -0,0 +1,68 @@
+use crate::bucket::{arn_region, S3Url};
+use crate::client::{create_client, ConnectionArgs};
+use crate::concurrency::{Concurrency, ConcurrencyArgs};
+use crate::notification::format_bytes;
+use crate::{list_prefixes, Error, ListOptions};
+use aws_sdk_s3::primitives::DateTimeFormat;
+use chrono::{DateTime, Local};
+use clap::Args;
+
+#[derive(Args)]
+pub struct LsArgs {
+    url: S3Url, // s3://<bucket>/<prefix>, everything under the prefix is listed
+    #[arg(short, long)]
+    profile: Option<String>,
+    #[arg(short, long)]
+    region: Option<String>,
+    #[arg(short, long)]
+    long: bool, // Exact sizes and UTC times, with the ETag too
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+}
+
+/// One line per object on stdout, e.g. `2024-01-31 09:15:02   1.2 MiB  STANDARD  logs/a.gz`
+pub async fn ls(args: LsArgs) -> Result<(), Error> {
+    let concurrency = Concurrency::new(&args.concurrency);
+    let region = args.region.or_else(|| arn_region(&args.url.bucket));
+    let client = create_client(region, args.profile, &args.connection, &concurrency).await;
+    let prefixes: Vec<String> = [args.url.key]
+        .into_iter()
+        .filter(|k| !k.is_empty())
+        .collect();
+    let objects =
+        list_prefixes(&client, &args.url.bucket, &prefixes, ListOptions::default()).await?;
+
+    for object in &objects {
+        let size = object.size().unwrap_or_default().max(0) as u64;
+        let storage_class = object
+            .storage_class()
+            .map(|c| c.as_str())
+            .unwrap_or("STANDARD");
+        let key = object.key().unwrap_or_default();
+        if args.long {
+            let modified = object
+                .last_modified()
+                .and_then(|t| t.fmt(DateTimeFormat::DateTime).ok())
+                .unwrap_or_default();
+            let etag = object.e_tag().unwrap_or_default().trim_matches('"');
+            println!("{modified:<24} {size:>15}  {storage_class:<19} {etag:<34}  {key}");
+        } else {
+            let modified = object
+                .last_modified()
+                .and_then(|t| DateTime::from_timestamp(t.secs(), 0))
+                .map(|t| {
+                    t.with_timezone(&Local)
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string()
+                })
+                .unwrap_or_default();
+            println!(
+                "{modified:<19} {:>10}  {storage_class:<19} {key}",
+                format_bytes(size)
+            );
+        }
+    }
+    Ok(())
+}
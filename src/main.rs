@@ -1,26 +1,118 @@
-use aws_config::meta::region::RegionProviderChain;
-use aws_config::profile::ProfileFileCredentialsProvider;
-use aws_config::Region;
-use aws_sdk_s3::types::Object;
+#![allow(clippy::result_large_err)]
+
+use archive::ArchiveFormat;
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata};
+use aws_sdk_s3::primitives::DateTime;
+use aws_sdk_s3::types::{ChecksumAlgorithm, ChecksumMode, Object};
 use aws_sdk_s3::Client;
-use clap::Parser;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bucket::{is_arn, is_directory_bucket, local_bucket_dir};
+use bytesize::ByteSize;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use client::{create_client, ConnectionArgs};
+use compress::Compression;
+use concurrency::{retry_slow_down, Concurrency, ConcurrencyArgs};
+use context::Context;
+use dedup::Duplicates;
+use delete_source::DeleteSource;
+use events::{Direction, Events, OutputFormat};
+use flate2::read::MultiGzDecoder;
+use fs4::tokio::AsyncFileExt;
 use futures::future::join_all;
 use glob::glob;
-use std::collections::HashSet;
+use layout::Layout;
+use list_cache::{ListCache, ListProgress};
+use list_output::ListFormat;
+use local_names::{CaseCollisions, LocalNames, PrefixConflicts};
+use md5::{Digest, Md5};
+use notification::{NotificationArgs, RunSummary};
+use order::Order;
+use overwrite::Overwrite;
+use posix::PosixAttributes;
+use rename::Rules;
+use report::Report;
+use sanitize::Sanitize;
+use schedule::{LastListing, ScheduleArgs};
+use shutdown::PartialFile;
+use sidecar::Sidecar;
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 use tokio::fs::{self, File};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Semaphore;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tracing::{debug, error, info, instrument, warn, Instrument};
+
+mod archive;
+mod backup;
+mod bisync;
+mod breaker;
+mod bucket;
+mod cas;
+mod cat;
+mod client;
+mod compress;
+mod concurrency;
+mod context;
+mod cp;
+mod dedup;
+mod delete_source;
+mod diff;
+mod direct;
+mod du;
+mod etag;
+mod events;
+mod glacier;
+mod inventory;
+mod layout;
+mod list_cache;
+mod list_output;
+mod local_names;
+mod local_watch;
+mod ls;
+mod manifest;
+mod memory;
+mod metrics;
+mod mirror;
+mod notification;
+mod order;
+mod overwrite;
+mod posix;
+mod presign;
+mod rename;
+mod report;
+mod rm;
+mod sanitize;
+mod schedule;
+mod select;
+mod shutdown;
+mod sidecar;
+mod signing;
+mod snapshot;
+mod sqs;
+mod stat;
+mod stats;
+mod symlink;
+mod telemetry;
+mod upload;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod verify;
+mod versions;
 
 const MAX_CONCURRENT_OPERATIONS: usize = 30;
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 enum Error {
     #[error("io error: {0}")]
     IOError(#[from] std::io::Error),
+    #[error("csv error: {0}")]
+    CsvError(#[from] csv::Error),
     #[error("s3 error: {0}")]
     S3Error(#[from] aws_sdk_s3::Error),
     #[error("s3 put object error: {0}")]
@@ -47,6 +139,112 @@ enum Error {
             aws_smithy_runtime_api::http::Response,
         >,
     ),
+    #[error("s3 head object error: {0}")]
+    HeadObjectError(
+        #[from]
+        aws_smithy_runtime_api::client::result::SdkError<
+            aws_sdk_s3::operation::head_object::HeadObjectError,
+            aws_smithy_runtime_api::http::Response,
+        >,
+    ),
+    #[error("s3 restore object error: {0}")]
+    RestoreObjectError(
+        #[from]
+        aws_smithy_runtime_api::client::result::SdkError<
+            aws_sdk_s3::operation::restore_object::RestoreObjectError,
+            aws_smithy_runtime_api::http::Response,
+        >,
+    ),
+    #[error("s3 list object versions error: {0}")]
+    ListObjectVersionsError(
+        #[from]
+        aws_smithy_runtime_api::client::result::SdkError<
+            aws_sdk_s3::operation::list_object_versions::ListObjectVersionsError,
+            aws_smithy_runtime_api::http::Response,
+        >,
+    ),
+    #[error("s3 delete object error: {0}")]
+    DeleteObjectError(
+        #[from]
+        aws_smithy_runtime_api::client::result::SdkError<
+            aws_sdk_s3::operation::delete_object::DeleteObjectError,
+            aws_smithy_runtime_api::http::Response,
+        >,
+    ),
+    #[error("s3 delete objects error: {0}")]
+    DeleteObjectsError(
+        #[from]
+        aws_smithy_runtime_api::client::result::SdkError<
+            aws_sdk_s3::operation::delete_objects::DeleteObjectsError,
+            aws_smithy_runtime_api::http::Response,
+        >,
+    ),
+    #[error("s3 create multipart upload error: {0}")]
+    CreateMultipartUploadError(
+        #[from]
+        aws_smithy_runtime_api::client::result::SdkError<
+            aws_sdk_s3::operation::create_multipart_upload::CreateMultipartUploadError,
+            aws_smithy_runtime_api::http::Response,
+        >,
+    ),
+    #[error("s3 upload part error: {0}")]
+    UploadPartError(
+        #[from]
+        aws_smithy_runtime_api::client::result::SdkError<
+            aws_sdk_s3::operation::upload_part::UploadPartError,
+            aws_smithy_runtime_api::http::Response,
+        >,
+    ),
+    #[error("s3 complete multipart upload error: {0}")]
+    CompleteMultipartUploadError(
+        #[from]
+        aws_smithy_runtime_api::client::result::SdkError<
+            aws_sdk_s3::operation::complete_multipart_upload::CompleteMultipartUploadError,
+            aws_smithy_runtime_api::http::Response,
+        >,
+    ),
+    #[error("s3 request error: {0}")]
+    BuildError(#[from] aws_sdk_s3::error::BuildError),
+    #[error("stopped before everything was transferred")]
+    Stopped,
+    #[error("{0} files don't match the bucket")]
+    VerifyFailed(usize),
+    #[error("{0} keys differ between the buckets")]
+    BucketsDiffer(usize),
+    #[error("{0} objects couldn't be deleted")]
+    DeleteFailed(usize),
+    #[error("{0} files would be deleted, more than --max-delete allows")]
+    TooManyDeletions(usize),
+    #[error("{0} keys only differ in case from another")]
+    CaseCollisions(usize),
+    #[error("{0} keys are also directories other keys are in")]
+    PrefixConflicts(usize),
+    #[error("nothing deleted without confirmation")]
+    NotConfirmed,
+    #[error("watch error: {0}")]
+    WatchError(#[from] notify_debouncer_mini::notify::Error),
+    #[error("request signing error: {0}")]
+    SigningError(String),
+    #[error("sqs error: {0}")]
+    SqsError(String),
+    #[error("sns error: {0}")]
+    SnsError(String),
+    #[error("inventory error: {0}")]
+    InventoryError(String),
+    #[error("http error: {0}")]
+    HttpError(#[from] reqwest::Error),
+}
+
+/// What happened to an object that didn't fail outright
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Outcome {
+    Downloaded,
+    DeleteMarker, // The latest version of the key is a delete marker so there was nothing to download
+    Failed,       // The error has already been printed
+    Uploaded,
+    NotAttempted, // The run was stopped before getting to it
+    Archived,     // It has to be restored first, see --restore
+    Skipped,      // There was already a file and --overwrite said to keep it
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
@@ -62,153 +260,1041 @@ impl<'a> From<&'a Object> for ObjectKey {
     }
 }
 
+#[derive(Debug, Clone, Copy, Default)]
+struct ListOptions {
+    max_keys: Option<i32>,    // Page size of each ListObjectsV2 request
+    limit: Option<usize>,     // Stop listing once this many objects have been found
+    top_level: bool, // Only objects directly under each prefix, listed with a "/" delimiter
+    max_depth: Option<usize>, // Only objects at most this many "/" separated levels under the prefix
+}
+
+impl ListOptions {
+    /// Page size for the next list request, no point asking for a full page when we
+    /// only need a handful more objects to reach the limit
+    fn page_size(&self, found: usize) -> Option<i32> {
+        let remaining = self.limit.map(|l| l.saturating_sub(found).min(1000) as i32);
+        match (self.max_keys, remaining) {
+            (Some(m), Some(r)) => Some(m.min(r)),
+            (m, r) => m.or(r),
+        }
+    }
+
+    /// Whether `key`, listed under `prefix`, is as shallow as --max-depth (or --no-recursive,
+    /// one level) allows
+    fn within_depth(&self, prefix: &str, key: &str) -> bool {
+        let rest = key.strip_prefix(prefix).unwrap_or(key);
+        let max_depth = if self.top_level {
+            Some(1)
+        } else {
+            self.max_depth
+        };
+        max_depth.is_none_or(|depth| rest.matches('/').count() < depth)
+    }
+
+    /// Whether listing `prefixes` with these options would take in `key`, so local files can be
+    /// walked the same way and those deeper down aren't taken for ones without an object
+    fn covers(&self, prefixes: &[String], key: &str) -> bool {
+        if prefixes.is_empty() {
+            return self.within_depth("", key);
+        }
+        prefixes
+            .iter()
+            .any(|p| key.starts_with(p.as_str()) && self.within_depth(p, key))
+    }
+}
+
+/// How objects are written to the upload bucket
+#[derive(Debug, Clone, Copy, Default)]
+struct UploadOptions {
+    checksum_algorithm: Option<UploadChecksum>,
+    metadata_sidecars: bool, // Send the metadata saved next to each file along with it
+    preserve: bool,          // Send each file's mode, owner and modified time as metadata
+    symlinks: bool,          // Send links as what they point to rather than following them
+    compress: Option<Compression>, // Send files compressed, with a Content-Encoding saying how
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum UploadChecksum {
+    Crc32,
+    Crc32c,
+    Crc64nvme,
+    Sha1,
+    Sha256,
+}
+
+impl From<UploadChecksum> for ChecksumAlgorithm {
+    fn from(checksum: UploadChecksum) -> Self {
+        match checksum {
+            UploadChecksum::Crc32 => ChecksumAlgorithm::Crc32,
+            UploadChecksum::Crc32c => ChecksumAlgorithm::Crc32C,
+            UploadChecksum::Crc64nvme => ChecksumAlgorithm::Crc64Nvme,
+            UploadChecksum::Sha1 => ChecksumAlgorithm::Sha1,
+            UploadChecksum::Sha256 => ChecksumAlgorithm::Sha256,
+        }
+    }
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)] // Read from `Cargo.toml`
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    source: Option<SourceArgs>, // Only None when a subcommand is given
+    #[command(flatten)]
+    sync: SyncArgs,
+    #[command(flatten)]
+    download: DownloadArgs,
+    #[command(flatten)]
+    glacier: glacier::GlacierArgs,
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+    #[command(flatten)]
+    schedule: ScheduleArgs,
+    #[command(flatten)]
+    notification: NotificationArgs,
+    #[command(flatten)]
+    metrics: metrics::MetricsArgs,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+    #[arg(long, value_name = "FILE")]
+    report_csv: Option<PathBuf>, // Write a row per object transferred (key, size, etag, status, duration, error) for auditing afterwards
+}
+
+impl Cli {
+    /// What clap can't check itself, as it's between args in structs the subcommands share
+    /// without the others
+    fn validate(&self) -> Result<(), clap::Error> {
+        if self.download.range.is_some() && self.sync.key.is_none() {
+            return Err(Cli::command().error(
+                ErrorKind::MissingRequiredArgument,
+                "--range can only be used with --key",
+            ));
+        }
+        // Events name the bucket an access point is for, its ARN doesn't say which that is
+        let access_point = self.source.as_ref().is_some_and(|s| is_arn(&s.bucket));
+        if self.sync.sqs_queue_url.is_some() && access_point && self.sync.event_bucket.is_none() {
+            return Err(Cli::command().error(
+                ErrorKind::MissingRequiredArgument,
+                "--sqs-queue-url with an access point as --bucket needs --event-bucket",
+            ));
+        }
+        match &self.command {
+            Some(Command::Bisync(args)) => args.validate(),
+            Some(Command::Verify(args)) => args.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Download a versioned bucket as it was at a point in time
+    Restore(versions::RestoreArgs),
+    /// Compare the downloaded files with the bucket without transferring anything
+    Verify(verify::VerifyArgs),
+    /// Sync both ways, copying changes made locally to the bucket as well as the other way round
+    Bisync(bisync::BisyncArgs),
+    /// List the keys only in one of two buckets, or that differ, without transferring anything
+    Diff(diff::DiffArgs),
+    /// Print the objects under s3://<bucket>/<prefix> with their size, modified time and storage class
+    Ls(ls::LsArgs),
+    /// Total up the size and number of objects under each prefix of s3://<bucket>/<prefix>
+    Du(du::DuArgs),
+    /// Print the size, ETag, checksums, storage class and metadata of s3://<bucket>/<key>
+    Stat(stat::StatArgs),
+    /// Print temporary URLs to download (or upload) s3://<bucket>/<key> without credentials
+    Presign(presign::PresignArgs),
+    /// Delete s3://<bucket>/<key>, or with --recursive everything under the prefix
+    Rm(rm::RmArgs),
+    /// Write s3://<bucket>/<key> to stdout, to pipe it into something else
+    Cat(cat::CatArgs),
+    /// Copy one object to a local file, or a file to an object, under whatever name
+    Cp(cp::CpArgs),
+    /// Upload a file, or whatever is piped in with "-", to s3://<bucket>/<key> as it's read
+    Upload(upload::UploadArgs),
+    /// Run an S3 Select query against s3://<bucket>/<key> (or everything under a prefix) and
+    /// write only its results
+    Select(select::SelectArgs),
+}
+
+// Must not flatten other args, clap can't tell if an `Option<SourceArgs>` is present otherwise
+#[derive(Args)]
+struct SourceArgs {
     #[arg(short, long)]
     bucket: String,
-    #[arg(long)]
-    prefix: Option<String>,
+    #[arg(long, value_delimiter = ',')]
+    prefix: Vec<String>,
     #[arg(short, long)]
     profile: Option<String>,
     #[arg(short, long)]
     region: Option<String>,
-    #[arg(short = 'p', long, default_value_t = String::from("./files"))]
+    #[arg(long, value_parser = clap::value_parser!(i32).range(1..=1000))]
+    max_keys: Option<i32>,
+    #[arg(long)]
+    limit: Option<usize>,
+    #[arg(long)]
+    no_recursive: bool, // Only objects directly under each prefix, not in "subdirectories" of it
+    #[arg(long, conflicts_with = "no_recursive", value_parser = clap::value_parser!(u32).range(1..))]
+    max_depth: Option<u32>, // Only objects this many levels under each prefix or fewer, 1 being --no-recursive
+}
+
+impl SourceArgs {
+    fn client_region(&self) -> Option<String> {
+        self.region
+            .clone()
+            .or_else(|| bucket::arn_region(&self.bucket))
+    }
+
+    fn list_options(&self) -> ListOptions {
+        ListOptions {
+            max_keys: self.max_keys,
+            limit: self.limit,
+            top_level: self.no_recursive,
+            max_depth: self.max_depth.map(|d| d as usize),
+        }
+    }
+}
+
+#[derive(Args, Clone)]
+struct DownloadArgs {
+    #[arg(short = 'd', long, default_value_t = String::from("./files"))]
     download_path: String, // Is there a better path option than string?
-    #[arg(long, requires = "input")]
+    #[arg(long, value_name = "FILE")]
+    sha256sums: Option<Option<PathBuf>>, // Keep a SHA256SUMS manifest of what's been downloaded, in the download path unless given a file
+    #[arg(long)]
+    fsync: bool, // Make sure each file (and its directory entry) is on disk before counting it as downloaded
+    #[arg(long, value_name = "SIZE", default_value = "1MiB", value_parser = parse_write_buffer)]
+    write_buffer: ByteSize, // How much is written to a file at a time
+    #[arg(long)]
+    preallocate: bool, // Have the filesystem set aside room for the whole file before writing it, so a big one isn't in pieces all over the disk
+    #[arg(long, conflicts_with_all = ["compress", "preallocate"])]
+    direct_io: bool, // Write files without going through the page cache (O_DIRECT), so a huge sync doesn't push out everything else cached
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[arg(long, conflicts_with_all = ["compress", "direct_io"])]
+    io_uring: bool, // Write files with io_uring instead of tokio's pool of blocking threads, for when there are a great many of them
+    #[arg(long)]
+    metadata_sidecars: bool, // Save each object's metadata, content type and storage class to <file>.s3meta.json, and upload them again from there
+    #[arg(long)]
+    xattrs: bool, // Save the same as user.s3.* extended attributes of each file, where the filesystem supports them
+    #[arg(long)]
+    preserve: bool, // Give files the mode, owner and modified time kept in mode/uid/gid/mtime (or s3cmd-attrs) metadata, and upload files with theirs
+    #[arg(long)]
+    symlinks: bool, // Upload symlinks as small objects with symlink-target metadata instead of following them, and recreate them on download
+    #[arg(long, conflicts_with_all = ["metadata_sidecars", "xattrs", "preserve"])]
+    dedup: bool, // Download objects with the same ETag and size once and hardlink the rest to it (copying where that isn't possible)
+    #[arg(long, conflicts_with_all = ["metadata_sidecars", "xattrs", "preserve", "symlinks", "dedup"])]
+    cas: bool, // Save each object's contents once in objects/<sha256> instead of under its key, with the SHA256SUMS manifest saying which key has which
+    #[arg(long, conflicts_with = "cas")]
+    snapshots: bool, // Download each run into a new dated directory, hardlinking files that haven't changed since the last one
+    #[arg(long, value_enum, default_value_t)]
+    overwrite: Overwrite, // What to do when a file is already there
+    #[arg(long, value_enum, default_value_t)]
+    order: Order, // Which objects to start on first
+    #[arg(long)]
+    backup_suffix: Option<String>, // Keep a file about to be replaced as <file><suffix> (e.g. ".bak"), like rsync --backup
+    #[arg(long)]
+    backup_dir: Option<PathBuf>, // Or move it to the same path under this directory instead
+    // Objects only ever exist in the archive, there are no files to do anything else with
+    #[arg(long, value_enum, conflicts_with_all = ["cas", "snapshots", "dedup", "metadata_sidecars", "xattrs", "symlinks", "sha256sums", "backup_suffix", "backup_dir"])]
+    archive: Option<ArchiveFormat>, // Write everything downloaded into one archive instead of a file each
+    #[arg(long, value_name = "FILE", default_value = "-", requires = "archive")]
+    archive_file: PathBuf, // "-" writes it to stdout, e.g. | zstd > backup.tar.zst (tar only)
+    // Files no longer have their keys' names or the objects' contents
+    #[arg(long, value_enum, conflicts_with_all = ["archive", "cas", "snapshots", "dedup", "symlinks", "sha256sums"])]
+    compress: Option<Compression>, // Compress each object as it's written, saved as <key>.zst or <key>.gz
+    // Files aren't the objects' contents, and a .gz key is saved without it
+    #[arg(long, conflicts_with_all = ["snapshots", "dedup", "metadata_sidecars"])]
+    decode_gzip: bool, // Decompress objects with Content-Encoding: gzip or a .gz key, saved without the .gz
+    // Files aren't where their keys say, and only a listing says what they're all called
+    #[arg(long, conflicts_with = "snapshots")]
+    flatten: bool, // Save every object in the bucket's directory under the last part of its key, adding part of a hash of the key when two have the same name
+    // The rest of a run expects files to be at <bucket>/<key>
+    #[arg(long, value_name = "TEMPLATE", conflicts_with = "snapshots")]
+    layout: Option<Layout>, // Where each object is saved under the download path, "{bucket}/{key}" unless given, {prefix_stripped_key} leaves out the --prefix
+    #[arg(long, value_enum, default_value_t)]
+    case_collisions: CaseCollisions, // What to do about keys that only differ in case, which Windows and macOS save as the same file
+    #[arg(long, value_enum, default_value_t)]
+    prefix_conflicts: PrefixConflicts, // What to do about a key like a/b when there are keys under a/b/ too
+    // Files aren't at <bucket>/<key> for keys with unsafe characters in them
+    #[arg(long, value_enum, conflicts_with = "snapshots")]
+    sanitize: Option<Sanitize>, // Rename files for keys with characters filesystems won't take, undone when uploading them
+    // Only of one object, see Cli::validate
+    #[arg(long, value_parser = parse_range, allow_hyphen_values = true, conflicts_with = "decode_gzip")]
+    range: Option<String>, // Only download these bytes of the object, e.g. 0-1048575 for the first MiB or -1024 for the last KiB
+}
+
+impl DownloadArgs {
+    /// The args given that save files under other names than their keys, or with other contents
+    /// than their objects
+    fn reshaping_args(&self) -> impl Iterator<Item = &'static str> {
+        [
+            ("--archive", self.archive.is_some()),
+            ("--cas", self.cas),
+            ("--compress", self.compress.is_some()),
+            ("--decode-gzip", self.decode_gzip),
+            ("--flatten", self.flatten),
+            ("--layout", self.layout.is_some()),
+            ("--sanitize", self.sanitize.is_some()),
+            ("--snapshots", self.snapshots),
+            ("--range", self.range.is_some()),
+        ]
+        .into_iter()
+        .filter(|(_, given)| *given)
+        .map(|(arg, _)| arg)
+    }
+
+    // --cas needs the manifest, it's the only record of where each key's contents are
+    fn sha256sums_path(&self) -> Option<PathBuf> {
+        match &self.sha256sums {
+            Some(Some(path)) => Some(path.clone()),
+            Some(None) => Some(PathBuf::from(&self.download_path).join("SHA256SUMS")),
+            None if self.cas => Some(PathBuf::from(&self.download_path).join("SHA256SUMS")),
+            None => None,
+        }
+    }
+}
+
+fn parse_write_buffer(s: &str) -> Result<ByteSize, String> {
+    match s.parse::<ByteSize>()? {
+        ByteSize(0) => Err("must be greater than 0".to_string()),
+        size => Ok(size),
+    }
+}
+
+/// "0-1048575", "1048576-" or "-1024" (the last 1024 bytes) as a Range header, inclusive like it
+fn parse_range(range: &str) -> Result<String, String> {
+    let invalid = || format!("expected <first>-<last>, <first>- or -<count> bytes, got {range}");
+    let (first, last) = range.split_once('-').ok_or_else(invalid)?;
+    let first = (!first.is_empty())
+        .then(|| first.parse::<u64>())
+        .transpose()
+        .map_err(|_| invalid())?;
+    let last = (!last.is_empty())
+        .then(|| last.parse::<u64>())
+        .transpose()
+        .map_err(|_| invalid())?;
+    match (first, last) {
+        (None, None) => Err(invalid()),
+        (Some(first), Some(last)) if last < first => Err(invalid()),
+        _ => Ok(format!("bytes={range}")),
+    }
+}
+
+#[derive(Args)]
+struct SyncArgs {
+    #[arg(long, conflicts_with = "prefix")]
+    keys_from_file: Option<String>, // "-" reads the keys from stdin
+    #[arg(short, long, conflicts_with_all = ["prefix", "keys_from_file"])]
+    key: Option<String>, // Download just this one object
+    #[arg(long, value_name = "URL", conflicts_with_all = ["keys_from_file", "key", "versions", "delete_extra"])]
+    inventory_manifest: Option<String>, // s3://.../manifest.json of an S3 Inventory report to take the keys from, rather than listing the bucket
+    // A snapshot, or what --delete-extra deletes, has to be of the bucket as it is now
+    #[arg(long, value_name = "TTL", value_parser = humantime::parse_duration, conflicts_with_all = ["keys_from_file", "key", "versions", "snapshots", "delete_extra"])]
+    list_cache: Option<Duration>, // Reuse the listing saved by a run less than this long ago (e.g. 1h) instead of listing the bucket again
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["upload_bucket", "delete_extra", "sqs_queue_url", "watch", "schedule"])]
+    list_output: Option<PathBuf>, // Write the listing (key, size, etag, last_modified, storage_class) to this file and stop, without downloading anything
+    #[arg(long, value_enum, default_value_t, requires = "list_output")]
+    list_format: ListFormat,
+    #[arg(long, requires = "key", conflicts_with = "upload_bucket")]
+    version_id: Option<String>,
+    #[arg(long, conflicts_with_all = ["keys_from_file", "key", "upload_bucket", "flatten"])]
+    versions: bool, // Download every version of every object rather than just the latest
+    // Only files saved under their keys with the objects' contents in them can be uploaded, the
+    // conflicts for the DownloadArgs are here as the subcommands share those without this
+    #[arg(long, requires_all = ["upload_profile", "upload_region"], conflicts_with_all = ["cas", "snapshots", "archive", "compress", "decode_gzip", "flatten", "layout", "range"])]
     upload_bucket: Option<String>,
     #[arg(long)]
     upload_prefix: Option<String>,
-    #[arg(long, group = "input")]
+    #[arg(long, value_name = "s/REGEX/REPLACEMENT/", requires = "upload_bucket")]
+    rename: Vec<rename::Rule>, // Upload objects under a key rewritten like sed would, e.g. 's/^raw\//processed\//', repeat to apply more than one in turn
+    #[arg(long)]
     upload_profile: Option<String>,
-    #[arg(long, group = "input")]
+    #[arg(long)]
     upload_region: Option<String>,
+    #[arg(long, value_name = "URL", requires = "upload_bucket")]
+    upload_inventory_manifest: Option<String>, // Diff against the upload bucket's inventory report rather than listing it
+    #[arg(long, requires = "upload_bucket")]
+    size_only: bool, // Only copy objects again when their size has changed, not their ETag (which differs for SSE-KMS objects whatever their contents)
+    #[arg(long, value_enum, requires = "upload_bucket")]
+    checksum_algorithm: Option<UploadChecksum>, // Have S3 check and keep this checksum of each object uploaded, so it can be verified downloading it again
+    #[arg(
+        long,
+        value_enum,
+        requires = "upload_bucket",
+        conflicts_with = "size_only"
+    )]
+    compress_upload: Option<Compression>, // Compress each file uploaded, under the same key with Content-Encoding set, for text-heavy data
+    #[arg(long, requires = "upload_bucket", conflicts_with_all = ["watch", "schedule"])]
+    watch_local: bool, // After syncing keep uploading files as they're added to the local copy of the bucket
+    #[arg(long, value_name = "URL", conflicts_with_all = ["watch", "schedule", "watch_local", "versions", "key", "keys_from_file", "archive", "flatten"])]
+    sqs_queue_url: Option<String>, // After syncing keep downloading (and uploading) objects as S3 event notifications for them arrive on this queue
+    #[arg(long, requires = "sqs_queue_url")]
+    event_bucket: Option<String>, // The bucket behind an access point given as --bucket, which is what events on the queue name
+    // Part of an object is no copy of it to delete the original for
+    #[arg(long, conflicts_with_all = ["versions", "version_id", "range"])]
+    delete_source: bool, // Move rather than copy, deleting each object from the bucket once it's been downloaded (or uploaded, with --upload-bucket)
+    // Only a full listing says what's no longer in the bucket
+    #[arg(long, conflicts_with_all = ["upload_bucket", "key", "keys_from_file", "versions", "limit", "cas", "snapshots", "archive", "compress", "decode_gzip", "flatten", "layout", "no_recursive", "max_depth"])]
+    delete_extra: bool, // Mirror the bucket, deleting local files whose objects have been deleted
+    #[arg(long, requires = "delete_extra")]
+    dry_run: bool, // Only list what --delete-extra would delete, without downloading or deleting anything
+}
+
+impl SyncArgs {
+    fn upload_options(&self, download: &DownloadArgs) -> UploadOptions {
+        UploadOptions {
+            checksum_algorithm: self.checksum_algorithm,
+            metadata_sidecars: download.metadata_sidecars,
+            preserve: download.preserve,
+            symlinks: download.symlinks,
+            compress: self.compress_upload,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let cli = Cli::parse();
+    if let Err(e) = cli.validate() {
+        e.exit();
+    }
+    // Subcommands can't take the top level --output, diff has its own
+    let output = match &cli.command {
+        Some(Command::Diff(args)) => args.output,
+        _ => cli.output,
+    };
+    // What ls, du, stat, presign, cat and select (without --output-file) print can be piped
+    // into something else, as can an archive
+    let stdout_taken = match &cli.command {
+        Some(
+            Command::Ls(_)
+            | Command::Du(_)
+            | Command::Stat(_)
+            | Command::Presign(_)
+            | Command::Cat(_),
+        ) => true,
+        Some(Command::Select(args)) => args.writes_to_stdout(),
+        _ => false,
+    } || (cli.download.archive.is_some()
+        && cli.download.archive_file.as_os_str() == "-");
+    // stdout is kept for the events with --output json
+    let tracer_provider = telemetry::init(stdout_taken || output == OutputFormat::Json);
+    let result = run(cli).await;
+    telemetry::shutdown(tracer_provider);
+    result
+}
 
-    println!("Setting up AWS download client...");
-    let download_client = create_client(cli.region, cli.profile).await;
+async fn run(cli: Cli) -> Result<(), Error> {
+    let events = Events::new(cli.output, Report::default());
+    match cli.command {
+        Some(Command::Restore(args)) => versions::restore(args, events).await,
+        Some(Command::Verify(args)) => verify::verify(args).await,
+        Some(Command::Bisync(args)) => bisync::bisync(args, events).await,
+        Some(Command::Diff(args)) => diff::diff(args).await,
+        Some(Command::Ls(args)) => ls::ls(args).await,
+        Some(Command::Du(args)) => du::du(args).await,
+        Some(Command::Stat(args)) => stat::stat(args).await,
+        Some(Command::Presign(args)) => presign::presign(args).await,
+        Some(Command::Rm(args)) => rm::rm(args).await,
+        Some(Command::Cat(args)) => cat::cat(args).await,
+        Some(Command::Select(args)) => select::select(args).await,
+        Some(Command::Cp(args)) => cp::cp(args).await,
+        Some(Command::Upload(args)) => upload::upload(args).await,
+        None => {
+            let source = cli
+                .source
+                .expect("clap requires --bucket without a subcommand");
+            // Shared by the download and upload clients so throttling on either slows both down
+            let concurrency = Concurrency::new(&cli.concurrency);
+            cli.metrics.serve(&concurrency);
+            let events = match &cli.report_csv {
+                Some(path) => Events::new(cli.output, Report::create(path)?),
+                None => events,
+            };
+            let context = Context {
+                delete_source: match cli.sync.delete_source {
+                    true => DeleteSource::new(cli.sync.upload_bucket.is_some()),
+                    false => DeleteSource::default(),
+                },
+                rename: Rules::new(&cli.sync.rename),
+                ..Context::new(cli.download.clone(), &source, concurrency.clone(), events)?
+            };
+            shutdown::handle_signals(concurrency.clone(), context.partial_files.clone());
+            let mut last_listing = LastListing::default();
+            if !cli.schedule.wait_for_first_run(&concurrency).await {
+                return Ok(());
+            }
+            loop {
+                let started = SystemTime::now();
+                let before = stats::snapshot();
+                let context = match cli.download.snapshots {
+                    true => Context {
+                        download: snapshot::for_run(&cli.download, started),
+                        ..context.clone()
+                    },
+                    false => context.clone(),
+                };
+                let result = sync(
+                    &source,
+                    &cli.sync,
+                    &cli.glacier,
+                    &cli.connection,
+                    &context,
+                    &mut last_listing,
+                )
+                .await;
+                let summary = RunSummary {
+                    bucket: &source.bucket,
+                    started,
+                    stats: stats::snapshot() - before,
+                    error: result.as_ref().err().map(|e| e.to_string()),
+                };
+                if result.is_ok() && summary.stats.failures == 0 {
+                    stats::sync_succeeded();
+                }
+                context.finish();
+                summary.print();
+                context.events.run_summary(&summary);
+                cli.notification
+                    .notify(&summary, &source, &cli.connection)
+                    .await;
+                if !cli.schedule.daemon() {
+                    return result;
+                }
+                match result {
+                    Err(e) if concurrency.stopped() => return Err(e),
+                    Err(e) => warn!("Sync failed, will try again next time: {}", e),
+                    Ok(()) => {}
+                }
+                if !cli.schedule.wait_for_next_run(&concurrency, started).await {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
 
-    println!("Obtaining list of {} objects...", cli.bucket);
-    let download_objects =
-        list_all_objects(&download_client, &cli.bucket, cli.prefix.clone()).await?;
-    println!("Found {} objects", download_objects.len());
+#[instrument(skip_all, fields(bucket = %source.bucket))]
+async fn sync(
+    source: &SourceArgs,
+    args: &SyncArgs,
+    glacier_args: &glacier::GlacierArgs,
+    connection: &ConnectionArgs,
+    context: &Context,
+    last_listing: &mut LastListing,
+) -> Result<(), Error> {
+    let (download, concurrency) = (&context.download, &context.concurrency);
+    info!("Setting up AWS download client...");
+    let download_client = create_client(
+        source.client_region(),
+        source.profile.clone(),
+        connection,
+        concurrency,
+    )
+    .await;
+    if bucket::is_multi_region_access_point(&source.bucket) {
+        info!("Using a multi-region access point, requests will go to the nearest replica");
+    }
 
-    match cli.upload_bucket {
-        Some(bucket) => {
-            if cli.upload_profile.is_none() || cli.upload_region.is_none() {
-                println!("Upload bucket specified, but no upload profile or region specified. Skipping upload...");
-                return Ok(());
+    let list_options = source.list_options();
+
+    if is_directory_bucket(&source.bucket) && (args.versions || args.version_id.is_some()) {
+        info!("Directory buckets don't support versioning, nothing to download");
+        return Ok(());
+    }
+
+    if args.versions {
+        info!("Obtaining list of {} object versions...", source.bucket);
+        let listing = versions::list_prefix_versions(
+            &download_client,
+            &source.bucket,
+            &source.prefix,
+            list_options,
+        )
+        .await?;
+        info!("Found {} object versions", listing.versions.len());
+        versions::download_all_versions(
+            &download_client,
+            &source.bucket,
+            listing.versions,
+            context,
+        )
+        .await?;
+        return Ok(());
+    }
+
+    if let (Some(key), Some(version_id)) = (args.key.as_deref(), args.version_id.as_deref()) {
+        let local_key = versions::versioned_key(key, version_id);
+        info!(
+            "Downloading version {} of {} to {}...",
+            version_id, key, local_key
+        );
+        process_object(
+            &download_client,
+            &source.bucket,
+            key,
+            Some(version_id),
+            &local_key,
+            None,
+            context,
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let download_objects = match (args.key.as_deref(), args.keys_from_file.as_deref()) {
+        (Some(key), _) => vec![Object::builder().key(key).build()],
+        (None, Some(keys_file)) => {
+            info!("Reading keys to download from {}...", keys_file);
+            let mut keys = read_keys(keys_file).await?;
+            if let Some(limit) = list_options.limit {
+                keys.truncate(limit);
+            }
+            keys
+        }
+        (None, None) => {
+            let cache = args.list_cache.map(|ttl| {
+                ListCache::new(
+                    &download.download_path,
+                    &source.bucket,
+                    &source.prefix,
+                    list_options,
+                    args.inventory_manifest.as_deref(),
+                    ttl,
+                )
+            });
+            let cached = match &cache {
+                Some(cache) => cache.load().await,
+                None => None,
+            };
+            match cached {
+                Some(objects) => objects,
+                None => {
+                    info!("Obtaining list of {} objects...", source.bucket);
+                    let objects = match args.inventory_manifest.as_deref() {
+                        Some(manifest) => {
+                            inventory::list(
+                                &download_client,
+                                manifest,
+                                &source.bucket,
+                                &source.prefix,
+                                list_options,
+                            )
+                            .await?
+                        }
+                        None => {
+                            list_cache::list_resuming(
+                                &download_client,
+                                &source.bucket,
+                                &source.prefix,
+                                list_options,
+                                &download.download_path,
+                                concurrency,
+                            )
+                            .await?
+                        }
+                    };
+                    if let Some(cache) = &cache {
+                        cache.save(&objects).await;
+                    }
+                    objects
+                }
             }
-            println!("Setting up AWS upload client...");
-            let upload_client = create_client(cli.upload_region, cli.upload_profile).await;
-            println!("Obtaining list of {:?} objects...", bucket);
-            let upload_objects = list_all_objects(&upload_client, &bucket, cli.prefix).await?;
-            println!("Found {} objects", download_objects.len());
-
-            println!("Diffing the results...");
-            let missing_items = find_missing_items(&download_objects, &upload_objects).await;
-            println!("Downloading missing items...");
-            get_missing_objects(
+        }
+    };
+    info!("Found {} objects", download_objects.len());
+    stats::listed(download_objects.len() as u64);
+    // Before any are left out for being archived, they're still in the bucket
+    let listed_keys: HashSet<String> = if args.delete_extra {
+        download_objects
+            .iter()
+            .filter_map(|o| o.key.clone())
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    if let Some(path) = args.list_output.as_deref() {
+        return list_output::write(path, args.list_format, &download_objects);
+    }
+    // From the whole listing, whether a name is taken depends on every other key rather than
+    // only what's downloaded this time
+    let names = LocalNames::find(
+        &download_objects,
+        download.flatten,
+        download.case_collisions,
+        download.prefix_conflicts,
+    )?;
+    let local_keys: Vec<&str> = download_objects
+        .iter()
+        .filter_map(|o| names.get(o.key()?))
+        .map(|local_key| decoded_key(download, local_key))
+        .collect();
+    context.paths.sanitizer().reserve(&local_keys);
+
+    let download_objects = glacier::restore_archived(
+        &download_client,
+        &source.bucket,
+        download_objects,
+        glacier_args,
+        concurrency,
+    )
+    .await?;
+
+    match args.upload_bucket.clone() {
+        Some(bucket) => {
+            info!("Setting up AWS upload client...");
+            let upload_client = create_client(
+                args.upload_region.clone(),
+                args.upload_profile.clone(),
+                connection,
+                concurrency,
+            )
+            .await;
+            info!("Obtaining list of {:?} objects...", bucket);
+            // The limit only applies to the source, the whole destination is needed to diff against
+            let upload_list_options = ListOptions {
+                limit: None,
+                ..list_options
+            };
+            // Where the prefixes' objects are uploaded to, as long as the rules only change the
+            // start of keys that's all of them
+            let upload_prefixes: Vec<String> = source
+                .prefix
+                .iter()
+                .map(|p| context.rename.apply(p).into_owned())
+                .collect();
+            let upload_objects = inventory::objects(
+                &upload_client,
+                &bucket,
+                &upload_prefixes,
+                upload_list_options,
+                args.upload_inventory_manifest.as_deref(),
+            )
+            .await?;
+            info!("Found {} objects", download_objects.len());
+
+            info!("Diffing the results...");
+            // Under the keys they'll have in the upload bucket
+            let renamed: Vec<Object> = download_objects
+                .iter()
+                .map(|o| context.rename.object(o))
+                .collect();
+            let mut missing_items = find_missing_items(&renamed, &upload_objects).await;
+            // A compressed copy's size and ETag never match the original's, so only what's
+            // missing is copied
+            let changed_items = match args.compress_upload {
+                Some(_) => HashSet::new(),
+                None => find_changed_items(&renamed, &upload_objects, args.size_only),
+            };
+            info!(
+                "{} missing and {} changed",
+                missing_items.len(),
+                changed_items.len()
+            );
+            missing_items.extend(changed_items);
+            // Back to the keys they're downloaded as
+            let source_keys: HashMap<&str, &str> = renamed
+                .iter()
+                .zip(&download_objects)
+                .filter_map(|(renamed, object)| Some((renamed.key()?, object.key()?)))
+                .collect();
+            let missing_items: HashSet<String> = missing_items
+                .iter()
+                .map(|key| {
+                    source_keys
+                        .get(key.as_str())
+                        .unwrap_or(&key.as_str())
+                        .to_string()
+                })
+                .collect();
+            stats::skipped(download_objects.len().saturating_sub(missing_items.len()) as u64);
+            info!("Downloading missing items...");
+            let outcomes = get_missing_objects(
                 &download_client,
-                &cli.bucket,
+                &source.bucket,
+                &download_objects,
                 missing_items,
-                cli.download_path.clone(),
+                &names,
+                context,
             )
             .await?;
+            print_delete_markers(&outcomes);
 
-            println!("Uploading missing items...");
-            upload_missing_objects(&upload_client, &bucket, cli.download_path.clone()).await?;
+            info!("Uploading missing items...");
+            // Only what was downloaded from the source bucket, keyed the same as it was there
+            let source_dir = format!(
+                "{}/{}",
+                download.download_path,
+                local_bucket_dir(&source.bucket)
+            );
+            let upload_options = args.upload_options(download);
+            upload_missing_objects(
+                &upload_client,
+                &bucket,
+                source_dir.clone(),
+                &names,
+                upload_options,
+                context,
+            )
+            .await?;
+            context
+                .delete_source
+                .flush(&download_client, &source.bucket)
+                .await?;
+            if args.watch_local {
+                local_watch::upload_changes(
+                    &upload_client,
+                    &bucket,
+                    source_dir,
+                    &names,
+                    upload_options,
+                    context,
+                )
+                .await?;
+            }
+            if let Some(queue_url) = args.sqs_queue_url.as_deref() {
+                let upload = sqs::Upload {
+                    client: upload_client,
+                    bucket,
+                    options: upload_options,
+                };
+                let events = sqs::EventSource {
+                    queue_url,
+                    bucket: args.event_bucket.as_deref(),
+                };
+                sqs::download_events(
+                    events,
+                    source,
+                    &download_client,
+                    &names,
+                    Some(upload),
+                    connection,
+                    context,
+                )
+                .await?;
+            }
         }
         None => {
-            let p = match cli.prefix.clone() {
-                Some(p) => p,
-                None => "".to_string(),
+            let p = if source.prefix.is_empty() {
+                "".to_string()
+            } else {
+                source.prefix.join(",")
             };
-            println!(
+            info!(
                 "No upload bucket specified, downloading everything from {}/{}",
-                cli.bucket, p
+                source.bucket, p
             );
-            download_all_objects(
+            if args.dry_run {
+                return mirror::delete_extra(
+                    &source.bucket,
+                    &source.prefix,
+                    &listed_keys,
+                    &names,
+                    context,
+                    true,
+                )
+                .await;
+            }
+            // Nothing to diff against, so on later runs in --watch mode only get what changed. A
+            // new snapshot gets everything, but what hasn't changed is linked from the last one
+            let download_objects = if download.snapshots {
+                snapshot::link_unchanged(&source.bucket, download_objects, &names, context).await
+            } else {
+                // A file deleted since it was downloaded is downloaded again
+                last_listing.changed(download_objects, |object| {
+                    let key = object.key().unwrap_or_default();
+                    names
+                        .get(key)
+                        .and_then(|local_key| saved_path(context, &source.bucket, local_key))
+                        .is_none_or(|path| std::fs::symlink_metadata(path).is_ok())
+                })
+            };
+            let outcomes = download_all_objects(
                 &download_client,
-                &cli.bucket,
+                &source.bucket,
                 download_objects,
-                cli.download_path.clone(),
+                &names,
+                context,
+                last_listing,
             )
             .await?;
+            print_delete_markers(&outcomes);
+            // Objects in the archive are only deleted once it's complete
+            context.archive.finish()?;
+            context
+                .delete_source
+                .flush(&download_client, &source.bucket)
+                .await?;
+            if args.delete_extra {
+                mirror::delete_extra(
+                    &source.bucket,
+                    &source.prefix,
+                    &listed_keys,
+                    &names,
+                    context,
+                    false,
+                )
+                .await?;
+            }
+            if let Some(queue_url) = args.sqs_queue_url.as_deref() {
+                let events = sqs::EventSource {
+                    queue_url,
+                    bucket: args.event_bucket.as_deref(),
+                };
+                sqs::download_events(
+                    events,
+                    source,
+                    &download_client,
+                    &names,
+                    None,
+                    connection,
+                    context,
+                )
+                .await?;
+            }
         }
     }
 
     Ok(())
 }
 
-async fn create_client(region: Option<String>, profile_name: Option<String>) -> Client {
-    let region = get_region(region).await;
-    println!("Using region: {}", region);
-    let credentials_provider = create_credentials_provider(profile_name).await;
-    let config = aws_config::from_env()
-        .credentials_provider(credentials_provider)
-        .region(region)
-        .load()
-        .await;
-    Client::new(&config)
+async fn read_keys(keys_file: &str) -> Result<Vec<Object>, Error> {
+    let mut contents = String::new();
+    if keys_file == "-" {
+        tokio::io::stdin().read_to_string(&mut contents).await?;
+    } else {
+        File::open(keys_file)
+            .await?
+            .read_to_string(&mut contents)
+            .await?;
+    }
+
+    // Keys can legitimately contain spaces so only strip line endings
+    let mut seen = HashSet::new();
+    Ok(contents
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|key| !key.is_empty() && seen.insert(key.to_string()))
+        .map(|key| Object::builder().key(key).build())
+        .collect())
 }
 
-async fn get_region(region: Option<String>) -> Region {
-    let default_region = RegionProviderChain::default_provider()
-        .region()
-        .await
-        .unwrap()
-        .to_string();
-    let region_str = region.unwrap_or_else(|| default_region);
-    Region::new(region_str)
+#[instrument(skip_all, fields(bucket = %bucket))]
+async fn list_prefixes(
+    client: &Client,
+    bucket: &str,
+    prefixes: &[String],
+    options: ListOptions,
+) -> Result<Vec<Object>, Error> {
+    let mut progress = ListProgress::new(Vec::new(), 0, None);
+    list_prefixes_from(client, bucket, prefixes, options, &mut progress, None).await?;
+    Ok(progress.objects)
 }
 
-async fn create_credentials_provider(
-    profile_name: Option<String>,
-) -> ProfileFileCredentialsProvider {
-    match profile_name {
-        Some(profile_name) => ProfileFileCredentialsProvider::builder()
-            .profile_name(profile_name)
-            .build(),
-        None => ProfileFileCredentialsProvider::builder().build(),
+/// Lists what's left to after `progress`, which keeps everything listed up to the point it
+/// fails or (with `concurrency`) is stopped
+async fn list_prefixes_from(
+    client: &Client,
+    bucket: &str,
+    prefixes: &[String],
+    options: ListOptions,
+    progress: &mut ListProgress,
+    concurrency: Option<&Concurrency>,
+) -> Result<(), Error> {
+    let prefixes: Vec<Option<&String>> = if prefixes.is_empty() {
+        vec![None]
+    } else {
+        prefixes.iter().map(Some).collect()
+    };
+    while let Some(prefix) = prefixes.get(progress.prefix) {
+        if options.limit.is_some_and(|l| progress.objects.len() >= l) {
+            break;
+        }
+        if let Some(prefix) = prefix {
+            info!("Listing {}/{}...", bucket, prefix);
+        }
+        list_all_objects(
+            client,
+            bucket,
+            prefix.cloned(),
+            options,
+            progress,
+            concurrency,
+        )
+        .await?;
+        progress.prefix += 1;
+        progress.start_after = None;
     }
+    Ok(())
 }
 
 async fn list_all_objects(
     client: &Client,
     bucket: &str,
     prefix: Option<String>,
-) -> Result<Vec<Object>, Error> {
+    options: ListOptions,
+    progress: &mut ListProgress,
+    concurrency: Option<&Concurrency>,
+) -> Result<(), Error> {
     let mut continuation_token: Option<String> = None;
-    let mut all_objects = Vec::new();
+
+    // Directory buckets only accept prefixes that end in a "/", so list everything in the
+    // enclosing directory and filter it down to the prefix ourselves. They don't take StartAfter
+    // either, so an interrupted listing starts that prefix again
+    let (prefix, filter) = match prefix {
+        Some(p) if is_directory_bucket(bucket) && !p.ends_with('/') => {
+            (p.rfind('/').map(|i| p[..=i].to_string()), Some(p))
+        }
+        p => (p, None),
+    };
+    let resumable = !is_directory_bucket(bucket);
+    // Only the first request, the continuation token carries on from there
+    let mut start_after = progress.start_after.clone().filter(|_| resumable);
 
     loop {
-        let resp = match prefix.as_ref() {
-            Some(p) => {
-                client
-                    .list_objects_v2()
-                    .bucket(bucket)
-                    .prefix(p)
-                    .set_continuation_token(continuation_token)
-                    .send()
-                    .await?
+        if concurrency.is_some_and(Concurrency::stopped) {
+            return Err(Error::Stopped);
+        }
+        let resp = client
+            .list_objects_v2()
+            .bucket(bucket)
+            .set_prefix(prefix.clone())
+            .set_max_keys(options.page_size(progress.objects.len()))
+            .set_delimiter(options.top_level.then(|| "/".to_string()))
+            .set_start_after(start_after.take())
+            .set_continuation_token(continuation_token)
+            .send()
+            .await?;
+
+        for object in resp.contents() {
+            let key = object.key().unwrap_or_default();
+            if let Some(filter) = filter.as_deref() {
+                if !key.starts_with(filter) {
+                    continue;
+                }
             }
-            None => {
-                client
-                    .list_objects_v2()
-                    .bucket(bucket)
-                    .set_continuation_token(continuation_token)
-                    .send()
-                    .await?
+            let listed_under = filter.as_deref().or(prefix.as_deref()).unwrap_or_default();
+            if !options.within_depth(listed_under, key) {
+                continue;
             }
-        };
+            progress.push(object.clone());
+        }
+        if resumable {
+            if let Some(last) = resp.contents().last().and_then(|o| o.key()) {
+                progress.start_after = Some(last.to_string());
+            }
+        }
 
-        for object in resp.contents() {
-            all_objects.push(object.clone())
+        if let Some(limit) = options.limit {
+            if progress.objects.len() >= limit {
+                progress.objects.truncate(limit);
+                break;
+            }
         }
 
         if let Some(is_truncated) = resp.is_truncated {
@@ -220,95 +1306,569 @@ async fn list_all_objects(
         break;
     }
 
-    Ok(all_objects)
+    Ok(())
 }
 
+#[instrument(skip_all)]
 async fn find_missing_items<'a>(
     old_bucket_items: &'a [Object],
     new_bucket_items: &'a [Object],
 ) -> HashSet<String> {
-    println!("Converting old items to a HashSet...");
+    info!("Converting old items to a HashSet...");
     let au_set: HashSet<_> = old_bucket_items
         .iter()
         .map(|object| ObjectKey::from(object).key)
         .collect();
-    println!("Converting new items to a HashSet...");
+    info!("Converting new items to a HashSet...");
     let us_set: HashSet<_> = new_bucket_items
         .iter()
         .map(|object| ObjectKey::from(object).key)
         .collect();
 
-    println!("Performing diff...");
+    info!("Performing diff...");
     au_set.difference(&us_set).cloned().collect()
 }
 
+/// Keys in both whose objects differ in size or, when both ETags are the MD5 of the contents,
+/// ETag. An object uploaded in parts has an ETag that can't be compared with one that wasn't
+fn find_changed_items(
+    old_bucket_items: &[Object],
+    new_bucket_items: &[Object],
+    size_only: bool,
+) -> HashSet<String> {
+    let new: HashMap<&str, &Object> = new_bucket_items
+        .iter()
+        .filter_map(|o| Some((o.key()?, o)))
+        .collect();
+    old_bucket_items
+        .iter()
+        .filter_map(|old| {
+            let key = old.key()?;
+            let new = new.get(key)?;
+            let etags = old.e_tag().zip(new.e_tag()).filter(|(old, new)| {
+                !size_only && etag::parts(old).is_none() && etag::parts(new).is_none()
+            });
+            let changed = old.size() != new.size() || etags.is_some_and(|(old, new)| old != new);
+            changed.then(|| key.to_string())
+        })
+        .collect()
+}
+
 async fn get_missing_objects(
     client: &Client,
     bucket: &str,
+    objects: &[Object],
     missing_items: HashSet<String>,
-    path: String,
-) -> Result<(), Error> {
+    names: &Arc<LocalNames>,
+    context: &Context,
+) -> Result<Vec<Outcome>, Error> {
+    let (download, concurrency) = (&context.download, &context.concurrency);
     let mut tasks = Vec::new();
-    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_OPERATIONS));
+    let duplicates = Duplicates::find(objects, download.dedup);
+    let mut missing: Vec<Object> = objects
+        .iter()
+        .filter(|o| o.key().is_some_and(|k| missing_items.contains(k)))
+        .cloned()
+        .collect();
+    download.order.sort_objects(&mut missing);
+    let keys: Vec<String> = missing.iter().map(|o| o.key.clone().unwrap()).collect();
 
-    for key in missing_items {
+    for object in missing {
         let client = client.clone();
         let bucket = bucket.to_string();
-        let sema_clone = semaphore.clone();
 
         // Spawn a new task for each object
-        let p = path.clone();
-        tasks.push(tokio::spawn(async move {
-            let _permit = sema_clone.acquire().await.unwrap();
-            process_object(&client, &bucket, &key, p).await
-        }));
+        let context = context.clone();
+        let duplicates = duplicates.clone();
+        let names = names.clone();
+        tasks.push(tokio::spawn(
+            async move {
+                let key = object.key().unwrap_or_default();
+                let Some(local_key) = names.get(key) else {
+                    return Ok(overwrite::skip(&context.events, &bucket, key));
+                };
+                duplicates
+                    .download(&client, &bucket, &object, local_key, &context)
+                    .await
+            }
+            .in_current_span(),
+        ));
     }
-    join_all(tasks).await;
-    Ok(())
+    let results = join_all(tasks).await;
+    save_remaining_keys(concurrency, &keys, &results, &download.download_path).await?;
+    let outcomes = successful_outcomes(results);
+    check_stopped(concurrency, &outcomes)?;
+    Ok(outcomes)
 }
 
 async fn download_all_objects(
     client: &Client,
     bucket: &str,
-    objects: Vec<Object>,
-    path: String,
-) -> Result<(), Error> {
+    mut objects: Vec<Object>,
+    names: &Arc<LocalNames>,
+    context: &Context,
+    last_listing: &mut LastListing,
+) -> Result<Vec<Outcome>, Error> {
+    let (download, concurrency) = (&context.download, &context.concurrency);
     let mut tasks = Vec::new();
-    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_OPERATIONS));
+    let duplicates = Duplicates::find(&objects, download.dedup);
+    download.order.sort_objects(&mut objects);
+    let keys: Vec<String> = objects.iter().map(|o| o.key.clone().unwrap()).collect();
 
     for object in objects {
         let client = client.clone();
         let bucket = bucket.to_string();
-        let sema_clone = semaphore.clone();
 
         // Spawn a new task for each object
-        let p = path.clone();
-        tasks.push(tokio::spawn(async move {
-            let _permit = sema_clone.acquire().await.unwrap();
-            process_object(&client, &bucket, &object.key.unwrap(), p).await
-        }));
+        let context = context.clone();
+        let duplicates = duplicates.clone();
+        let names = names.clone();
+        tasks.push(tokio::spawn(
+            async move {
+                let key = object.key().unwrap_or_default();
+                let Some(local_key) = names.get(key) else {
+                    return Ok(overwrite::skip(&context.events, &bucket, key));
+                };
+                duplicates
+                    .download(&client, &bucket, &object, local_key, &context)
+                    .await
+            }
+            .in_current_span(),
+        ));
+    }
+    let results = join_all(tasks).await;
+    for (key, _) in keys.iter().zip(&results).filter(|(_, r)| done(r)) {
+        last_listing.downloaded(key);
+    }
+    save_remaining_keys(concurrency, &keys, &results, &download.download_path).await?;
+    let outcomes = successful_outcomes(results);
+    check_stopped(concurrency, &outcomes)?;
+    Ok(outcomes)
+}
+
+/// Whether the object's file is there now, not needing another go
+fn done(result: &Result<Result<Outcome, Error>, tokio::task::JoinError>) -> bool {
+    matches!(
+        result,
+        Ok(Ok(Outcome::Downloaded
+            | Outcome::DeleteMarker
+            | Outcome::Skipped))
+    )
+}
+
+/// When the run was stopped, writes out every key that wasn't downloaded so the next run can
+/// pick up from there with --keys-from-file
+async fn save_remaining_keys(
+    concurrency: &Concurrency,
+    keys: &[String],
+    results: &[Result<Result<Outcome, Error>, tokio::task::JoinError>],
+    path: &str,
+) -> Result<(), Error> {
+    if !concurrency.stopped() {
+        return Ok(());
     }
-    join_all(tasks).await;
+    let remaining: Vec<&str> = keys
+        .iter()
+        .zip(results)
+        .filter(|(_, r)| !done(r))
+        .map(|(key, _)| key.as_str())
+        .collect();
+    if remaining.is_empty() {
+        return Ok(());
+    }
+    fs::create_dir_all(path).await?;
+    let remaining_file = format!("{}/remaining-keys.txt", path);
+    fs::write(&remaining_file, remaining.join("\n") + "\n").await?;
+    info!(
+        "Wrote the {} keys still to download to {}, pass it to --keys-from-file to carry on",
+        remaining.len(),
+        remaining_file
+    );
     Ok(())
 }
 
+fn successful_outcomes(
+    results: Vec<Result<Result<Outcome, Error>, tokio::task::JoinError>>,
+) -> Vec<Outcome> {
+    let total = results.len();
+    let outcomes: Vec<Outcome> = results
+        .into_iter()
+        .filter_map(|r| r.ok().and_then(|r| r.ok()))
+        .collect();
+    // Errors aren't in the outcomes at all, the failures that were already printed are
+    let succeeded = outcomes.iter().filter(|o| **o != Outcome::Failed).count();
+    stats::failed((total - succeeded) as u64);
+    outcomes
+}
+
+/// Errors out of the run if the breaker stopped it, after saying how much was left undone
+fn check_stopped(concurrency: &Concurrency, outcomes: &[Outcome]) -> Result<(), Error> {
+    if !concurrency.stopped() {
+        return Ok(());
+    }
+    let not_attempted = outcomes
+        .iter()
+        .filter(|o| **o == Outcome::NotAttempted)
+        .count();
+    warn!("{} objects were not attempted", not_attempted);
+    Err(Error::Stopped)
+}
+
+fn print_delete_markers(outcomes: &[Outcome]) {
+    let tombstoned = outcomes
+        .iter()
+        .filter(|o| **o == Outcome::DeleteMarker)
+        .count();
+    if tombstoned > 0 {
+        warn!(
+            "Skipped {} keys whose latest version is a delete marker",
+            tombstoned
+        );
+    }
+}
+
+/// The S3 error code and message where there is one, the SDK's own message is just "service error"
+fn error_detail(err: &Error) -> String {
+    fn sdk_detail<E: ProvideErrorMetadata + std::error::Error + 'static>(
+        err: &aws_smithy_runtime_api::client::result::SdkError<
+            E,
+            aws_smithy_runtime_api::http::Response,
+        >,
+    ) -> String {
+        match err.code() {
+            Some(code) => format!("{}: {}", code, err.message().unwrap_or_default()),
+            // Never got as far as a response, e.g. a timeout
+            None => DisplayErrorContext(err).to_string(),
+        }
+    }
+    match err {
+        Error::GetObjectError(e) => sdk_detail(e),
+        Error::PutObjectError(e) => sdk_detail(e),
+        e => e.to_string(),
+    }
+}
+
+/// Versioned buckets answer a GetObject for a deleted key with a 404 flagged as a delete marker
+fn is_delete_marker<E>(
+    err: &aws_smithy_runtime_api::client::result::SdkError<
+        E,
+        aws_smithy_runtime_api::http::Response,
+    >,
+) -> bool {
+    err.raw_response()
+        .and_then(|r| r.headers().get("x-amz-delete-marker"))
+        == Some("true")
+}
+
+/// A conditional GetObject for something that hasn't changed since
+fn is_not_modified<E>(
+    err: &aws_smithy_runtime_api::client::result::SdkError<
+        E,
+        aws_smithy_runtime_api::http::Response,
+    >,
+) -> bool {
+    err.raw_response().map(|r| r.status().as_u16()) == Some(304)
+}
+
+/// What the SDK's checksum validation fails reading a body with, somewhere down the error's sources
+fn checksum_mismatch<'a>(
+    err: &'a (dyn std::error::Error + 'static),
+) -> Option<&'a aws_smithy_checksums::body::validate::Error> {
+    let mut source = Some(err);
+    while let Some(e) = source {
+        if let Some(mismatch) = e.downcast_ref() {
+            return Some(mismatch);
+        }
+        source = e.source();
+    }
+    None
+}
+
+// With --decode-gzip a.txt.gz is saved as a.txt
+fn decoded_key<'a>(download: &DownloadArgs, local_key: &'a str) -> &'a str {
+    match local_key.strip_suffix(".gz") {
+        Some(stripped) if download.decode_gzip && !stripped.is_empty() => stripped,
+        _ => local_key,
+    }
+}
+
+/// Where `process_object` saves the object it's given as `local_key`, None with --cas where it
+/// has no file of its own
+fn saved_path(context: &Context, bucket: &str, local_key: &str) -> Option<PathBuf> {
+    let download = &context.download;
+    if download.cas {
+        return None;
+    }
+    let local_key = decoded_key(download, local_key);
+    let path = PathBuf::from(format!("{}/", download.download_path))
+        .join(context.paths.path(bucket, local_key));
+    match download.compress {
+        Some(compression) if !local_key.ends_with('/') => Some(compression.path(path)),
+        _ => Some(path),
+    }
+}
+
+#[instrument(skip_all, fields(bucket = %bucket, key = %key))]
 async fn process_object(
     client: &Client,
     bucket: &str,
     key: &str,
-    path: String,
-) -> Result<(), Error> {
-    let get_obj_resp = client.get_object().bucket(bucket).key(key).send().await?;
+    version_id: Option<&str>,
+    local_key: &str,
+    listed: Option<&Object>,
+    context: &Context,
+) -> Result<Outcome, Error> {
+    let (download, events) = (&context.download, &context.events);
+    events.object_started(Direction::Download, bucket, key);
+    let local_key = decoded_key(download, local_key);
+    let relative_path = context.paths.path(bucket, local_key);
+    if layout::escapes(&relative_path) {
+        let e = format!("{} is outside the download path", relative_path.display());
+        error!("Not downloading {}: {}", key, e);
+        events.object_failed(Direction::Download, bucket, key, &e);
+        return Ok(Outcome::Failed);
+    }
+    let mut local_path = PathBuf::from(format!("{}/", download.download_path)).join(&relative_path);
+    // Links are only recreated with --symlinks, without it any there are the user's own
+    if download.symlinks {
+        let root = Path::new(&download.download_path);
+        let outside = match symlink::through_link(root, &local_path).await {
+            Ok(outside) => outside,
+            Err(e) => {
+                error!("Got an error checking where {} would be saved: {}", key, e);
+                events.object_failed(Direction::Download, bucket, key, &e);
+                return Ok(Outcome::Failed);
+            }
+        };
+        if outside {
+            let e = format!(
+                "{} is through a link outside the download path",
+                relative_path.display()
+            );
+            error!("Not downloading {}: {}", key, e);
+            events.object_failed(Direction::Download, bucket, key, &e);
+            return Ok(Outcome::Failed);
+        }
+    }
+    // A folder made in the console is an empty object ending in "/", which can't be a file. An
+    // archive has an entry for it, otherwise the directory is all there is to it
+    if local_key.ends_with('/') && !context.archive.enabled() {
+        if !download.cas {
+            if let Err(e) = fs::create_dir_all(&local_path).await {
+                error!("Got an error creating the directory {}: {}", key, e);
+                events.object_failed(Direction::Download, bucket, key, &e);
+                return Ok(Outcome::Failed);
+            }
+        }
+        stats::skipped(1);
+        events.object_skipped(Direction::Download, bucket, key, "directory");
+        return Ok(Outcome::Skipped);
+    }
+    if let Some(compression) = download.compress {
+        local_path = compression.path(local_path);
+    }
+    let existing = match download.overwrite {
+        Overwrite::Always => None,
+        _ => overwrite::existing(&local_path).await,
+    };
+    if download.overwrite == Overwrite::Never && existing.is_some() {
+        return Ok(overwrite::skip(events, bucket, key));
+    }
+    // From the listing when it has the ETag, so an unchanged file costs no GET. A (de)compressed
+    // file is left to the headers below
+    let listed = listed
+        .filter(|o| o.e_tag().is_some() && download.compress.is_none() && !download.decode_gzip);
+    if let (Overwrite::IfDifferent, Some(existing), Some(listed)) =
+        (download.overwrite, existing, listed)
+    {
+        let same = overwrite::same_contents(
+            client,
+            bucket,
+            key,
+            &local_path,
+            existing.0,
+            listed.size(),
+            listed.e_tag(),
+        )
+        .await;
+        if same {
+            return Ok(overwrite::skip(events, bucket, key));
+        }
+    }
+    // S3 answers 304 rather than sending it again when the object is no newer than the file
+    let if_modified_since = existing
+        .filter(|_| download.overwrite == Overwrite::IfNewer)
+        .map(|(_, modified)| DateTime::from(modified));
+    let get_obj_resp = match retry_slow_down(|| {
+        client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .set_version_id(version_id.map(|v| v.to_string()))
+            .set_if_modified_since(if_modified_since)
+            .set_range(download.range.clone())
+            // The SDK checks the body against the object's CRC32C, SHA-1 or SHA-256 (when it has
+            // one) as it's read, whatever AWS_RESPONSE_CHECKSUM_VALIDATION is set to
+            .checksum_mode(ChecksumMode::Enabled)
+            .send()
+    })
+    .await
+    {
+        Ok(resp) => resp,
+        Err(e) if is_delete_marker(&e) => {
+            stats::skipped(1);
+            events.object_skipped(Direction::Download, bucket, key, "delete_marker");
+            return Ok(Outcome::DeleteMarker);
+        }
+        Err(e) if is_not_modified(&e) => return Ok(overwrite::skip(events, bucket, key)),
+        // Found out here rather than with a HeadObject for every object in the listing, unless
+        // --restore needs to know up front
+        Err(e) if e.code() == Some("InvalidObjectState") => {
+            warn!(
+                "Skipping {}, it's archived and hasn't been restored (see --restore)",
+                key
+            );
+            stats::skipped(1);
+            events.object_skipped(Direction::Download, bucket, key, "archived");
+            return Ok(Outcome::Archived);
+        }
+        Err(e) => {
+            let e = Error::from(e);
+            events.object_failed(Direction::Download, bucket, key, &error_detail(&e));
+            return Err(e);
+        }
+    };
+    context
+        .delete_source
+        .fetched(key, get_obj_resp.version_id.as_deref());
+    let sidecar =
+        (download.metadata_sidecars || download.xattrs).then(|| Sidecar::from(&get_obj_resp));
+    let link_target = download
+        .symlinks
+        .then(|| symlink::target(get_obj_resp.metadata.as_ref()))
+        .flatten();
+    let posix = download.preserve.then(|| {
+        PosixAttributes::from_metadata(get_obj_resp.metadata.as_ref().unwrap_or(&HashMap::new()))
+    });
+    // The file's own time from before it was uploaded is better than when it was uploaded
+    let last_modified = posix.and_then(|p| p.mtime).or(get_obj_resp
+        .last_modified
+        .and_then(|t| SystemTime::try_from(t).ok()));
+    let gzipped = download.decode_gzip
+        && (key.ends_with(".gz")
+            || get_obj_resp
+                .content_encoding
+                .as_deref()
+                .is_some_and(|e| e.split(',').any(|e| e.trim() == "gzip")));
+    // Without the listing it's decided from the headers, the body is dropped without being read
+    if let (Overwrite::IfDifferent, Some(existing), None) = (download.overwrite, existing, listed) {
+        // A (de)compressed file's size and ETag say nothing about the object's, only the times
+        // are compared
+        let same = match download.compress.is_some() || gzipped {
+            true => overwrite::same(existing, Some(existing.0 as i64), last_modified),
+            false => {
+                overwrite::same_contents(
+                    client,
+                    bucket,
+                    key,
+                    &local_path,
+                    existing.0,
+                    get_obj_resp.content_length,
+                    get_obj_resp.e_tag.as_deref(),
+                )
+                .await
+            }
+        };
+        if same {
+            return Ok(overwrite::skip(events, bucket, key));
+        }
+    }
+    // Held until the data is written out and dropped, along with the buffer it's written through
+    let size = get_obj_resp.content_length.unwrap_or_default().max(0) as u64;
+    let _memory = context
+        .concurrency
+        .reserve_memory(size + size.min(download.write_buffer.as_u64()))
+        .await;
     let body = match get_obj_resp.body.collect().await {
         Ok(b) => b,
         Err(e) => {
-            println!("Got an error downloading {}: {}", key, e);
-            return Ok(());
+            match checksum_mismatch(&e) {
+                Some(mismatch) => {
+                    error!("Checksum mismatch downloading {}: {}", key, mismatch);
+                    events.object_failed(Direction::Download, bucket, key, mismatch);
+                }
+                None => {
+                    error!("Got an error downloading {}: {}", key, e);
+                    events.object_failed(Direction::Download, bucket, key, &e);
+                }
+            }
+            return Ok(Outcome::Failed);
         }
     };
-    let data = body.into_bytes().to_vec();
+    let mut data = body.into_bytes().to_vec();
+    let etag = get_obj_resp.e_tag;
+    if gzipped {
+        let mut decoded = Vec::new();
+        // Multi as concatenated gzip files (e.g. appended logs) are one valid gzip file
+        if let Err(e) = MultiGzDecoder::new(&data[..]).read_to_end(&mut decoded) {
+            error!("Got an error decompressing {}: {}", key, e);
+            events.object_failed(Direction::Download, bucket, key, &e);
+            return Ok(Outcome::Failed);
+        }
+        data = decoded;
+    }
 
-    let local_path = PathBuf::from(format!("{path}/")).join(&bucket).join(&key);
+    if context.archive.enabled() {
+        if let Err(e) = context.archive.add(
+            &relative_path,
+            &data,
+            last_modified,
+            posix.and_then(|p| p.mode),
+        ) {
+            error!("Got an error adding {} to the archive: {}", key, e);
+            events.object_failed(Direction::Download, bucket, key, &e);
+            return Ok(Outcome::Failed);
+        }
+        stats::downloaded(data.len() as u64);
+        events.object_completed(
+            Direction::Download,
+            bucket,
+            key,
+            data.len() as u64,
+            etag.as_deref(),
+        );
+        return Ok(Outcome::Downloaded);
+    }
+
+    // Held until the file is in place
+    let _io = context.concurrency.io_permit().await;
+    if download.cas {
+        // Recorded under the path it would have had, which is what tells keys apart
+        match cas::store(
+            &download.download_path,
+            &data,
+            download.fsync,
+            &context.partial_files,
+        )
+        .await
+        {
+            Ok(sum) => context.manifest.add_sum(&local_path, sum),
+            Err(e) => {
+                error!("Got an error writing the contents of {}: {}", key, e);
+                events.object_failed(Direction::Download, bucket, key, &e);
+                return Ok(Outcome::Failed);
+            }
+        }
+        stats::downloaded(data.len() as u64);
+        events.object_completed(
+            Direction::Download,
+            bucket,
+            key,
+            data.len() as u64,
+            etag.as_deref(),
+        );
+        return Ok(Outcome::Downloaded);
+    }
 
     // Create the directory if it does not exist
     if let Some(parent) = local_path.parent() {
@@ -316,92 +1876,475 @@ async fn process_object(
             match fs::create_dir_all(parent).await {
                 Ok(d) => d,
                 Err(e) => {
-                    println!("Got an error create file {}: {}", key, e);
-                    return Ok(());
+                    error!("Got an error create file {}: {}", key, e);
+                    events.object_failed(Direction::Download, bucket, key, &e);
+                    return Ok(Outcome::Failed);
                 }
             };
         }
     }
 
-    let mut file = match File::create(&local_path).await {
-        Ok(f) => f,
-        Err(e) => {
-            println!("Got an error create file {}: {}", key, e);
-            return Ok(());
+    if let Some(target) = link_target {
+        let root = Path::new(&download.download_path);
+        let outside = match symlink::escapes(root, &local_path, &target).await {
+            Ok(outside) => outside,
+            Err(e) => {
+                error!("Got an error creating the symlink {}: {}", key, e);
+                events.object_failed(Direction::Download, bucket, key, &e);
+                return Ok(Outcome::Failed);
+            }
+        };
+        if outside {
+            let e = format!("its target {} is outside the download path", target);
+            error!("Not creating the symlink {}: {}", key, e);
+            events.object_failed(Direction::Download, bucket, key, &e);
+            return Ok(Outcome::Failed);
         }
-    };
-    match file.write_all(&data).await {
-        Ok(w) => w,
-        Err(e) => {
-            println!("Got an error writing file {}: {}", key, e);
-            return Ok(());
+        // An existing link to the same place is left as it is
+        let existing = symlink::read(&local_path).await.ok().flatten();
+        if existing.as_deref() != Some(Path::new(&target)) {
+            if let Err(e) = backup::keep(&local_path, None, download).await {
+                error!("Got an error backing up {}: {}", key, e);
+                events.object_failed(Direction::Download, bucket, key, &e);
+                return Ok(Outcome::Failed);
+            }
+        }
+        if let Err(e) =
+            symlink::create(&target, &local_path, download.fsync, &context.partial_files).await
+        {
+            error!("Got an error creating the symlink {}: {}", key, e);
+            events.object_failed(Direction::Download, bucket, key, &e);
+            return Ok(Outcome::Failed);
+        }
+        stats::downloaded(data.len() as u64);
+        events.object_completed(
+            Direction::Download,
+            bucket,
+            key,
+            data.len() as u64,
+            etag.as_deref(),
+        );
+        return Ok(Outcome::Downloaded);
+    }
+
+    let partial = PartialFile::new(&local_path, &context.partial_files);
+    // tokio hands writes to a blocking thread in pieces no bigger than this, and an encoder
+    // writes a lot of small ones. Nothing more than the object is ever held in it
+    let buffer_size = (download.write_buffer.as_u64() as usize).min(data.len().max(1));
+    let mut file = match () {
+        _ if download.direct_io => {
+            let written;
+            (data, written) = direct::write(partial.part_path(), data, buffer_size).await;
+            match written {
+                Ok(file) => file,
+                Err(e) => {
+                    error!("Got an error writing file {}: {}", key, e);
+                    events.object_failed(Direction::Download, bucket, key, &e);
+                    return Ok(Outcome::Failed);
+                }
+            }
+        }
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        _ if download.io_uring => {
+            let written;
+            (data, written) = uring::write(partial.part_path(), data).await;
+            match written {
+                Ok(file) => file,
+                Err(e) => {
+                    error!("Got an error writing file {}: {}", key, e);
+                    events.object_failed(Direction::Download, bucket, key, &e);
+                    return Ok(Outcome::Failed);
+                }
+            }
+        }
+        _ => {
+            let mut file = match File::create(partial.part_path()).await {
+                Ok(f) => f,
+                Err(e) => {
+                    error!("Got an error create file {}: {}", key, e);
+                    events.object_failed(Direction::Download, bucket, key, &e);
+                    return Ok(Outcome::Failed);
+                }
+            };
+            // Only when what's written is the object as it is, the file would be left longer otherwise
+            if download.preallocate && download.compress.is_none() && !data.is_empty() {
+                if let Err(e) = file.allocate(data.len() as u64).await {
+                    warn!("Couldn't preallocate {}: {}", key, e);
+                }
+            }
+            file.set_max_buf_size(buffer_size);
+            let mut writer = BufWriter::with_capacity(buffer_size, file);
+            let written = match download.compress {
+                Some(compression) => compression.write(writer, &data).await,
+                // tokio carries on writing in the background after write_all, flush waits for it
+                None => writer
+                    .write_all(&data)
+                    .await
+                    .and(writer.flush().await)
+                    .map(|()| writer),
+            };
+            match written {
+                Ok(writer) => writer.into_inner(),
+                Err(e) => {
+                    error!("Got an error writing file {}: {}", key, e);
+                    events.object_failed(Direction::Download, bucket, key, &e);
+                    return Ok(Outcome::Failed);
+                }
+            }
         }
     };
+    // The same time as in the bucket, for make, rsync and backups that go by it
+    if let Some(modified) = last_modified {
+        let std_file = file.into_std().await;
+        if let Err(e) = std_file.set_modified(modified) {
+            warn!("Couldn't set the modified time of {}: {}", key, e);
+        }
+        file = File::from_std(std_file);
+    }
+    if download.fsync {
+        if let Err(e) = file.sync_all().await {
+            error!("Got an error writing file {}: {}", key, e);
+            events.object_failed(Direction::Download, bucket, key, &e);
+            return Ok(Outcome::Failed);
+        }
+    }
+    drop(file);
+    if let Some(posix) = posix {
+        if let Err(e) = posix.apply(partial.part_path()) {
+            warn!("Couldn't set the mode or owner of {}: {}", key, e);
+        }
+    }
+    if let (true, Some(sidecar)) = (download.xattrs, &sidecar) {
+        if let Err(e) = sidecar.write_xattrs(partial.part_path()) {
+            warn!("Couldn't set the extended attributes of {}: {}", key, e);
+        }
+    }
+    if let Err(e) = backup::keep(&local_path, Some(partial.part_path()), download).await {
+        error!("Got an error backing up {}: {}", key, e);
+        events.object_failed(Direction::Download, bucket, key, &e);
+        return Ok(Outcome::Failed);
+    }
+    if let Err(e) = partial.finish(download.fsync).await {
+        error!("Got an error renaming the downloaded file {}: {}", key, e);
+        events.object_failed(Direction::Download, bucket, key, &e);
+        return Ok(Outcome::Failed);
+    }
+    if let (true, Some(sidecar)) = (download.metadata_sidecars, &sidecar) {
+        if let Err(e) = sidecar.write(&local_path).await {
+            error!("Got an error saving the metadata of {}: {}", key, e);
+            events.object_failed(Direction::Download, bucket, key, &e);
+            return Ok(Outcome::Failed);
+        }
+    }
+    context.manifest.add(&local_path, &data);
+    stats::downloaded(data.len() as u64);
+    events.object_completed(
+        Direction::Download,
+        bucket,
+        key,
+        data.len() as u64,
+        etag.as_deref(),
+    );
 
-    //println!("Downloaded and saved: {}", key);
+    debug!("Downloaded and saved: {}", key);
 
-    Ok(())
+    Ok(Outcome::Downloaded)
 }
 
-async fn upload_missing_objects(client: &Client, bucket: &str, dir: String) -> Result<(), Error> {
+async fn upload_missing_objects(
+    client: &Client,
+    bucket: &str,
+    dir: String,
+    names: &LocalNames,
+    options: UploadOptions,
+    context: &Context,
+) -> Result<(), Error> {
     let mut tasks = Vec::new();
-    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_OPERATIONS));
 
     let path_pattern = format!("{dir}/**/*");
     let file_paths = match glob(path_pattern.as_str()) {
         Ok(f) => f,
         Err(e) => {
-            println!("Error globbing: {}", e);
+            error!("Error globbing: {}", e);
             return Ok(());
         }
     };
 
     for local_path in file_paths {
         let path = local_path.unwrap();
-        if path.is_dir() {
+        // A linked directory is uploaded as the link rather than what's in it
+        let is_dir = if options.symlinks {
+            (path.is_dir() && !path.is_symlink()) || symlink::inside_link(Path::new(&dir), &path)
+        } else {
+            path.is_dir()
+        };
+        if is_dir
+            || shutdown::is_part_file(&path)
+            || (options.metadata_sidecars && sidecar::is_sidecar(&path))
+        {
             continue;
         }
         let client = client.clone();
         let target_bucket = bucket.to_string();
-        let sema_clone = semaphore.clone();
+        let context = context.clone();
+        // Files saved under a sanitized or disambiguated name go back to the key they came from
         let key = match path.strip_prefix(format!("{}/", dir).as_str()) {
-            Ok(k) => k.to_str().unwrap().to_string(),
+            Ok(k) => names
+                .key(&context.paths.sanitizer().original(k.to_str().unwrap()))
+                .to_string(),
             Err(e) => {
-                println!("Error getting key name from path: {}", e);
+                error!("Error getting key name from path: {}", e);
                 return Ok(());
             }
         };
 
-        tasks.push(tokio::spawn(async move {
-            let _permit = sema_clone.acquire().await.unwrap();
-            upload_object(&client, &target_bucket, &key, path).await
-        }));
+        tasks.push(tokio::spawn(
+            async move {
+                let Some(_permit) = context.concurrency.acquire(Direction::Upload).await else {
+                    return Ok(Outcome::NotAttempted);
+                };
+                upload_object(&client, &target_bucket, &key, path, options, &context).await?;
+                Ok(Outcome::Uploaded)
+            }
+            .in_current_span(),
+        ));
     }
 
     // Wait for all uploads to complete
-    join_all(tasks).await;
-    Ok(())
+    let outcomes = successful_outcomes(join_all(tasks).await);
+    check_stopped(&context.concurrency, &outcomes)
 }
 
+#[instrument(skip_all, fields(bucket = %bucket, key = %key))]
 async fn upload_object(
     client: &Client,
     bucket: &str,
     key: &str,
     local_path: PathBuf,
+    options: UploadOptions,
+    context: &Context,
 ) -> Result<(), Error> {
-    let mut file = File::open(&local_path).await?;
+    let events = &context.events;
+    // `key` is still what the object is called in the source bucket, to delete it there
+    let target = context.rename.apply(key);
+    events.object_started(Direction::Upload, bucket, &target);
+    match put_file(
+        client,
+        bucket,
+        &target,
+        &local_path,
+        options,
+        &context.concurrency,
+    )
+    .await
+    {
+        Ok((bytes, etag)) => {
+            stats::uploaded(bytes);
+            context.delete_source.uploaded(key);
+            events.object_completed(Direction::Upload, bucket, &target, bytes, etag.as_deref());
+            info!("Uploaded: {}", target);
+            Ok(())
+        }
+        Err(e) => {
+            events.object_failed(Direction::Upload, bucket, &target, &error_detail(&e));
+            Err(e)
+        }
+    }
+}
+
+/// Sends the file, returning how big it was and the ETag it was given
+async fn put_file(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    local_path: &PathBuf,
+    options: UploadOptions,
+    concurrency: &Concurrency,
+) -> Result<(u64, Option<String>), Error> {
+    let link = if options.symlinks {
+        symlink::read(local_path).await?
+    } else {
+        None
+    };
     let mut data = Vec::new();
-    file.read_to_end(&mut data).await?;
-
-    client
-        .put_object()
-        .bucket(bucket)
-        .key(key)
-        .body(data.into())
-        .send()
-        .await?;
+    // Held until the request has been sent and the data dropped
+    let _memory = match &link {
+        Some(target) => {
+            data.extend_from_slice(target.as_os_str().as_encoded_bytes());
+            None
+        }
+        None => {
+            let mut file = File::open(local_path).await?;
+            let memory = concurrency
+                .reserve_memory(file.metadata().await?.len())
+                .await;
+            file.read_to_end(&mut data).await?;
+            memory
+        }
+    };
+    // A link's target is left as it is, it's read back from the object as is
+    let compress = options.compress.filter(|_| link.is_none());
+    if let Some(compression) = compress {
+        data = compression.write(Vec::new(), &data).await?;
+    }
+    // Cheap to clone if the request has to be sent again
+    let data = bytes::Bytes::from(data);
+    // S3 rejects the upload if what arrives doesn't hash to this
+    let content_md5 = BASE64_STANDARD.encode(Md5::digest(&data));
+    let sidecar = if options.metadata_sidecars {
+        Sidecar::read(local_path).await?
+    } else {
+        None
+    };
+    let mut metadata = Vec::new();
+    match &link {
+        Some(target) => metadata.push((symlink::METADATA_KEY, symlink::metadata_value(target))),
+        None if options.preserve => metadata = PosixAttributes::of_file(local_path)?.to_metadata(),
+        None => {}
+    }
+
+    let output = retry_slow_down(|| {
+        let request = client
+            .put_object()
+            .bucket(bucket)
+            .key(key)
+            .body(data.clone().into())
+            .content_md5(&content_md5)
+            .set_checksum_algorithm(options.checksum_algorithm.map(ChecksumAlgorithm::from));
+        let request = match &sidecar {
+            Some(sidecar) => sidecar.apply(request),
+            None => request,
+        };
+        // After the sidecar's, which is of the file as it was
+        let request = match compress {
+            Some(compression) => request.content_encoding(compression.content_encoding()),
+            None => request,
+        };
+        metadata
+            .iter()
+            .fold(request, |request, (name, value)| {
+                request.metadata(*name, value)
+            })
+            .send()
+    })
+    .await?;
 
-    println!("Uploaded: {}", key);
+    Ok((data.len() as u64, output.e_tag))
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn range_needs_key() {
+        let cli = Cli::try_parse_from(["s3dl", "-b", "bucket", "--range", "0-9"]).unwrap();
+        assert!(cli.validate().is_err());
+        let cli =
+            Cli::try_parse_from(["s3dl", "-b", "bucket", "-k", "key", "--range", "0-9"]).unwrap();
+        assert!(cli.validate().is_ok());
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("0-9").unwrap(), "bytes=0-9");
+        assert_eq!(parse_range("100-").unwrap(), "bytes=100-");
+        assert_eq!(parse_range("-500").unwrap(), "bytes=-500");
+        for range in ["-", "9-0", "a-9", "10", ""] {
+            assert!(parse_range(range).is_err(), "{range}");
+        }
+    }
+
+    #[test]
+    fn bisync_rejects_reshaping_args() {
+        let cli =
+            Cli::try_parse_from(["s3dl", "bisync", "-b", "bucket", "--compress", "zstd"]).unwrap();
+        assert!(cli.validate().is_err());
+        let cli = Cli::try_parse_from(["s3dl", "bisync", "-b", "bucket"]).unwrap();
+        assert!(cli.validate().is_ok());
+        let cli = Cli::try_parse_from(["s3dl", "bisync", "-b", "bucket", "--limit", "10"]).unwrap();
+        assert!(cli.validate().is_err());
+    }
+
+    #[test]
+    fn delete_extra_needs_everything_listed() {
+        let parse = |arg| Cli::try_parse_from(["s3dl", "-b", "bucket", "--delete-extra", arg]);
+        assert!(parse("--no-recursive").is_err());
+        let cli = Cli::try_parse_from(["s3dl", "-b", "b", "--delete-extra", "--max-depth", "2"]);
+        assert!(cli.is_err());
+        // Objects created since the listing was cached would have their files deleted
+        assert!(parse("--list-cache=1h").is_err());
+        assert!(parse("--dry-run").is_ok());
+    }
+
+    #[test]
+    fn verify_rejects_reshaping_args() {
+        for arg in [
+            "--sanitize=replace",
+            "--layout={key}",
+            "--compress=zstd",
+            "--limit=10",
+        ] {
+            let cli = Cli::try_parse_from(["s3dl", "verify", "-b", "bucket", arg]).unwrap();
+            assert!(cli.validate().is_err());
+        }
+        // Worked out the same way as when downloading
+        let cli = Cli::try_parse_from(["s3dl", "verify", "-b", "bucket", "--flatten"]).unwrap();
+        assert!(cli.validate().is_ok());
+        // Local files are walked as deep as the bucket is listed
+        let cli =
+            Cli::try_parse_from(["s3dl", "verify", "-b", "bucket", "--no-recursive"]).unwrap();
+        assert!(cli.validate().is_ok());
+    }
+
+    #[test]
+    fn local_files_deeper_than_max_depth_are_left_out() {
+        let options = ListOptions {
+            max_depth: Some(2),
+            ..ListOptions::default()
+        };
+        assert!(options.covers(&[], "a/b.txt"));
+        assert!(!options.covers(&[], "a/b/c.txt"));
+        let prefixes = ["logs/".to_string()];
+        assert!(options.covers(&prefixes, "logs/2024/a.txt"));
+        assert!(!options.covers(&prefixes, "logs/2024/01/a.txt"));
+        assert!(!options.covers(&prefixes, "other/a.txt"));
+        assert!(ListOptions::default().covers(&prefixes, "logs/2024/01/a.txt"));
+    }
+
+    #[test]
+    fn access_point_events_need_the_bucket() {
+        let access_point = "arn:aws:s3:us-west-2:123456789012:accesspoint/my-ap";
+        let queue = "https://sqs.us-west-2.amazonaws.com/123456789012/events";
+        let parse = |args: &[&str]| {
+            let base = ["s3dl", "--sqs-queue-url", queue, "-b"];
+            Cli::try_parse_from(base.iter().chain(args)).unwrap()
+        };
+        assert!(parse(&[access_point]).validate().is_err());
+        let cli = parse(&[access_point, "--event-bucket", "my-bucket"]);
+        assert!(cli.validate().is_ok());
+        assert!(parse(&["my-bucket"]).validate().is_ok());
+        assert!(Cli::try_parse_from(["s3dl", "-b", "my-bucket", "--event-bucket", "b"]).is_err());
+    }
+
+    #[test]
+    fn write_buffer_is_not_empty() {
+        let parse = |size| Cli::try_parse_from(["s3dl", "-b", "bucket", "--write-buffer", size]);
+        assert!(parse("0").is_err());
+        assert!(parse("0KiB").is_err());
+        assert!(parse("64KiB").is_ok());
+    }
+
+    #[test]
+    fn download_args_shared_by_subcommands() {
+        for command in ["verify", "bisync", "restore"] {
+            let help = Cli::try_parse_from(["s3dl", command, "--help"]).err();
+            assert_eq!(help.map(|e| e.kind()), Some(ErrorKind::DisplayHelp));
+        }
+    }
 }
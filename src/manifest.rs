@@ -0,0 +1,201 @@
+use crate::Error;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{error, info};
+
+/// A SHA256SUMS file for everything under the download path, checkable with
+/// `sha256sum -c` from there. Does nothing without --sha256sums, cheap to clone
+#[derive(Clone, Default)]
+pub struct Manifest(Option<Arc<Mutex<Sums>>>);
+
+struct Sums {
+    path: PathBuf,
+    root: PathBuf,
+    sums: BTreeMap<String, String>, // Path relative to the root -> hex digest
+}
+
+impl Manifest {
+    /// Files downloaded by earlier runs keep their lines from the existing manifest, if there is
+    /// one
+    pub fn open(path: PathBuf, root: &str) -> Result<Self, Error> {
+        let mut sums = BTreeMap::new();
+        match std::fs::read_to_string(&path) {
+            Ok(existing) => {
+                for line in existing.lines() {
+                    if let Some((file, sum)) = parse_line(line) {
+                        sums.insert(file, sum);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Manifest(Some(Arc::new(Mutex::new(Sums {
+            path,
+            root: PathBuf::from(root),
+            sums,
+        })))))
+    }
+
+    /// Hashes what was just written to `local_path`, from the bytes already in memory rather than
+    /// reading the file back
+    pub fn add(&self, local_path: &Path, data: &[u8]) {
+        if self.0.is_some() {
+            self.add_sum(local_path, format!("{:x}", Sha256::digest(data)));
+        }
+    }
+
+    /// The same, for when the digest is already known
+    pub fn add_sum(&self, local_path: &Path, sum: String) {
+        let Some(manifest) = &self.0 else {
+            return;
+        };
+        let mut manifest = manifest.lock().unwrap_or_else(|e| e.into_inner());
+        let file = local_path
+            .strip_prefix(&manifest.root)
+            .unwrap_or(local_path)
+            .to_string_lossy()
+            .into_owned();
+        manifest.sums.insert(file, sum);
+    }
+
+    /// `local_path` has the same contents as `original`, which was just added
+    pub fn add_copy(&self, local_path: &Path, original: &Path) {
+        let Some(manifest) = &self.0 else {
+            return;
+        };
+        let mut manifest = manifest.lock().unwrap_or_else(|e| e.into_inner());
+        let relative = |path: &Path| {
+            path.strip_prefix(&manifest.root)
+                .unwrap_or(path)
+                .to_string_lossy()
+                .into_owned()
+        };
+        let (file, original) = (relative(local_path), relative(original));
+        if let Some(sum) = manifest.sums.get(&original).cloned() {
+            manifest.sums.insert(file, sum);
+        }
+    }
+
+    /// Rewrites the manifest with everything downloaded so far, called at the end of each run
+    pub fn write(&self) {
+        let Some(manifest) = &self.0 else {
+            return;
+        };
+        let manifest = manifest.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+        for (file, sum) in &manifest.sums {
+            out.push_str(&format_line(file, sum));
+        }
+        // Replaced in one go so a run killed part way through doesn't leave half a manifest
+        let temp = manifest.path.with_extension("tmp");
+        let result =
+            std::fs::write(&temp, out).and_then(|_| std::fs::rename(&temp, &manifest.path));
+        match result {
+            Ok(()) => info!(
+                "Wrote {} checksums to {}",
+                manifest.sums.len(),
+                manifest.path.display()
+            ),
+            Err(e) => error!("Got an error writing {}: {}", manifest.path.display(), e),
+        }
+    }
+}
+
+// The same as sha256sum prints, which escapes names with a backslash or newline in them and
+// marks the line with a leading backslash
+fn format_line(file: &str, sum: &str) -> String {
+    if file.contains(['\\', '\n']) {
+        let escaped = file.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{}  {}\n", sum, escaped)
+    } else {
+        format!("{}  {}\n", sum, file)
+    }
+}
+
+fn parse_line(line: &str) -> Option<(String, String)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (sum, file) = line.split_once("  ")?;
+    let file = if escaped {
+        unescape(file)
+    } else {
+        file.to_string()
+    };
+    Some((file, sum.to_string()))
+}
+
+fn unescape(file: &str) -> String {
+    let mut out = String::with_capacity(file.len());
+    let mut chars = file.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                out.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                out.push('\\');
+                chars.next();
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn lines() {
+        for file in [
+            "a.txt",
+            "dir/with spaces.txt",
+            "back\\slash",
+            "new\nline",
+            "\\n",
+        ] {
+            let line = format_line(file, HELLO);
+            assert_eq!(line.starts_with('\\'), file.contains(['\\', '\n']));
+            assert_eq!(line.matches('\n').count(), 1);
+            let parsed = parse_line(line.trim_end_matches('\n'));
+            assert_eq!(parsed, Some((file.to_string(), HELLO.to_string())));
+        }
+        assert_eq!(parse_line("not a checksum line"), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let root = std::env::temp_dir().join(format!("s3dl-manifest-{}", std::process::id()));
+        let path = root.join("SHA256SUMS");
+        std::fs::create_dir_all(&root).unwrap();
+        let root_str = root.to_str().unwrap();
+
+        let manifest = Manifest::open(path.clone(), root_str).unwrap();
+        manifest.add(&root.join("dir/a.txt"), b"hello");
+        manifest.add_copy(&root.join("b.txt"), &root.join("dir/a.txt"));
+        manifest.add_copy(&root.join("c.txt"), &root.join("missing.txt"));
+        manifest.write();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written, format!("{HELLO}  b.txt\n{HELLO}  dir/a.txt\n"));
+
+        // A later run keeps the earlier lines
+        let manifest = Manifest::open(path.clone(), root_str).unwrap();
+        manifest.add_sum(&root.join("new\nline"), "00".into());
+        manifest.write();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            written,
+            format!("{HELLO}  b.txt\n{HELLO}  dir/a.txt\n\\00  new\\nline\n")
+        );
+    }
+}
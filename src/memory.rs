@@ -0,0 +1,46 @@
+use bytesize::ByteSize;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Bytes per permit, a semaphore only has so many
+const UNIT: u64 = 1024;
+
+/// How much of the objects being transferred can be held in memory at once, no limit without
+/// --max-memory
+#[derive(Clone, Debug, Default)]
+pub struct Memory(Option<Budget>);
+
+#[derive(Clone, Debug)]
+struct Budget {
+    semaphore: Arc<Semaphore>,
+    total: u32, // Permits
+}
+
+impl Memory {
+    pub fn new(max: Option<ByteSize>) -> Self {
+        Memory(max.map(|max| {
+            let total = permits(max.as_u64());
+            Budget {
+                semaphore: Arc::new(Semaphore::new(total as usize)),
+                total,
+            }
+        }))
+    }
+
+    /// Waits until `bytes` more can be held in memory without going over --max-memory, given
+    /// back when the permit is dropped. An object bigger than all of it waits for everything
+    /// else to finish and then has it to itself, rather than never being transferred
+    pub async fn reserve(&self, bytes: u64) -> Option<OwnedSemaphorePermit> {
+        let budget = self.0.as_ref()?;
+        budget
+            .semaphore
+            .clone()
+            .acquire_many_owned(permits(bytes).min(budget.total))
+            .await
+            .ok()
+    }
+}
+
+fn permits(bytes: u64) -> u32 {
+    bytes.div_ceil(UNIT).clamp(1, u32::MAX as u64) as u32
+}
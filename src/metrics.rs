@@ -0,0 +1,219 @@
+use crate::concurrency::Concurrency;
+use crate::stats;
+use clap::Args;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info};
+
+// Scrapers send a few hundred bytes, anything bigger or slower isn't one
+const MAX_REQUEST: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Args)]
+pub struct MetricsArgs {
+    #[arg(long, value_name = "ADDRESS")]
+    metrics_address: Option<SocketAddr>, // Serve Prometheus metrics at /metrics, e.g. 0.0.0.0:9100 while running with --watch or --schedule
+}
+
+impl MetricsArgs {
+    /// Serves /metrics in the background for as long as the process runs
+    pub fn serve(&self, concurrency: &Concurrency) {
+        let Some(address) = self.metrics_address else {
+            return;
+        };
+        // Bound here rather than in the task so a bad address is reported before anything starts
+        let listener = match std::net::TcpListener::bind(address)
+            .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+            .and_then(TcpListener::from_std)
+        {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Got an error serving metrics on {}: {}", address, e);
+                return;
+            }
+        };
+        info!("Serving metrics on http://{}/metrics", address);
+        let concurrency = concurrency.clone();
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        error!("Got an error serving metrics: {}", e);
+                        continue;
+                    }
+                };
+                let concurrency = concurrency.clone();
+                tokio::spawn(async move {
+                    let _ =
+                        tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, &concurrency)).await;
+                });
+            }
+        });
+    }
+}
+
+// Just enough HTTP/1.1 for a scraper: one GET per connection, closed after the response
+async fn respond(mut stream: TcpStream, concurrency: &Concurrency) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let (status, body) = match is_metrics_request(&request) {
+        true => ("200 OK", render(concurrency)),
+        false => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\ncontent-type: text/plain; version=0.0.4\r\ncontent-length: {}\r\n\
+         connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// "GET /metrics HTTP/1.1", with or without a query string
+fn is_metrics_request(request: &[u8]) -> bool {
+    let line = request.split(|b| *b == b'\r').next().unwrap_or_default();
+    let mut parts = line.split(|b| *b == b' ');
+    let (method, target) = (parts.next(), parts.next());
+    let path = target.and_then(|t| t.split(|b| *b == b'?').next());
+    method == Some(b"GET") && path == Some(b"/metrics")
+}
+
+// Prometheus text exposition format
+fn render(concurrency: &Concurrency) -> String {
+    let totals = stats::snapshot();
+    let metrics = [
+        (
+            "objects_listed_total",
+            "counter",
+            "Objects found listing the source bucket",
+            totals.objects_listed,
+        ),
+        (
+            "objects_downloaded_total",
+            "counter",
+            "Objects downloaded",
+            totals.objects_downloaded,
+        ),
+        (
+            "bytes_downloaded_total",
+            "counter",
+            "Bytes downloaded",
+            totals.bytes_downloaded,
+        ),
+        (
+            "objects_uploaded_total",
+            "counter",
+            "Objects uploaded",
+            totals.objects_uploaded,
+        ),
+        (
+            "bytes_uploaded_total",
+            "counter",
+            "Bytes uploaded",
+            totals.bytes_uploaded,
+        ),
+        (
+            "objects_skipped_total",
+            "counter",
+            "Objects that didn't need transferring",
+            totals.objects_skipped,
+        ),
+        (
+            "failures_total",
+            "counter",
+            "Objects that couldn't be transferred",
+            totals.failures,
+        ),
+        (
+            "transfers_in_flight",
+            "gauge",
+            "Transfers running right now",
+            stats::in_flight(),
+        ),
+        (
+            "concurrency_limit",
+            "gauge",
+            "How many transfers are allowed to run at once",
+            concurrency.limit() as u64,
+        ),
+        (
+            "last_success_timestamp_seconds",
+            "gauge",
+            "When the last sync without errors finished, 0 if none has yet",
+            stats::last_success(),
+        ),
+    ];
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(out, "# HELP s3_downloader_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE s3_downloader_{} {}", name, kind);
+        let _ = writeln!(out, "s3_downloader_{} {}", name, value);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concurrency::ConcurrencyArgs;
+    use clap::Parser;
+
+    async fn get(request: &str) -> String {
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            concurrency: ConcurrencyArgs,
+        }
+        let concurrency = Concurrency::new(&Cli::parse_from(["s3dl"]).concurrency);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let served = tokio::spawn(async move { respond(server, &concurrency).await });
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        served.await.unwrap().unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn metrics() {
+        let response = get("GET /metrics?x=1 HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("content-length: {}\r\n", body.len())));
+        assert!(body.contains("# TYPE s3_downloader_objects_downloaded_total counter\n"));
+        assert!(body.contains("\ns3_downloader_concurrency_limit "));
+    }
+
+    #[tokio::test]
+    async fn anything_else() {
+        for request in [
+            "GET / HTTP/1.1\r\n\r\n",
+            "POST /metrics HTTP/1.1\r\n\r\n",
+            "GET /metricsx HTTP/1.1\r\n\r\n",
+        ] {
+            let response = get(request).await;
+            assert!(
+                response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+                "{request}"
+            );
+            assert!(response.ends_with("content-length: 0\r\nconnection: close\r\n\r\n"));
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::bucket::local_bucket_dir;
+use crate::context::Context;
+use crate::local_names::LocalNames;
+use crate::{sidecar, verify, Error, ListOptions};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tracing::{error, info};
+
+/// With --delete-extra, removes the files under the bucket's directory that don't have an
+/// object any more, and then any directories that leaves empty. `keys` has to be everything
+/// listed, not only what was downloaded, and `names` what they're saved as. With `dry_run`
+/// they're only printed
+pub async fn delete_extra(
+    bucket: &str,
+    prefixes: &[String],
+    keys: &HashSet<String>,
+    names: &LocalNames,
+    context: &Context,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let download = &context.download;
+    let bucket_dir = PathBuf::from(local_bucket_dir(bucket));
+    let dir = PathBuf::from(&download.download_path).join(&bucket_dir);
+    // Where each object is saved under the download path, worked out the same way as when
+    // downloading it so a file that was renamed isn't taken for one without an object
+    let expected: HashSet<PathBuf> = keys
+        .iter()
+        .filter_map(|key| names.get(key))
+        .map(|local| context.paths.path(bucket, local))
+        .collect();
+    // --delete-extra can't be used with a listing that leaves anything out
+    let local = verify::local_files(&dir, prefixes, ListOptions::default(), download)?;
+    let extra: Vec<&String> = local
+        .keys()
+        .filter(|name| !expected.contains(&bucket_dir.join(name)))
+        .collect();
+    if dry_run {
+        for name in &extra {
+            info!("Would delete: {}", name);
+        }
+        info!("{} files would be deleted", extra.len());
+        return Ok(());
+    }
+
+    let mut deleted = 0;
+    for name in extra {
+        let path = dir.join(name);
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            error!("Got an error deleting {}: {}", path.display(), e);
+            continue;
+        }
+        info!("Deleted: {}", name);
+        deleted += 1;
+        if download.metadata_sidecars {
+            let _ = tokio::fs::remove_file(sidecar::path_for(&path)).await;
+        }
+        remove_empty_parents(&download.download_path, &dir, &path, &expected).await;
+    }
+    info!("Deleted {} files that aren't in {}", deleted, bucket);
+    Ok(())
+}
+
+// Stops at the first that isn't empty, or that a directory marker object is for
+async fn remove_empty_parents(
+    download_path: &str,
+    dir: &Path,
+    path: &Path,
+    expected: &HashSet<PathBuf>,
+) {
+    for parent in path.ancestors().skip(1) {
+        if !parent.starts_with(dir) || parent == dir {
+            break;
+        }
+        let marker = parent.strip_prefix(download_path).unwrap_or(parent);
+        if expected.contains(marker) || tokio::fs::remove_dir(parent).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::concurrency::{Concurrency, ConcurrencyArgs};
+    use crate::events::{Events, OutputFormat};
+    use crate::local_names::{CaseCollisions, PrefixConflicts};
+    use crate::report::Report;
+    use crate::{DownloadArgs, SourceArgs};
+    use aws_sdk_s3::types::Object;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        source: SourceArgs,
+        #[command(flatten)]
+        download: DownloadArgs,
+        #[command(flatten)]
+        concurrency: ConcurrencyArgs,
+    }
+
+    #[tokio::test]
+    async fn only_files_without_an_object_are_deleted() {
+        let dir = std::env::temp_dir().join(format!("s3dl-mirror-{}", std::process::id()));
+        for (path, contents) in [("a.txt", "a"), ("old/b.txt", "b"), ("keep/c.txt", "c")] {
+            let path = dir.join("bucket").join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        let download_path = dir.to_string_lossy().to_string();
+        let cli = Cli::parse_from(["s3dl", "-b", "bucket", "-d", &download_path]);
+        let concurrency = Concurrency::new(&cli.concurrency);
+        let events = Events::new(OutputFormat::Text, Report::default());
+        let context = Context::new(cli.download, &cli.source, concurrency, events).unwrap();
+        // keep/ is a directory marker
+        let keys: HashSet<String> = ["a.txt", "keep/"].map(String::from).into();
+        let objects: Vec<Object> = keys
+            .iter()
+            .map(|key| Object::builder().key(key).build())
+            .collect();
+        let names = LocalNames::find(
+            &objects,
+            false,
+            CaseCollisions::Warn,
+            PrefixConflicts::Suffix,
+        )
+        .unwrap();
+
+        delete_extra("bucket", &[], &keys, &names, &context, true)
+            .await
+            .unwrap();
+        let dry_run_left = dir.join("bucket/old/b.txt").exists();
+        delete_extra("bucket", &[], &keys, &names, &context, false)
+            .await
+            .unwrap();
+        let left: Vec<bool> = ["a.txt", "old", "keep", "keep/c.txt"]
+            .iter()
+            .map(|path| dir.join("bucket").join(path).exists())
+            .collect();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(dry_run_left);
+        assert_eq!(left, [true, false, true, false]);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::client::{get_region, reqwest_client, ConnectionArgs};
+use crate::concurrency::xml_value;
+use crate::signing::SigningClient;
+use crate::stats::Snapshot;
+use crate::{Error, SourceArgs};
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::Args;
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+// A slow receiver shouldn't hold up the next sync
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to tell about each run once it's finished
+#[derive(Args)]
+pub struct NotificationArgs {
+    #[arg(long, value_name = "URL")]
+    webhook_url: Option<String>, // POSTed a JSON summary of each run, successful or not
+    #[arg(long, value_name = "ARN")]
+    sns_topic_arn: Option<String>, // Published the same summary, using the source bucket's profile
+}
+
+/// What a run did, sent on to whatever is listening
+pub struct RunSummary<'a> {
+    pub bucket: &'a str,
+    pub started: SystemTime,
+    pub stats: Snapshot,
+    pub error: Option<String>, // Why the run as a whole failed
+}
+
+impl RunSummary<'_> {
+    pub fn status(&self) -> &'static str {
+        if self.error.is_none() {
+            "succeeded"
+        } else {
+            "failed"
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.started.elapsed().unwrap_or_default()
+    }
+
+    /// How much of the run actually succeeded, as the last thing it says
+    pub fn print(&self) {
+        let stats = &self.stats;
+        let bytes = stats.bytes_downloaded + stats.bytes_uploaded;
+        let duration = self.duration();
+        info!(
+            "Summary: {} listed, {} downloaded, {} uploaded, {} skipped, {} failed",
+            stats.objects_listed,
+            stats.objects_downloaded,
+            stats.objects_uploaded,
+            stats.objects_skipped,
+            stats.failures
+        );
+        info!(
+            "Transferred {} in {:.1}s ({}/s)",
+            format_bytes(bytes),
+            duration.as_secs_f64(),
+            format_bytes((bytes as f64 / duration.as_secs_f64().max(0.001)) as u64)
+        );
+    }
+
+    pub fn to_json(&self) -> Value {
+        let started: DateTime<Utc> = self.started.into();
+        json!({
+            "status": self.status(),
+            "error": self.error,
+            "bucket": self.bucket,
+            "started_at": started.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "duration_seconds": self.duration().as_secs_f64(),
+            "objects_listed": self.stats.objects_listed,
+            "objects_downloaded": self.stats.objects_downloaded,
+            "bytes_downloaded": self.stats.bytes_downloaded,
+            "objects_uploaded": self.stats.objects_uploaded,
+            "bytes_uploaded": self.stats.bytes_uploaded,
+            "objects_skipped": self.stats.objects_skipped,
+            "failed_objects": self.stats.failures,
+        })
+    }
+}
+
+impl NotificationArgs {
+    /// Failing to notify is printed but doesn't fail the run
+    pub async fn notify(
+        &self,
+        summary: &RunSummary<'_>,
+        source: &SourceArgs,
+        connection: &ConnectionArgs,
+    ) {
+        if let Some(url) = &self.webhook_url {
+            if let Err(e) = post_webhook(url, summary.to_json(), connection).await {
+                error!("Got an error calling the webhook {}: {}", url, e);
+            }
+        }
+        if let Some(topic_arn) = &self.sns_topic_arn {
+            if let Err(e) = publish_to_sns(topic_arn, summary, source, connection).await {
+                error!("Got an error publishing to {}: {}", topic_arn, e);
+            }
+        }
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+async fn post_webhook(url: &str, payload: Value, connection: &ConnectionArgs) -> Result<(), Error> {
+    reqwest_client(connection)?
+        .post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .json(&payload)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn publish_to_sns(
+    topic_arn: &str,
+    summary: &RunSummary<'_>,
+    source: &SourceArgs,
+    connection: &ConnectionArgs,
+) -> Result<(), Error> {
+    // arn:aws:sns:<region>:<account>:<name>
+    let region = match topic_arn.split(':').nth(3).filter(|r| !r.is_empty()) {
+        Some(region) => region.to_string(),
+        None => get_region(source.client_region()).await.to_string(),
+    };
+    // Same overrides the SDK clients honour, e.g. for LocalStack
+    let url = std::env::var("AWS_ENDPOINT_URL_SNS")
+        .or_else(|_| std::env::var("AWS_ENDPOINT_URL"))
+        .unwrap_or_else(|_| format!("https://sns.{}.amazonaws.com/", region));
+    let subject = format!(
+        "rust-s3-downloader sync of {} {}",
+        summary.bucket,
+        summary.status()
+    );
+    let message = serde_json::to_string_pretty(&summary.to_json()).unwrap_or_default();
+    let body = form_urlencoded::Serializer::new(String::new())
+        .append_pair("Action", "Publish")
+        .append_pair("Version", "2010-03-31")
+        .append_pair("TopicArn", topic_arn)
+        // Subjects are limited to 100 characters
+        .append_pair("Subject", &subject.chars().take(100).collect::<String>())
+        .append_pair("Message", &message)
+        .finish();
+
+    let client = SigningClient::new("sns", region, source.profile.clone(), connection).await?;
+    let headers = [("content-type", "application/x-www-form-urlencoded")];
+    let response = client.post(&url, &headers, body).await?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.bytes().await?;
+        return Err(Error::SnsError(format!(
+            "{} {}: {}",
+            status.as_u16(),
+            xml_value(&body, "Code").unwrap_or_default(),
+            xml_value(&body, "Message").unwrap_or_default()
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use aws_sdk_s3::types::Object;
+use clap::ValueEnum;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Which objects are started first, the rest waiting for a free transfer in this order
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Order {
+    #[default]
+    Listed, // As they came from the listing
+    SmallestFirst, // The many small objects are done early and the long tail is a few big ones
+    LargestFirst,  // A giant object listed last doesn't start last and hold up the end of the run
+    Interleaved, // Taking turns between sizes (1 GB+, 100 MB+, ... largest of each first), the big ones start early and small ones keep finishing
+}
+
+impl Order {
+    pub fn sort_objects(self, objects: &mut Vec<Object>) {
+        self.sort(objects, |o| o.size().unwrap_or_default());
+    }
+
+    // Items the same size stay as they were
+    fn sort<T>(self, items: &mut Vec<T>, size: impl Fn(&T) -> i64) {
+        match self {
+            Order::Listed => {}
+            Order::SmallestFirst => items.sort_by_key(size),
+            Order::LargestFirst => items.sort_by_key(|i| Reverse(size(i))),
+            Order::Interleaved => {
+                items.sort_by_key(|i| Reverse(size(i)));
+                // Buckets by order of magnitude, the nth of each bucket before the n+1th of any
+                let mut taken: HashMap<u32, usize> = HashMap::new();
+                let mut ranked: Vec<((usize, Reverse<u32>), T)> = items
+                    .drain(..)
+                    .map(|item| {
+                        let bucket = size(&item).max(1).ilog10();
+                        let turn = taken.entry(bucket).or_default();
+                        *turn += 1;
+                        ((*turn, Reverse(bucket)), item)
+                    })
+                    .collect();
+                ranked.sort_by_key(|(rank, _)| *rank);
+                items.extend(ranked.into_iter().map(|(_, item)| item));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(order: Order, sizes: &[i64]) -> Vec<i64> {
+        let mut objects: Vec<Object> = sizes
+            .iter()
+            .map(|s| Object::builder().size(*s).build())
+            .collect();
+        order.sort_objects(&mut objects);
+        objects.iter().map(|o| o.size().unwrap()).collect()
+    }
+
+    #[test]
+    fn smallest_first() {
+        let sizes = [5, 5000, 7, 6000, 50];
+        assert_eq!(sorted(Order::Listed, &sizes), sizes);
+        assert_eq!(sorted(Order::SmallestFirst, &sizes), [5, 7, 50, 5000, 6000]);
+    }
+
+    #[test]
+    fn size_aware() {
+        let sizes = [5, 5000, 7, 6000, 50];
+        assert_eq!(sorted(Order::LargestFirst, &sizes), [6000, 5000, 50, 7, 5]);
+        assert_eq!(sorted(Order::Interleaved, &sizes), [6000, 50, 7, 5000, 5]);
+    }
+}
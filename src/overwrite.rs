@@ -0,0 +1,125 @@
+use crate::events::{Direction, Events};
+use crate::{etag, stats, Outcome};
+use aws_sdk_s3::Client;
+use clap::ValueEnum;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// What to do about a file that's already where an object would be downloaded to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Overwrite {
+    Never,       // Keep whatever is there
+    IfNewer,     // Only when the object was modified after the file was
+    IfDifferent, // Only when the size or ETag don't match the object's (the modified time, for --compress and --decode-gzip)
+    #[default]
+    Always,
+}
+
+/// The size and modified time of what's already at `path`, None if there's nothing
+pub async fn existing(path: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+/// For --overwrite if-different, whether the file at `path` that's `size` bytes has the object's
+/// contents. When the sizes match the file's ETag is worked out to compare, which for an object
+/// uploaded in parts means asking S3 how big they were
+pub async fn same_contents(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    path: &Path,
+    size: u64,
+    object_size: Option<i64>,
+    object_etag: Option<&str>,
+) -> bool {
+    let Some(object_etag) = object_etag.map(|e| e.trim_matches('"')) else {
+        return false;
+    };
+    if object_size != Some(size as i64) {
+        return false;
+    }
+    let part_size = match etag::parts(object_etag) {
+        Some(_) => match etag::part_size(client, bucket, key).await {
+            Ok(part_size) => Some(part_size),
+            Err(e) => {
+                warn!(
+                    "Couldn't find the part size of {}, downloading it: {}",
+                    key, e
+                );
+                return false;
+            }
+        },
+        None => None,
+    };
+    match etag::local_etag(path, part_size).await {
+        Ok(local_etag) => local_etag == object_etag,
+        Err(e) => {
+            warn!(
+                "Couldn't read {}, downloading it again: {}",
+                path.display(),
+                e
+            );
+            false
+        }
+    }
+}
+
+/// Whether a file has this size and modified time. Downloads are given the object's modified
+/// time, to the second as that's all the Last-Modified header has
+pub fn same(existing: (u64, SystemTime), size: Option<i64>, modified: Option<SystemTime>) -> bool {
+    let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+    size == Some(existing.0 as i64) && modified.and_then(secs) == secs(existing.1)
+}
+
+/// Leaves the file alone, counting it as skipped
+pub fn skip(events: &Events, bucket: &str, key: &str) -> Outcome {
+    stats::skipped(1);
+    events.object_skipped(Direction::Download, bucket, key, "exists");
+    Outcome::Skipped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn same_size_and_second() {
+        let modified = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+        let existing = (10, modified);
+        let header = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert!(same(existing, Some(10), Some(header)));
+        assert!(!same(existing, Some(11), Some(header)));
+        assert!(!same(
+            existing,
+            Some(10),
+            Some(header + Duration::from_secs(1))
+        ));
+        assert!(!same(existing, None, Some(header)));
+        assert!(!same(existing, Some(10), None));
+    }
+
+    #[tokio::test]
+    async fn same_contents_by_etag() {
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version_latest()
+            .build();
+        let client = Client::from_conf(config);
+        let path = std::env::temp_dir().join(format!("s3dl-overwrite-{}", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+        let etag = "\"781e5e245d69b566979b86e28d23f2c7\"";
+        let same = |size, etag| same_contents(&client, "bucket", "key", &path, 10, size, etag);
+
+        let matching = same(Some(10), Some(etag)).await;
+        let resized = same(Some(11), Some(etag)).await;
+        let changed = same(Some(10), Some("\"0123456789abcdef0123456789abcdef\"")).await;
+        let unknown = same(Some(10), None).await;
+        let existing = existing(&path).await;
+        let _ = std::fs::remove_file(&path);
+        assert!(matching);
+        assert!(!resized && !changed && !unknown);
+        assert_eq!(existing.map(|(size, _)| size), Some(10));
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// File mode, owner and modified time as s3fs, s3cmd and others keep them in object metadata,
+/// either as separate mode, uid, gid and mtime keys or all together in s3cmd-attrs
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PosixAttributes {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub mtime: Option<SystemTime>,
+}
+
+impl PosixAttributes {
+    pub fn from_metadata(metadata: &HashMap<String, String>) -> Self {
+        // s3cmd: "atime:1700000000/ctime:1700000000/gid:0/gname:root/mode:33188/..."
+        let s3cmd: HashMap<&str, &str> = metadata
+            .get("s3cmd-attrs")
+            .map(|attrs| {
+                attrs
+                    .split('/')
+                    .filter_map(|attr| attr.split_once(':'))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let get = |name: &str| {
+            metadata
+                .get(name)
+                .map(String::as_str)
+                .or_else(|| s3cmd.get(name).copied())
+        };
+        PosixAttributes {
+            mode: get("mode").and_then(|m| m.parse().ok()),
+            uid: get("uid").and_then(|u| u.parse().ok()),
+            gid: get("gid").and_then(|g| g.parse().ok()),
+            // Whole seconds, or with a fraction as rclone writes it
+            mtime: get("mtime")
+                .and_then(|m| m.parse::<f64>().ok())
+                .filter(|m| m.is_finite() && *m >= 0.0)
+                .map(|m| SystemTime::UNIX_EPOCH + Duration::from_secs_f64(m)),
+        }
+    }
+
+    /// Metadata to upload the file with, the same keys s3fs uses
+    pub fn to_metadata(self) -> Vec<(&'static str, String)> {
+        let mtime = self.mtime.and_then(|m| {
+            m.duration_since(SystemTime::UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs())
+        });
+        [
+            ("mode", self.mode.map(|m| m.to_string())),
+            ("uid", self.uid.map(|u| u.to_string())),
+            ("gid", self.gid.map(|g| g.to_string())),
+            ("mtime", mtime.map(|m| m.to_string())),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .collect()
+    }
+
+    #[cfg(unix)]
+    pub fn of_file(path: &Path) -> std::io::Result<Self> {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = std::fs::metadata(path)?;
+        Ok(PosixAttributes {
+            mode: Some(metadata.mode()),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            mtime: metadata.modified().ok(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn of_file(path: &Path) -> std::io::Result<Self> {
+        Ok(PosixAttributes {
+            mtime: std::fs::metadata(path)?.modified().ok(),
+            ..Default::default()
+        })
+    }
+
+    /// The permission bits and owner, the modified time is set along with the rest of the file.
+    /// Setuid, setgid and sticky bits are never applied from a bucket, and changing the owner
+    /// only works as root
+    #[cfg(unix)]
+    pub fn apply(&self, path: &Path) -> std::io::Result<()> {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        let current = std::fs::metadata(path)?;
+        // Not trying when it's already right keeps this working for anyone but root
+        let uid = self.uid.filter(|uid| *uid != current.uid());
+        let gid = self.gid.filter(|gid| *gid != current.gid());
+        if uid.is_some() || gid.is_some() {
+            std::os::unix::fs::chown(path, uid, gid)?;
+        }
+        if let Some(mode) = self.mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply(&self, _path: &Path) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn mtime(secs: f64) -> Option<SystemTime> {
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs_f64(secs))
+    }
+
+    #[test]
+    fn separate_keys() {
+        let attrs = PosixAttributes::from_metadata(&metadata(&[
+            ("mode", "33188"),
+            ("uid", "1000"),
+            ("gid", "100"),
+            ("mtime", "1700000000"),
+        ]));
+        assert_eq!(attrs.mode, Some(0o100644));
+        assert_eq!(attrs.uid, Some(1000));
+        assert_eq!(attrs.gid, Some(100));
+        assert_eq!(attrs.mtime, mtime(1700000000.0));
+    }
+
+    #[test]
+    fn s3cmd_attrs() {
+        let attrs = PosixAttributes::from_metadata(&metadata(&[(
+            "s3cmd-attrs",
+            "atime:1600000000/ctime:1600000000/gid:0/gname:root/mode:33188/mtime:1700000000/uid:0/uname:root",
+        )]));
+        assert_eq!(attrs.mode, Some(33188));
+        assert_eq!(attrs.uid, Some(0));
+        assert_eq!(attrs.gid, Some(0));
+        assert_eq!(attrs.mtime, mtime(1700000000.0));
+    }
+
+    #[test]
+    fn separate_keys_win_over_s3cmd_attrs() {
+        let attrs = PosixAttributes::from_metadata(&metadata(&[
+            ("s3cmd-attrs", "gid:0/mode:33188/mtime:1600000000/uid:0"),
+            ("mode", "33261"),
+            ("mtime", "1700000000"),
+        ]));
+        assert_eq!(attrs.mode, Some(33261));
+        assert_eq!(attrs.mtime, mtime(1700000000.0));
+        assert_eq!(attrs.uid, Some(0));
+        assert_eq!(attrs.gid, Some(0));
+    }
+
+    #[test]
+    fn mtimes() {
+        let parse = |m| PosixAttributes::from_metadata(&metadata(&[("mtime", m)])).mtime;
+        assert_eq!(parse("1700000000.5"), mtime(1700000000.5));
+        assert_eq!(parse("0"), Some(SystemTime::UNIX_EPOCH));
+        assert_eq!(parse("-1"), None);
+        assert_eq!(parse("NaN"), None);
+        assert_eq!(parse("inf"), None);
+        assert_eq!(parse("yesterday"), None);
+    }
+
+    #[test]
+    fn missing_and_bad_values() {
+        let attrs = PosixAttributes::from_metadata(&metadata(&[
+            ("mode", "rw-r--r--"),
+            ("uid", "-1"),
+            ("s3cmd-attrs", "nonsense"),
+        ]));
+        assert_eq!(attrs.mode, None);
+        assert_eq!(attrs.uid, None);
+        assert_eq!(attrs.gid, None);
+        assert_eq!(attrs.mtime, None);
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let attrs = PosixAttributes {
+            mode: Some(0o100600),
+            uid: Some(1000),
+            gid: Some(1000),
+            mtime: mtime(1700000000.0),
+        };
+        let metadata = attrs
+            .to_metadata()
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        let parsed = PosixAttributes::from_metadata(&metadata);
+        assert_eq!(parsed.mode, attrs.mode);
+        assert_eq!(parsed.uid, attrs.uid);
+        assert_eq!(parsed.gid, attrs.gid);
+        assert_eq!(parsed.mtime, attrs.mtime);
+    }
+}
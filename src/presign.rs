@@ -0,0 +1,55 @@
+use crate::bucket::{arn_region, S3Url};
+use crate::client::{create_client, ConnectionArgs};
+use crate::concurrency::{Concurrency, ConcurrencyArgs};
+use crate::Error;
+use aws_sdk_s3::presigning::PresigningConfig;
+use clap::Args;
+use std::time::Duration;
+
+#[derive(Args)]
+pub struct PresignArgs {
+    #[arg(required = true)]
+    urls: Vec<S3Url>, // s3://<bucket>/<key>, as many as needed
+    #[arg(short, long)]
+    profile: Option<String>,
+    #[arg(short, long)]
+    region: Option<String>,
+    #[arg(long, default_value = "1h", value_parser = humantime::parse_duration)]
+    expires_in: Duration, // Up to 7 days, less when the credentials expire before then
+    #[arg(long)]
+    put: bool, // URLs to upload the objects with rather than download them
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+}
+
+/// Prints a URL per key on stdout, anyone with it can get (or put) the object until it expires.
+/// Nothing is sent to S3, the URLs are only signed
+pub async fn presign(args: PresignArgs) -> Result<(), Error> {
+    let concurrency = Concurrency::new(&args.concurrency);
+    let region = args.region.or_else(|| arn_region(&args.urls[0].bucket));
+    let client = create_client(region, args.profile, &args.connection, &concurrency).await;
+    let config = PresigningConfig::expires_in(args.expires_in)
+        .map_err(|e| Error::SigningError(e.to_string()))?;
+
+    for url in &args.urls {
+        let request = if args.put {
+            client
+                .put_object()
+                .bucket(&url.bucket)
+                .key(&url.key)
+                .presigned(config.clone())
+                .await?
+        } else {
+            client
+                .get_object()
+                .bucket(&url.bucket)
+                .key(&url.key)
+                .presigned(config.clone())
+                .await?
+        };
+        println!("{}", request.uri());
+    }
+    Ok(())
+}
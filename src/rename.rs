@@ -0,0 +1,186 @@
+use aws_sdk_s3::types::Object;
+use regex::Regex;
+use std::borrow::Cow;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// A sed style s/<regex>/<replacement>/ rule for --rename, with a g on the end to replace every
+/// match rather than the first. \1 in the replacement is the first group and & the whole match,
+/// anything else (a $ included) is taken as it is. Any character can take the place of the "/"
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pattern: Regex,
+    replacement: String, // In the regex crate's syntax
+    all: bool,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected s/<regex>/<replacement>/, got {rule}");
+        let mut chars = rule.chars();
+        let delimiter = match (chars.next(), chars.next()) {
+            (Some('s'), Some(d)) if !d.is_alphanumeric() && d != '\\' => d,
+            _ => return Err(invalid()),
+        };
+        let parts = split(chars.as_str(), delimiter);
+        let [pattern, replacement, flags] = parts.as_slice() else {
+            return Err(invalid());
+        };
+        let all = match flags.as_str() {
+            "" => false,
+            "g" => true,
+            _ => return Err(format!("only the g flag is supported, got {flags}")),
+        };
+        Ok(Rule {
+            pattern: Regex::new(pattern).map_err(|e| e.to_string())?,
+            replacement: replacement_syntax(replacement),
+            all,
+        })
+    }
+}
+
+// The parts between unescaped delimiters, with "\<delimiter>" turned back into the delimiter
+fn split(rule: &str, delimiter: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = rule.chars();
+    while let Some(c) = chars.next() {
+        let part = parts.last_mut().unwrap();
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == delimiter => part.push(next),
+                Some(next) => {
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => part.push('\\'),
+            },
+            c if c == delimiter => parts.push(String::new()),
+            c => part.push(c),
+        }
+    }
+    parts
+}
+
+// sed's \1 and & to the regex crate's ${1} and ${0}, with a $ as $$ so it isn't taken as a group
+fn replacement_syntax(replacement: &str) -> String {
+    let mut converted = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(d) if d.is_ascii_digit() => converted.push_str(&format!("${{{d}}}")),
+                Some('$') => converted.push_str("$$"),
+                Some(other) => converted.push(other),
+                None => converted.push('\\'),
+            },
+            '&' => converted.push_str("${0}"),
+            '$' => converted.push_str("$$"),
+            c => converted.push(c),
+        }
+    }
+    converted
+}
+
+/// The --rename rules, none by default. Cheap to clone
+#[derive(Clone, Default)]
+pub struct Rules(Arc<[Rule]>);
+
+impl Rules {
+    pub fn new(rules: &[Rule]) -> Self {
+        Rules(rules.into())
+    }
+
+    /// What `key` is uploaded as, every rule applied to it in turn
+    pub fn apply<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        let mut key = Cow::Borrowed(key);
+        for rule in self.0.iter() {
+            let renamed = match rule.all {
+                true => rule.pattern.replace_all(&key, rule.replacement.as_str()),
+                false => rule.pattern.replace(&key, rule.replacement.as_str()),
+            };
+            if let Cow::Owned(renamed) = renamed {
+                key = Cow::Owned(renamed);
+            }
+        }
+        key
+    }
+
+    /// The object as it would be in the upload bucket, to diff against what's there
+    pub fn object(&self, object: &Object) -> Object {
+        let mut renamed = object.clone();
+        if let Some(key) = object.key() {
+            renamed.key = Some(self.apply(key).into_owned());
+        }
+        renamed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rename(rules: &[&str], key: &str) -> String {
+        let rules: Vec<Rule> = rules.iter().map(|r| r.parse().unwrap()).collect();
+        Rules::new(&rules).apply(key).into_owned()
+    }
+
+    #[test]
+    fn rules() {
+        assert_eq!(
+            rename(&["s/^raw\\//processed\\//"], "raw/a.txt"),
+            "processed/a.txt"
+        );
+        assert_eq!(rename(&["s/a/b/"], "a/a.txt"), "b/a.txt");
+        assert_eq!(rename(&["s/a/b/g"], "a/a.txt"), "b/b.txt");
+        // In turn
+        assert_eq!(rename(&["s/a/b/", "s/b/c/"], "a.txt"), "c.txt");
+        assert_eq!(rename(&["s/z/y/"], "a.txt"), "a.txt");
+    }
+
+    #[test]
+    fn other_delimiters() {
+        assert_eq!(
+            rename(&["s|^raw/|processed/|"], "raw/a.txt"),
+            "processed/a.txt"
+        );
+        assert_eq!(rename(&["s#a\\#b#c#"], "a#b.txt"), "c.txt");
+        // Only the delimiter is unescaped, the regex still gets the rest
+        assert_eq!(rename(&["s/\\.JPG$/.jpg/"], "a.JPG"), "a.jpg");
+    }
+
+    #[test]
+    fn bad_rules() {
+        for rule in [
+            "",
+            "s",
+            "s/a/b",
+            "s/a/b/c/",
+            "y/a/b/",
+            "sa/b/c/",
+            "s\\a\\b\\",
+            "s/a/b/i",
+            "s/(/b/",
+        ] {
+            assert!(rule.parse::<Rule>().is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn replacements() {
+        assert_eq!(replacement_syntax("\\2-\\1"), "${2}-${1}");
+        assert_eq!(replacement_syntax("[&]"), "[${0}]");
+        assert_eq!(replacement_syntax("\\&"), "&");
+        assert_eq!(replacement_syntax("$1$"), "$$1$$");
+        assert_eq!(replacement_syntax("\\$"), "$$");
+        assert_eq!(replacement_syntax("a\\"), "a\\");
+
+        let swap = "s/(\\w+)-(\\w+)/\\2-\\1/";
+        assert_eq!(rename(&[swap], "dir/a-b.txt"), "dir/b-a.txt");
+        assert_eq!(rename(&["s/[0-9]+/<&>/g"], "a1b22"), "a<1>b<22>");
+        // A $ is only ever itself
+        assert_eq!(rename(&["s/(a)/$1$$/"], "a.txt"), "$1$$.txt");
+        assert_eq!(rename(&["s/a/${1}x/"], "a.txt"), "${1}x.txt");
+    }
+}
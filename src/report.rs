@@ -0,0 +1,151 @@
+use crate::events::Direction;
+use crate::Error;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::error;
+
+/// The --report-csv file, does nothing without it. Cheap to clone
+#[derive(Clone, Default)]
+pub struct Report(Option<Arc<Mutex<Rows>>>);
+
+struct Rows {
+    writer: csv::Writer<File>,
+    started: HashMap<(Direction, String, String), Instant>, // Transfers without a row yet
+}
+
+/// How a transfer ended, with what goes in the row's size and ETag or error columns
+pub enum Status<'a> {
+    Completed(u64, Option<&'a str>), // Bytes and ETag
+    Skipped(&'a str),                // Why
+    Failed(&'a str),
+}
+
+impl Report {
+    /// Every object transferred from here on gets a row, the file is replaced if it exists
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record([
+            "direction",
+            "bucket",
+            "key",
+            "size",
+            "etag",
+            "status",
+            "duration_seconds",
+            "error",
+        ])?;
+        writer.flush()?;
+        Ok(Report(Some(Arc::new(Mutex::new(Rows {
+            writer,
+            started: HashMap::new(),
+        })))))
+    }
+
+    pub fn started(&self, direction: Direction, bucket: &str, key: &str) {
+        if let Some(report) = &self.0 {
+            let mut report = report.lock().unwrap_or_else(|e| e.into_inner());
+            report.started.insert(
+                (direction, bucket.to_string(), key.to_string()),
+                Instant::now(),
+            );
+        }
+    }
+
+    /// Writes the object's row straight away so the report is complete up to the point a run is
+    /// killed
+    pub fn finished(&self, direction: Direction, bucket: &str, key: &str, status: Status) {
+        let Some(report) = &self.0 else {
+            return;
+        };
+        let mut report = report.lock().unwrap_or_else(|e| e.into_inner());
+        let duration = report
+            .started
+            .remove(&(direction, bucket.to_string(), key.to_string()))
+            .map(|started| format!("{:.3}", started.elapsed().as_secs_f64()))
+            .unwrap_or_default();
+        let (status, size, etag, error) = match status {
+            Status::Completed(size, etag) => ("completed", Some(size), etag, None),
+            Status::Skipped(reason) => ("skipped", None, None, Some(reason)),
+            Status::Failed(error) => ("failed", None, None, Some(error)),
+        };
+        let size = size.map(|s| s.to_string()).unwrap_or_default();
+        // S3 quotes them, which is just noise in a spreadsheet
+        let etag = etag.map(|e| e.trim_matches('"')).unwrap_or_default();
+        let result = report
+            .writer
+            .write_record([
+                direction.as_str(),
+                bucket,
+                key,
+                &size,
+                etag,
+                status,
+                &duration,
+                error.unwrap_or_default(),
+            ])
+            .and_then(|_| report.writer.flush().map_err(csv::Error::from));
+        if let Err(e) = result {
+            error!("Got an error writing to the report: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows() {
+        let path = std::env::temp_dir().join(format!("s3dl-report-{}.csv", std::process::id()));
+        let report = Report::create(&path).unwrap();
+        report.started(Direction::Download, "bucket", "a, \"quoted\".txt");
+        report.finished(
+            Direction::Download,
+            "bucket",
+            "a, \"quoted\".txt",
+            Status::Completed(12, Some("\"abc\"")),
+        );
+        report.finished(
+            Direction::Upload,
+            "bucket",
+            "b.txt",
+            Status::Skipped("already there"),
+        );
+        report.clone().finished(
+            Direction::Download,
+            "bucket",
+            "c.txt",
+            Status::Failed("AccessDenied"),
+        );
+        // Without --report-csv
+        Report::default().finished(Direction::Download, "bucket", "d.txt", Status::Failed("x"));
+
+        let rows: Vec<Vec<String>> = csv::Reader::from_path(&path)
+            .unwrap()
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0][..3], ["download", "bucket", "a, \"quoted\".txt"]);
+        assert_eq!(rows[0][3..6], ["12", "abc", "completed"]);
+        assert!(rows[0][6].parse::<f64>().is_ok());
+        assert_eq!(
+            rows[1],
+            [
+                "upload",
+                "bucket",
+                "b.txt",
+                "",
+                "",
+                "skipped",
+                "",
+                "already there"
+            ]
+        );
+        assert_eq!(rows[2][5..], ["failed", "", "AccessDenied"]);
+    }
+}
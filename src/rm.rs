@@ -0,0 +1,96 @@
+use crate::bucket::{arn_region, S3Url};
+use crate::client::{create_client, ConnectionArgs};
+use crate::concurrency::{Concurrency, ConcurrencyArgs};
+use crate::{delete_source, list_prefixes, Error, ListOptions};
+use clap::Args;
+use std::io::IsTerminal;
+use tracing::{info, warn};
+
+#[derive(Args)]
+pub struct RmArgs {
+    url: S3Url, // s3://<bucket>/<key>, or a prefix with --recursive
+    #[arg(short, long)]
+    profile: Option<String>,
+    #[arg(short, long)]
+    region: Option<String>,
+    #[arg(long)]
+    recursive: bool, // Delete every object whose key starts with it
+    #[arg(long)]
+    dry_run: bool, // Only list what would be deleted
+    #[arg(short, long)]
+    yes: bool, // Don't ask first, needed when there's nobody to ask
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+}
+
+/// Deletes with DeleteObjects, up to 1000 keys a request. In a versioned bucket that adds a
+/// delete marker, the versions stay
+pub async fn rm(args: RmArgs) -> Result<(), Error> {
+    let concurrency = Concurrency::new(&args.concurrency);
+    let region = args.region.or_else(|| arn_region(&args.url.bucket));
+    let client = create_client(region, args.profile, &args.connection, &concurrency).await;
+    let bucket = &args.url.bucket;
+
+    let keys: Vec<String> = if args.recursive {
+        let prefixes: Vec<String> = [args.url.key.clone()]
+            .into_iter()
+            .filter(|k| !k.is_empty())
+            .collect();
+        info!("Obtaining list of {} objects...", bucket);
+        list_prefixes(&client, bucket, &prefixes, ListOptions::default())
+            .await?
+            .into_iter()
+            .filter_map(|o| o.key)
+            .collect()
+    } else if args.url.key.is_empty() {
+        warn!(
+            "No key given, use --recursive to delete everything in {}",
+            bucket
+        );
+        return Ok(());
+    } else {
+        vec![args.url.key.clone()]
+    };
+    if keys.is_empty() {
+        info!("Nothing to delete under s3://{}/{}", bucket, args.url.key);
+        return Ok(());
+    }
+
+    if args.dry_run {
+        for key in &keys {
+            info!("Would delete s3://{}/{}", bucket, key);
+        }
+        info!("Would delete {} objects", keys.len());
+        return Ok(());
+    }
+    if !args.yes && !confirm(keys.len(), bucket, &args.url.key) {
+        return Err(Error::NotConfirmed);
+    }
+
+    let latest: Vec<(String, Option<String>)> = keys.iter().map(|k| (k.clone(), None)).collect();
+    let deleted = delete_source::delete_keys(&client, bucket, &latest).await?;
+    info!("Deleted {} objects from {}", deleted, bucket);
+    match keys.len() - deleted {
+        0 => Ok(()),
+        failed => Err(Error::DeleteFailed(failed)),
+    }
+}
+
+// Only asks when someone is there to answer, otherwise nothing gets deleted without --yes
+fn confirm(count: usize, bucket: &str, key: &str) -> bool {
+    if !std::io::stdin().is_terminal() {
+        warn!("Not deleting anything without --yes when there's nobody to ask");
+        return false;
+    }
+    eprint!(
+        "Delete {} objects from s3://{}/{}? [y/N] ",
+        count, bucket, key
+    );
+    let mut answer = String::new();
+    match std::io::stdin().read_line(&mut answer) {
+        Ok(_) => answer.trim().eq_ignore_ascii_case("y"),
+        Err(_) => false,
+    }
+}
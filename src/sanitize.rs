@@ -0,0 +1,302 @@
+use crate::bucket::local_bucket_dir;
+use crate::local_names::disambiguate;
+use crate::Error;
+use clap::ValueEnum;
+use percent_encoding::percent_decode_str;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::{error, info};
+
+/// What's done with characters in keys that a filesystem won't take (control characters, and
+/// <>:"\|?* which Windows won't), "/" is left to separate directories. Names Windows keeps for
+/// devices (CON, aux.txt, COM1.log and so on) are escaped too
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Sanitize {
+    Percent, // %0A, %3A and so on, with a % itself as %25 so the key can be worked out again, and %43ON for CON
+    Replace, // "_", and CON_ or aux_.txt, with the keys that changed kept in .key-map-<bucket>.json in the download path. Keys that come out the same have part of the hash of the key added
+}
+
+// Besides control characters
+const UNSAFE: &str = "<>:\"\\|?*";
+
+// Whatever the extension or case, along with COM1-9 and LPT1-9
+const RESERVED: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+
+/// What keys are called on disk with --sanitize, does nothing without it. Cheap to clone
+#[derive(Clone, Default)]
+pub struct Sanitizer(Option<Arc<Renames>>);
+
+struct Renames {
+    mode: Sanitize,
+    map_path: PathBuf,
+    keys: Mutex<BTreeMap<String, String>>, // Local name -> key, with --sanitize replace
+    kept: Mutex<HashSet<String>>,          // Keys in the listing that are safe as they are
+}
+
+impl Sanitizer {
+    /// Keys renamed by earlier runs are kept from the existing map, if there is one
+    pub fn new(mode: Option<Sanitize>, download_path: &str, bucket: &str) -> Result<Self, Error> {
+        let Some(mode) = mode else {
+            return Ok(Sanitizer::default());
+        };
+        let map_path = PathBuf::from(download_path)
+            .join(format!(".key-map-{}.json", local_bucket_dir(bucket)));
+        let keys = match std::fs::read(&map_path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Sanitizer(Some(Arc::new(Renames {
+            mode,
+            map_path,
+            keys: Mutex::new(keys),
+            kept: Mutex::new(HashSet::new()),
+        }))))
+    }
+
+    /// Settles what each of `local_keys` is called before any are downloaded, so which of the
+    /// keys that come out the same keeps the name doesn't depend on which is downloaded first.
+    /// A key that's safe as it is always keeps it, even from one renamed to it by an earlier run
+    pub fn reserve(&self, local_keys: &[&str]) {
+        let Some(sanitizer) = self.0.as_ref().filter(|s| s.mode == Sanitize::Replace) else {
+            return;
+        };
+        let (kept, renamed): (Vec<&str>, Vec<&str>) =
+            local_keys.iter().partition(|key| replaced(key) == **key);
+        {
+            let mut keys = sanitizer.keys.lock().unwrap_or_else(|e| e.into_inner());
+            let mut reserved = sanitizer.kept.lock().unwrap_or_else(|e| e.into_inner());
+            for key in kept {
+                keys.remove(key);
+                reserved.insert(key.to_string());
+            }
+        }
+        for key in renamed {
+            self.local_key(key);
+        }
+    }
+
+    /// What `key` is called on disk
+    pub fn local_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        let Some(sanitizer) = &self.0 else {
+            return Cow::Borrowed(key);
+        };
+        match sanitizer.mode {
+            Sanitize::Percent => escape_reserved(percent_encoded(key), sanitizer.mode),
+            Sanitize::Replace => {
+                let local = replaced(key);
+                if local == key {
+                    return Cow::Borrowed(key);
+                }
+                let mut keys = sanitizer.keys.lock().unwrap_or_else(|e| e.into_inner());
+                let kept = sanitizer.kept.lock().unwrap_or_else(|e| e.into_inner());
+                // a:b and a?b are both a_b, whichever had it first (in this run or an earlier one)
+                // keeps it, unless there's a key that's a_b already
+                let other = match keys.get(&local) {
+                    Some(other) if other != key => Some(other.as_str()),
+                    Some(_) => None,
+                    None => kept.contains(&local).then_some(local.as_str()),
+                };
+                let local = match other {
+                    Some(other) => {
+                        let renamed = disambiguate(&local, key);
+                        if !keys.contains_key(&renamed) {
+                            info!(
+                                "{} and {} are both {} once sanitized, saving {} as {}",
+                                other, key, local, key, renamed
+                            );
+                        }
+                        renamed
+                    }
+                    _ => local,
+                };
+                keys.insert(local.clone(), key.to_string());
+                Cow::Owned(local)
+            }
+        }
+    }
+
+    /// The key a file saved as `local_key` came from, to upload it under
+    pub fn original<'a>(&self, local_key: &'a str) -> Cow<'a, str> {
+        let Some(sanitizer) = &self.0 else {
+            return Cow::Borrowed(local_key);
+        };
+        match sanitizer.mode {
+            Sanitize::Percent => percent_decode_str(local_key).decode_utf8_lossy(),
+            Sanitize::Replace => {
+                let keys = sanitizer.keys.lock().unwrap_or_else(|e| e.into_inner());
+                match keys.get(local_key) {
+                    Some(key) => Cow::Owned(key.clone()),
+                    None => Cow::Borrowed(local_key),
+                }
+            }
+        }
+    }
+
+    /// Saves which keys were renamed with --sanitize replace
+    pub fn write(&self) {
+        let Some(sanitizer) = self.0.as_ref().filter(|s| s.mode == Sanitize::Replace) else {
+            return;
+        };
+        let keys = sanitizer.keys.lock().unwrap_or_else(|e| e.into_inner());
+        if keys.is_empty() {
+            return;
+        }
+        let path = &sanitizer.map_path;
+        // Replaced in one go so a run killed part way through doesn't leave half of it
+        let temp = path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(&*keys)
+            .map_err(std::io::Error::other)
+            .and_then(|json| std::fs::write(&temp, json))
+            .and_then(|()| std::fs::rename(&temp, path));
+        match result {
+            Ok(()) => info!("Wrote {} renamed keys to {}", keys.len(), path.display()),
+            Err(e) => error!("Got an error writing {}: {}", path.display(), e),
+        }
+    }
+}
+
+// The unsafe characters, and "%" so a "%3A" already in a key isn't mistaken for an encoded ":".
+// Anything else is left as it is, non-ASCII characters included
+fn percent_encoded(key: &str) -> Cow<'_, str> {
+    let encoded = |c: char| c.is_ascii_control() || c == '%' || UNSAFE.contains(c);
+    if !key.contains(encoded) {
+        return Cow::Borrowed(key);
+    }
+    let mut local = String::with_capacity(key.len());
+    for c in key.chars() {
+        match encoded(c) {
+            true => write!(local, "%{:02X}", c as u8).unwrap(),
+            false => local.push(c),
+        }
+    }
+    Cow::Owned(local)
+}
+
+// Each unsafe character as "_", and device names escaped
+fn replaced(key: &str) -> String {
+    let local: String = key
+        .chars()
+        .map(|c| match c.is_ascii_control() || UNSAFE.contains(c) {
+            true => '_',
+            false => c,
+        })
+        .collect();
+    escape_reserved(Cow::Owned(local), Sanitize::Replace).into_owned()
+}
+
+fn reserved(name: &str) -> bool {
+    // Windows ignores spaces at the end of the name as well
+    let stem = name
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(' ');
+    let stem = stem.to_ascii_uppercase();
+    let numbered = stem
+        .strip_prefix("COM")
+        .or_else(|| stem.strip_prefix("LPT"));
+    RESERVED.contains(&stem.as_str())
+        || numbered.is_some_and(|n| {
+            matches!(
+                n,
+                "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9" | "¹" | "²" | "³"
+            )
+        })
+}
+
+// Each part of the path that's a device name to Windows, CON as %43ON (which decodes back to it)
+// or CON_
+fn escape_reserved(path: Cow<'_, str>, mode: Sanitize) -> Cow<'_, str> {
+    if !path.split('/').any(reserved) {
+        return path;
+    }
+    let escaped: Vec<String> = path
+        .split('/')
+        .map(|name| match (reserved(name), mode) {
+            (false, _) => name.to_string(),
+            // They all start with an ASCII letter
+            (true, Sanitize::Percent) => format!("%{:02X}{}", name.as_bytes()[0], &name[1..]),
+            (true, Sanitize::Replace) => {
+                let stem = name.find('.').unwrap_or(name.len());
+                format!("{}_{}", &name[..stem], &name[stem..])
+            }
+        })
+        .collect();
+    Cow::Owned(escaped.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_keys_keep_their_names() {
+        for order in [["a:b", "a_b"], ["a_b", "a:b"]] {
+            let sanitizer =
+                Sanitizer::new(Some(Sanitize::Replace), "/nonexistent", "bucket").unwrap();
+            sanitizer.reserve(&["a:b", "a_b"]);
+            let renamed = disambiguate("a_b", "a:b");
+            for key in order {
+                let expected = if key == "a_b" { "a_b" } else { &renamed };
+                assert_eq!(sanitizer.local_key(key), expected);
+            }
+            assert_eq!(sanitizer.original("a_b"), "a_b");
+            assert_eq!(sanitizer.original(&renamed), "a:b");
+        }
+    }
+
+    #[test]
+    fn percent_encoding_round_trips() {
+        let key = "a:b/c?d%3A\n";
+        let local = percent_encoded(key);
+        assert_eq!(local, "a%3Ab/c%3Fd%253A%0A");
+        assert_eq!(percent_decode_str(&local).decode_utf8_lossy(), key);
+
+        // Filesystems take these as they are
+        let key = "caf\u{e9}/\u{65e5}\u{672c}:\u{1f600}.txt";
+        let local = percent_encoded(key);
+        assert_eq!(local, "caf\u{e9}/\u{65e5}\u{672c}%3A\u{1f600}.txt");
+        assert_eq!(percent_decode_str(&local).decode_utf8_lossy(), key);
+        assert!(matches!(percent_encoded("caf\u{e9}.txt"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn reserved_names() {
+        for name in [
+            "CON",
+            "con",
+            "aux.txt",
+            "COM1.log",
+            "lpt9",
+            "NUL .tar.gz",
+            "com¹",
+        ] {
+            assert!(reserved(name), "{name}");
+        }
+        for name in ["CONSOLE", "COM0", "COM10", "xcon", "auxiliary.txt", ""] {
+            assert!(!reserved(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn reserved_names_are_escaped() {
+        let path = || Cow::Borrowed("CON/a/aux.txt");
+        assert_eq!(
+            escape_reserved(path(), Sanitize::Percent),
+            "%43ON/a/%61ux.txt"
+        );
+        assert_eq!(
+            escape_reserved(path(), Sanitize::Replace),
+            "CON_/a/aux_.txt"
+        );
+        assert!(matches!(
+            escape_reserved(Cow::Borrowed("a/b.txt"), Sanitize::Percent),
+            Cow::Borrowed(_)
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+use crate::concurrency::Concurrency;
+use crate::{stats, ObjectKey};
+use aws_sdk_s3::types::Object;
+use chrono::{DateTime, Local};
+use clap::Args;
+use croner::Cron;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+#[derive(Args)]
+pub struct ScheduleArgs {
+    // An archive is finished once, at the end of the run
+    #[arg(long, conflicts_with = "archive")]
+    pub watch: bool, // Keep running, syncing again every --interval
+    #[arg(long, default_value = "15m", value_parser = humantime::parse_duration, requires = "watch")]
+    interval: Duration, // e.g. 30s, 15m, 1h 30m
+    #[arg(long, value_parser = parse_schedule, conflicts_with_all = ["watch", "interval", "archive"])]
+    schedule: Option<Cron>, // Keep running, syncing at these times in local time, e.g. "0 2 * * *" for 2am every day
+}
+
+fn parse_schedule(s: &str) -> Result<Cron, String> {
+    Cron::new(s)
+        .with_seconds_optional()
+        .parse()
+        .map_err(|e| e.to_string())
+}
+
+impl ScheduleArgs {
+    /// Whether to keep running after the first sync
+    pub fn daemon(&self) -> bool {
+        self.watch || self.schedule.is_some()
+    }
+
+    /// With --schedule the first sync waits for its time to come round too, false if we were
+    /// stopped in the meantime
+    pub async fn wait_for_first_run(&self, concurrency: &Concurrency) -> bool {
+        match &self.schedule {
+            Some(schedule) => {
+                wait_until(next_occurrence(schedule, Local::now()), concurrency).await
+            }
+            None => true,
+        }
+    }
+
+    /// Sleeps until it's time for the next sync, false if we were stopped in the meantime. A
+    /// scheduled time that went by while the sync `started` then was still running is caught up
+    /// on straight away, however many of them were missed
+    pub async fn wait_for_next_run(&self, concurrency: &Concurrency, started: SystemTime) -> bool {
+        let Some(schedule) = &self.schedule else {
+            info!("Next sync in {}", humantime::format_duration(self.interval));
+            return tokio::select! {
+                _ = tokio::time::sleep(self.interval) => true,
+                _ = concurrency.wait_until_stopped() => false,
+            };
+        };
+        let missed = next_occurrence(schedule, started.into()).filter(|m| *m <= Local::now());
+        if let Some(missed) = missed {
+            warn!(
+                "Missed the sync due at {} while the last one was running, starting it now",
+                missed.format("%Y-%m-%d %H:%M:%S")
+            );
+            return !concurrency.stopped();
+        }
+        wait_until(next_occurrence(schedule, Local::now()), concurrency).await
+    }
+}
+
+fn next_occurrence(schedule: &Cron, after: DateTime<Local>) -> Option<DateTime<Local>> {
+    schedule.find_next_occurrence(&after, false).ok()
+}
+
+async fn wait_until(time: Option<DateTime<Local>>, concurrency: &Concurrency) -> bool {
+    let Some(time) = time else {
+        warn!("The schedule never comes round again, stopping");
+        return false;
+    };
+    info!("Next sync at {}", time.format("%Y-%m-%d %H:%M:%S"));
+    let wait = (time - Local::now()).to_std().unwrap_or_default();
+    tokio::select! {
+        _ = tokio::time::sleep(wait) => true,
+        _ = concurrency.wait_until_stopped() => false,
+    }
+}
+
+/// The ETag of every object the previous sync saw and got onto disk, so a --watch run without an
+/// upload bucket to diff against doesn't download everything again each time
+#[derive(Default)]
+pub struct LastListing {
+    etags: Option<HashMap<ObjectKey, Option<String>>>,
+    pending: HashMap<ObjectKey, Option<String>>, // Changed, only counted as seen once downloaded
+}
+
+impl LastListing {
+    /// Everything that's new or changed since last time, which is everything the first time, or
+    /// whose file isn't `on_disk` any more. Until `downloaded` is called for them they count as
+    /// changed next time too, so whatever fails or isn't got to is tried again
+    pub fn changed(
+        &mut self,
+        objects: Vec<Object>,
+        on_disk: impl Fn(&Object) -> bool,
+    ) -> Vec<Object> {
+        let listed = objects.len();
+        let first = self.etags.is_none();
+        let previous = self.etags.take().unwrap_or_default();
+        let mut etags = HashMap::new();
+        self.pending.clear();
+        let mut changed = Vec::new();
+        for object in objects {
+            let key = ObjectKey::from(&object);
+            // Without an ETag (e.g. from --keys-from-file) there's no telling, so download it
+            let unchanged = object.e_tag.is_some()
+                && previous.get(&key) == Some(&object.e_tag)
+                && on_disk(&object);
+            if unchanged {
+                etags.insert(key, object.e_tag.clone());
+            } else {
+                self.pending.insert(key, object.e_tag.clone());
+                changed.push(object);
+            }
+        }
+        if !first {
+            info!(
+                "{} objects are new or changed since the last sync",
+                changed.len()
+            );
+            stats::skipped((listed - changed.len()) as u64);
+        }
+        self.etags = Some(etags);
+        changed
+    }
+
+    /// `key`'s file is there now, it's only downloaded again if the object changes
+    pub fn downloaded(&mut self, key: &str) {
+        let key = ObjectKey {
+            key: key.to_string(),
+        };
+        if let (Some(etags), Some(etag)) = (&mut self.etags, self.pending.remove(&key)) {
+            etags.insert(key, etag);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn objects() -> Vec<Object> {
+        ["a", "b"]
+            .map(|key| Object::builder().key(key).e_tag("\"1\"").build())
+            .to_vec()
+    }
+
+    fn keys(objects: &[Object]) -> Vec<&str> {
+        objects.iter().filter_map(|o| o.key()).collect()
+    }
+
+    #[test]
+    fn only_downloaded_objects_are_left_out() {
+        let mut last = LastListing::default();
+        assert_eq!(keys(&last.changed(objects(), |_| true)), ["a", "b"]);
+        // b failed
+        last.downloaded("a");
+        assert_eq!(keys(&last.changed(objects(), |_| true)), ["b"]);
+        last.downloaded("b");
+        assert!(last.changed(objects(), |_| true).is_empty());
+        // a's file was deleted
+        assert_eq!(
+            keys(&last.changed(objects(), |o| o.key() != Some("a"))),
+            ["a"]
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use crate::bucket::{arn_region, S3Url};
+use crate::client::{create_client, ConnectionArgs};
+use crate::concurrency::{Concurrency, ConcurrencyArgs};
+use crate::{list_prefixes, Error, ListOptions};
+use aws_sdk_s3::types::{
+    CompressionType, CsvInput, CsvOutput, ExpressionType, FileHeaderInfo, InputSerialization,
+    JsonInput, JsonOutput, JsonType, OutputSerialization, ParquetInput,
+    SelectObjectContentEventStream,
+};
+use clap::{Args, ValueEnum};
+use std::path::PathBuf;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SelectInput {
+    Csv,
+    Json, // One JSON object per line, or with --json-document one document
+    Parquet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SelectOutput {
+    Csv,
+    Json, // One JSON object per line
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SelectCompression {
+    Gzip,
+    Bzip2,
+}
+
+#[derive(Args)]
+pub struct SelectArgs {
+    url: S3Url, // s3://<bucket>/<key>, or a prefix ending in "/" to query everything under it
+    #[arg(short, long)]
+    sql: String, // e.g. "SELECT s.id, s.total FROM S3Object s WHERE s.country = 'NZ'"
+    #[arg(long, value_enum, default_value_t = SelectInput::Csv)]
+    input: SelectInput,
+    #[arg(long)]
+    no_header: bool, // The CSV has no header row, its columns are _1, _2 and so on
+    #[arg(long)]
+    json_document: bool, // The JSON is a single document rather than one object per line
+    #[arg(long, value_enum)]
+    compression: Option<SelectCompression>, // How the CSV or JSON objects are compressed
+    #[arg(long, value_enum, default_value_t = SelectOutput::Csv)]
+    output: SelectOutput,
+    #[arg(long, value_name = "FILE")]
+    output_file: Option<PathBuf>, // Rather than stdout
+    #[arg(short, long)]
+    profile: Option<String>,
+    #[arg(short, long)]
+    region: Option<String>,
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+}
+
+impl SelectArgs {
+    pub fn writes_to_stdout(&self) -> bool {
+        self.output_file.is_none()
+    }
+}
+
+/// Has S3 run the query against each object and writes only what it returns, so a few columns or
+/// rows of a big object don't mean downloading all of it
+pub async fn select(args: SelectArgs) -> Result<(), Error> {
+    let concurrency = Concurrency::new(&args.concurrency);
+    let region = args.region.clone().or_else(|| arn_region(&args.url.bucket));
+    let client = create_client(region, args.profile.clone(), &args.connection, &concurrency).await;
+
+    let keys = if args.url.key.is_empty() || args.url.key.ends_with('/') {
+        let prefixes: Vec<String> = [args.url.key.clone()]
+            .into_iter()
+            .filter(|k| !k.is_empty())
+            .collect();
+        list_prefixes(&client, &args.url.bucket, &prefixes, ListOptions::default())
+            .await?
+            .into_iter()
+            .filter_map(|o| o.key)
+            .filter(|k| !k.ends_with('/'))
+            .collect()
+    } else {
+        vec![args.url.key.clone()]
+    };
+
+    let mut writer: Box<dyn AsyncWrite + Unpin + Send> = match &args.output_file {
+        Some(path) => Box::new(tokio::fs::File::create(path).await?),
+        None => Box::new(tokio::io::stdout()),
+    };
+    let (mut scanned, mut returned) = (0, 0);
+    for key in &keys {
+        let mut output = client
+            .select_object_content()
+            .bucket(&args.url.bucket)
+            .key(key)
+            .expression(&args.sql)
+            .expression_type(ExpressionType::Sql)
+            .input_serialization(input_serialization(&args))
+            .output_serialization(output_serialization(args.output))
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+        while let Some(event) = output
+            .payload
+            .recv()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?
+        {
+            match event {
+                SelectObjectContentEventStream::Records(records) => {
+                    let Some(payload) = records.payload else {
+                        continue;
+                    };
+                    match writer.write_all(payload.as_ref()).await {
+                        Ok(()) => {}
+                        // Whatever it's piped into has seen enough, e.g. head
+                        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                SelectObjectContentEventStream::Stats(stats) => {
+                    if let Some(details) = stats.details {
+                        scanned += details.bytes_scanned.unwrap_or_default();
+                        returned += details.bytes_returned.unwrap_or_default();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    writer.flush().await?;
+    info!(
+        "Queried {} objects, {} bytes scanned and {} returned",
+        keys.len(),
+        scanned,
+        returned
+    );
+    Ok(())
+}
+
+fn input_serialization(args: &SelectArgs) -> InputSerialization {
+    let input =
+        InputSerialization::builder().set_compression_type(args.compression.map(|c| match c {
+            SelectCompression::Gzip => CompressionType::Gzip,
+            SelectCompression::Bzip2 => CompressionType::Bzip2,
+        }));
+    match args.input {
+        SelectInput::Csv => input.csv(
+            CsvInput::builder()
+                .file_header_info(match args.no_header {
+                    true => FileHeaderInfo::None,
+                    false => FileHeaderInfo::Use,
+                })
+                .build(),
+        ),
+        SelectInput::Json => input.json(
+            JsonInput::builder()
+                .r#type(match args.json_document {
+                    true => JsonType::Document,
+                    false => JsonType::Lines,
+                })
+                .build(),
+        ),
+        SelectInput::Parquet => input.parquet(ParquetInput::builder().build()),
+    }
+    .build()
+}
+
+fn output_serialization(output: SelectOutput) -> OutputSerialization {
+    let serialization = OutputSerialization::builder();
+    match output {
+        SelectOutput::Csv => serialization.csv(CsvOutput::builder().build()),
+        SelectOutput::Json => serialization.json(JsonOutput::builder().build()),
+    }
+    .build()
+}
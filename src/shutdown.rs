@@ -0,0 +1,200 @@
+use crate::concurrency::Concurrency;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/// Files being written right now, removed if we're made to quit before they're done. Cheap to
+/// clone
+#[derive(Clone, Default)]
+pub struct PartialFiles(Arc<Mutex<Vec<PathBuf>>>);
+
+/// The first Ctrl-C (or SIGTERM) stops new transfers and lets the running ones finish,
+/// a second one quits straight away, removing `partial_files`. SIGUSR1 and SIGUSR2 pause and
+/// resume starting transfers
+pub fn handle_signals(concurrency: Concurrency, partial_files: PartialFiles) {
+    #[cfg(unix)]
+    handle_pause_signals(concurrency.clone());
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        warn!(
+            "Stopping, waiting for running transfers to finish (press Ctrl-C again to quit now)..."
+        );
+        concurrency.stop();
+
+        wait_for_signal().await;
+        warn!("Quitting, removing partly written files...");
+        for path in partial_files.0.lock().unwrap().drain(..) {
+            let _ = std::fs::remove_file(path);
+        }
+        std::process::exit(130);
+    });
+}
+
+#[cfg(unix)]
+fn handle_pause_signals(concurrency: Concurrency) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut pause = signal(SignalKind::user_defined1()).expect("failed to listen for SIGUSR1");
+    let mut resume = signal(SignalKind::user_defined2()).expect("failed to listen for SIGUSR2");
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = pause.recv() => {
+                    info!("Paused, running transfers will finish but no new ones start until SIGUSR2");
+                    concurrency.pause();
+                }
+                _ = resume.recv() => {
+                    info!("Resuming");
+                    concurrency.resume();
+                }
+            }
+        }
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// Downloads are written next to where they belong as a hidden file with this on the end, which
+// unlike ".part" no key is going to have
+const PART_SUFFIX: &str = ".s3dl-part";
+
+/// A download being written to `.<name>.<pid>.s3dl-part`, only renamed to `<name>` by `finish`
+/// once it's complete so nothing reading the directory sees half a file. Unless finished it gets
+/// removed again when dropped, one left behind means a run was killed part way through writing
+/// it. The pid keeps two runs writing the same file from writing over each other's
+pub struct PartialFile {
+    path: PathBuf,
+    part_path: PathBuf,
+    finished: bool,
+    partial_files: PartialFiles,
+}
+
+impl PartialFile {
+    pub fn new(path: &Path, partial_files: &PartialFiles) -> Self {
+        let mut name = OsString::from(".");
+        name.push(path.file_name().unwrap_or_default());
+        name.push(format!(".{}{}", std::process::id(), PART_SUFFIX));
+        let part_path = path.with_file_name(name);
+        partial_files.0.lock().unwrap().push(part_path.clone());
+        PartialFile {
+            path: path.to_path_buf(),
+            part_path,
+            finished: false,
+            partial_files: partial_files.clone(),
+        }
+    }
+
+    /// Where to write the download to
+    pub fn part_path(&self) -> &Path {
+        &self.part_path
+    }
+
+    /// The file has to be closed first. With `sync` the rename is flushed to disk too, which
+    /// means syncing the directory it's in
+    pub async fn finish(mut self, sync: bool) -> std::io::Result<()> {
+        tokio::fs::rename(&self.part_path, &self.path).await?;
+        self.finished = true;
+        if sync {
+            sync_parent(&self.path).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        self.partial_files
+            .0
+            .lock()
+            .unwrap()
+            .retain(|p| *p != self.part_path);
+        if !self.finished {
+            let _ = std::fs::remove_file(&self.part_path);
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn sync_parent(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(parent) => tokio::fs::File::open(parent).await?.sync_all().await,
+        None => Ok(()),
+    }
+}
+
+// Directories can't be opened to sync them, NTFS keeps renames in its journal anyway
+#[cfg(not(unix))]
+async fn sync_parent(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Left over from a download that never finished, not something to upload
+pub fn is_part_file(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| name.starts_with('.') && name.ends_with(PART_SUFFIX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("s3dl-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn finished_files_are_renamed() {
+        let dir = dir("finished");
+        let path = dir.join("a.txt");
+        let partial_files = PartialFiles::default();
+        let partial = PartialFile::new(&path, &partial_files);
+        assert!(is_part_file(partial.part_path()));
+        std::fs::write(partial.part_path(), b"a").unwrap();
+        partial.finish(true).await.unwrap();
+        let contents = std::fs::read(&path);
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(contents.unwrap(), b"a");
+        assert!(partial_files.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unfinished_files_are_removed() {
+        let dir = dir("unfinished");
+        let partial_files = PartialFiles::default();
+        let partial = PartialFile::new(&dir.join("a.txt"), &partial_files);
+        let part_path = partial.part_path().to_path_buf();
+        std::fs::write(&part_path, b"a").unwrap();
+        assert_eq!(
+            partial_files.0.lock().unwrap().as_slice(),
+            [part_path.as_path()]
+        );
+        drop(partial);
+        let left = std::fs::read_dir(&dir).unwrap().count();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(left, 0);
+        assert!(partial_files.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn part_files() {
+        assert!(is_part_file(Path::new("dir/.a.txt.123.s3dl-part")));
+        assert!(!is_part_file(Path::new("dir/a.txt.s3dl-part")));
+        assert!(!is_part_file(Path::new("dir/.a.txt.part")));
+    }
+}
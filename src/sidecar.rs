@@ -0,0 +1,128 @@
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::operation::put_object::builders::PutObjectFluentBuilder;
+use aws_sdk_s3::types::StorageClass;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// Written next to the file it describes
+const SUFFIX: &str = ".s3meta.json";
+
+/// What S3 knows about an object besides its contents, kept with --metadata-sidecars so
+/// uploading the file again recreates the object as it was
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sidecar {
+    etag: Option<String>,
+    version_id: Option<String>,
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+    content_disposition: Option<String>,
+    content_language: Option<String>,
+    cache_control: Option<String>,
+    storage_class: Option<String>,
+    metadata: BTreeMap<String, String>, // x-amz-meta-* without the prefix
+}
+
+impl From<&GetObjectOutput> for Sidecar {
+    fn from(output: &GetObjectOutput) -> Self {
+        Sidecar {
+            etag: output.e_tag.clone(),
+            version_id: output.version_id.clone(),
+            content_type: output.content_type.clone(),
+            content_encoding: output.content_encoding.clone(),
+            content_disposition: output.content_disposition.clone(),
+            content_language: output.content_language.clone(),
+            cache_control: output.cache_control.clone(),
+            storage_class: output
+                .storage_class
+                .as_ref()
+                .map(|s| s.as_str().to_string()),
+            metadata: output
+                .metadata
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+impl Sidecar {
+    pub async fn write(&self, file: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(std::io::Error::other)?;
+        tokio::fs::write(path_for(file), json).await
+    }
+
+    /// None when the file has no sidecar
+    pub async fn read(file: &Path) -> std::io::Result<Option<Sidecar>> {
+        match tokio::fs::read(path_for(file)).await {
+            Ok(json) => Ok(Some(
+                serde_json::from_slice(&json).map_err(std::io::Error::other)?,
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The ETag is left out, S3 works that out again from the contents
+    pub fn apply(&self, request: PutObjectFluentBuilder) -> PutObjectFluentBuilder {
+        let metadata =
+            (!self.metadata.is_empty()).then(|| self.metadata.clone().into_iter().collect());
+        request
+            .set_content_type(self.content_type.clone())
+            .set_content_encoding(self.content_encoding.clone())
+            .set_content_disposition(self.content_disposition.clone())
+            .set_content_language(self.content_language.clone())
+            .set_cache_control(self.cache_control.clone())
+            .set_storage_class(self.storage_class.as_deref().map(StorageClass::from))
+            .set_metadata(metadata)
+    }
+}
+
+impl Sidecar {
+    /// Saves the same things as user.s3.* extended attributes of the file instead, user
+    /// metadata as user.s3.meta.*
+    #[cfg(unix)]
+    pub fn write_xattrs(&self, file: &Path) -> std::io::Result<()> {
+        let fields = [
+            ("etag", &self.etag),
+            ("version_id", &self.version_id),
+            ("content_type", &self.content_type),
+            ("content_encoding", &self.content_encoding),
+            ("content_disposition", &self.content_disposition),
+            ("content_language", &self.content_language),
+            ("cache_control", &self.cache_control),
+            ("storage_class", &self.storage_class),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                xattr::set(file, format!("user.s3.{}", name), value.as_bytes())?;
+            }
+        }
+        for (name, value) in &self.metadata {
+            xattr::set(file, format!("user.s3.meta.{}", name), value.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn write_xattrs(&self, _file: &Path) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "extended attributes are only supported on Unix",
+        ))
+    }
+}
+
+/// Where the sidecar for `file` goes
+pub fn path_for(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(SUFFIX);
+    PathBuf::from(path)
+}
+
+/// A sidecar rather than a downloaded object, not something to upload on its own
+pub fn is_sidecar(path: &Path) -> bool {
+    path.as_os_str().to_string_lossy().ends_with(SUFFIX)
+}
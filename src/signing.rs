@@ -0,0 +1,80 @@
+use crate::client::{create_credentials_provider, reqwest_client, ConnectionArgs};
+use crate::Error;
+use aws_sdk_s3::config::{ProvideCredentials, SharedCredentialsProvider};
+use aws_sigv4::http_request::{sign, SignableBody, SignableRequest, SigningSettings};
+use aws_sigv4::sign::v4;
+use std::time::SystemTime;
+
+/// Sends SigV4 signed requests to the services we don't pull in an SDK crate for
+pub struct SigningClient {
+    http: reqwest::Client,
+    service: &'static str,
+    region: String,
+    credentials: SharedCredentialsProvider,
+}
+
+impl SigningClient {
+    pub async fn new(
+        service: &'static str,
+        region: String,
+        profile: Option<String>,
+        connection: &ConnectionArgs,
+    ) -> Result<Self, Error> {
+        Ok(SigningClient {
+            http: reqwest_client(connection)?,
+            service,
+            region,
+            credentials: SharedCredentialsProvider::new(create_credentials_provider(profile).await),
+        })
+    }
+
+    pub async fn post(
+        &self,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: String,
+    ) -> Result<reqwest::Response, Error> {
+        let credentials = self
+            .credentials
+            .provide_credentials()
+            .await
+            .map_err(|e| Error::SigningError(e.to_string()))?;
+        let identity = credentials.into();
+        let params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&self.region)
+            .name(self.service)
+            .time(SystemTime::now())
+            .settings(SigningSettings::default())
+            .build()
+            .map_err(|e| Error::SigningError(e.to_string()))?
+            .into();
+        let signable = SignableRequest::new(
+            "POST",
+            url,
+            headers.iter().copied(),
+            SignableBody::Bytes(body.as_bytes()),
+        )
+        .map_err(|e| Error::SigningError(e.to_string()))?;
+        let (signature, _) = sign(signable, &params)
+            .map_err(|e| Error::SigningError(e.to_string()))?
+            .into_parts();
+
+        let mut request = self.http.post(url).body(body);
+        for (name, value) in headers.iter().copied().chain(signature.headers()) {
+            request = request.header(name, value);
+        }
+        Ok(request.send().await?)
+    }
+}
+
+/// The region in a service endpoint like https://sqs.eu-west-1.amazonaws.com/...
+pub fn endpoint_region(url: &str, service: &str) -> Option<String> {
+    url.split("://")
+        .nth(1)?
+        .strip_prefix(service)?
+        .strip_prefix('.')?
+        .split('.')
+        .next()
+        .map(|r| r.to_string())
+}
@@ -0,0 +1,106 @@
+use crate::bucket::local_bucket_dir;
+use crate::context::Context;
+use crate::local_names::LocalNames;
+use crate::{dedup, sidecar, stats, DownloadArgs};
+use aws_sdk_s3::types::Object;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+// Sorts in the order they were taken, and has no colons for Windows
+const FORMAT: &str = "%Y-%m-%dT%H%M%SZ";
+
+/// With --snapshots each run downloads into its own directory under the download path, named
+/// after when it started
+pub fn for_run(download: &DownloadArgs, started: SystemTime) -> DownloadArgs {
+    let started: DateTime<Utc> = started.into();
+    let mut snapshot = download.clone();
+    snapshot.download_path = format!("{}/{}", download.download_path, started.format(FORMAT));
+    snapshot
+}
+
+/// Hardlinks the files for objects that haven't changed since the previous snapshot into this
+/// one, returning the objects that still need downloading. Unchanged means the same size and
+/// modified time, which downloads are given from LastModified
+pub async fn link_unchanged(
+    bucket: &str,
+    objects: Vec<Object>,
+    names: &LocalNames,
+    context: &Context,
+) -> Vec<Object> {
+    let download = &context.download;
+    let Some(previous) = previous(download) else {
+        info!("No earlier snapshot, downloading everything");
+        return objects;
+    };
+    info!("Linking unchanged files from {}...", previous.display());
+    let previous = previous.join(local_bucket_dir(bucket));
+    let current = PathBuf::from(&download.download_path).join(local_bucket_dir(bucket));
+    let mut changed = Vec::new();
+    let mut linked = 0;
+    for object in objects {
+        // Under the name it was saved as, which for keys only differing in case is a file on
+        // macOS with the other key's name
+        let Some(name) = names.get(object.key().unwrap_or_default()) else {
+            changed.push(object);
+            continue;
+        };
+        let (old, new) = (previous.join(name), current.join(name));
+        if unchanged(&old, &object).await && link(&old, &new, context).await.is_ok() {
+            context.manifest.add_copy(&new, &old);
+            linked += 1;
+        } else {
+            changed.push(object);
+        }
+    }
+    stats::skipped(linked);
+    info!(
+        "Linked {} unchanged files, {} to download",
+        linked,
+        changed.len()
+    );
+    changed
+}
+
+// The newest snapshot before this run's
+fn previous(download: &DownloadArgs) -> Option<PathBuf> {
+    let current = Path::new(&download.download_path);
+    let name = current.file_name()?.to_str()?;
+    let root = current.parent()?;
+    std::fs::read_dir(root)
+        .ok()?
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|n| n.as_str() < name && NaiveDateTime::parse_from_str(n, FORMAT).is_ok())
+        .max()
+        .map(|n| root.join(n))
+}
+
+async fn unchanged(path: &Path, object: &Object) -> bool {
+    let Ok(metadata) = tokio::fs::symlink_metadata(path).await else {
+        return false;
+    };
+    // Listings have milliseconds, the Last-Modified header the time was set from doesn't
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64);
+    metadata.is_file()
+        && object.size() == Some(metadata.len() as i64)
+        && modified.is_some()
+        && modified == object.last_modified().map(|t| t.secs())
+}
+
+async fn link(old: &Path, new: &Path, context: &Context) -> std::io::Result<()> {
+    let (sync, partial_files) = (context.download.fsync, &context.partial_files);
+    dedup::link(old, new, sync, partial_files).await?;
+    if context.download.metadata_sidecars {
+        let sidecar = sidecar::path_for(old);
+        if tokio::fs::try_exists(&sidecar).await? {
+            dedup::link(&sidecar, &sidecar::path_for(new), sync, partial_files).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,317 @@
+use crate::client::{get_region, ConnectionArgs};
+use crate::context::Context;
+use crate::events::Direction;
+use crate::local_names::LocalNames;
+use crate::signing::{endpoint_region, SigningClient};
+use crate::{
+    overwrite, process_object, stats, upload_object, Error, Outcome, SourceArgs, UploadOptions,
+};
+use aws_sdk_s3::Client;
+use futures::future::join_all;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{debug, error, info, Instrument};
+
+// Long polling, a receive waits this long for a message before coming back empty
+const WAIT_TIME_SECONDS: u32 = 20;
+
+/// Where a downloaded object gets copied to when replicating
+#[derive(Clone)]
+pub struct Upload {
+    pub client: Client,
+    pub bucket: String,
+    pub options: UploadOptions,
+}
+
+/// A queue subscribed to a bucket's event notifications
+pub struct EventSource<'a> {
+    pub queue_url: &'a str,
+    pub bucket: Option<&'a str>, // The bucket events name, when --bucket is an access point to it
+}
+
+/// Just enough of an SQS client to receive and delete messages
+struct Queue {
+    client: SigningClient,
+    url: String,
+}
+
+struct Message {
+    receipt_handle: String,
+    body: String,
+}
+
+impl Queue {
+    // Uses the same profile and region as the download client
+    async fn new(
+        url: &str,
+        region: Option<String>,
+        profile: Option<String>,
+        connection: &ConnectionArgs,
+    ) -> Result<Self, Error> {
+        // The queue could be in a different region to the bucket
+        let region = match endpoint_region(url, "sqs") {
+            Some(region) => region,
+            None => get_region(region).await.to_string(),
+        };
+        Ok(Queue {
+            client: SigningClient::new("sqs", region, profile, connection).await?,
+            url: url.to_string(),
+        })
+    }
+
+    async fn receive(&self) -> Result<Vec<Message>, Error> {
+        let response = self
+            .call(
+                "ReceiveMessage",
+                json!({
+                    "QueueUrl": self.url,
+                    "MaxNumberOfMessages": 10,
+                    "WaitTimeSeconds": WAIT_TIME_SECONDS,
+                }),
+            )
+            .await?;
+        let messages = response["Messages"].as_array().cloned().unwrap_or_default();
+        Ok(messages
+            .into_iter()
+            .filter_map(|m| {
+                Some(Message {
+                    receipt_handle: m["ReceiptHandle"].as_str()?.to_string(),
+                    body: m["Body"].as_str()?.to_string(),
+                })
+            })
+            .collect())
+    }
+
+    async fn delete(&self, message: &Message) -> Result<(), Error> {
+        self.call(
+            "DeleteMessage",
+            json!({
+                "QueueUrl": self.url,
+                "ReceiptHandle": message.receipt_handle,
+            }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    // SQS takes JSON requests
+    async fn call(&self, action: &str, input: Value) -> Result<Value, Error> {
+        let target = format!("AmazonSQS.{}", action);
+        let headers = [
+            ("content-type", "application/x-amz-json-1.0"),
+            ("x-amz-target", target.as_str()),
+        ];
+        let response = self
+            .client
+            .post(&self.url, &headers, input.to_string())
+            .await?;
+        let status = response.status();
+        let output: Value = response.json().await?;
+        if !status.is_success() {
+            return Err(Error::SqsError(format!(
+                "{} {}: {}",
+                status.as_u16(),
+                output["__type"].as_str().unwrap_or_default(),
+                output["message"].as_str().unwrap_or_default()
+            )));
+        }
+        Ok(output)
+    }
+}
+
+/// Downloads (and with `upload`, replicates) each object an S3 `ObjectCreated` event on the
+/// queue refers to, until the run is stopped. Messages are only deleted once everything they
+/// mention has been transferred, anything else becomes visible again to be retried
+pub async fn download_events(
+    events: EventSource<'_>,
+    source: &SourceArgs,
+    client: &Client,
+    names: &Arc<LocalNames>,
+    upload: Option<Upload>,
+    connection: &ConnectionArgs,
+    context: &Context,
+) -> Result<(), Error> {
+    let concurrency = &context.concurrency;
+    let bucket = source.bucket.as_str();
+    let prefixes = &source.prefix;
+    let queue_url = events.queue_url;
+    let queue = Queue::new(
+        queue_url,
+        source.client_region(),
+        source.profile.clone(),
+        connection,
+    )
+    .await?;
+    info!(
+        "Waiting for new objects in {} from {} (Ctrl-C to stop)...",
+        bucket, queue_url
+    );
+
+    loop {
+        let messages = tokio::select! {
+            messages = queue.receive() => messages,
+            _ = concurrency.wait_until_stopped() => return Ok(()),
+        };
+        let messages = match messages {
+            Ok(m) => m,
+            Err(e) => {
+                error!("Got an error receiving from the queue: {}", e);
+                // Don't spin on e.g. missing permissions
+                tokio::time::sleep(std::time::Duration::from_secs(WAIT_TIME_SECONDS.into())).await;
+                continue;
+            }
+        };
+
+        for message in messages {
+            let keys: Vec<String> = created_keys(&message.body, events.bucket.unwrap_or(bucket))
+                .into_iter()
+                .filter(|k| prefixes.is_empty() || prefixes.iter().any(|p| k.starts_with(p)))
+                .collect();
+
+            let mut tasks = Vec::new();
+            for key in keys {
+                let client = client.clone();
+                let bucket = bucket.to_string();
+                let upload = upload.clone();
+                let context = context.clone();
+                let names = names.clone();
+                tasks.push(tokio::spawn(
+                    async move {
+                        let Some(_permit) = context.concurrency.acquire(Direction::Download).await
+                        else {
+                            return Ok(Outcome::NotAttempted);
+                        };
+                        transfer(&client, &bucket, &key, &names, upload, &context).await
+                    }
+                    .in_current_span(),
+                ));
+            }
+
+            let mut done = true;
+            for result in join_all(tasks).await {
+                match result {
+                    Ok(Ok(Outcome::NotAttempted)) => done = false,
+                    Ok(Ok(Outcome::Failed)) | Err(_) => {
+                        stats::failed(1);
+                        done = false;
+                    }
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => {
+                        error!("Got an error transferring an object from the queue: {}", e);
+                        stats::failed(1);
+                        done = false;
+                    }
+                }
+            }
+            if done {
+                if let Err(e) = queue.delete(&message).await {
+                    error!("Got an error deleting a message from the queue: {}", e);
+                }
+            }
+            if let Err(e) = context.delete_source.flush(client, bucket).await {
+                error!("Got an error deleting objects from {}: {}", bucket, e);
+            }
+        }
+    }
+}
+
+async fn transfer(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    names: &LocalNames,
+    upload: Option<Upload>,
+    context: &Context,
+) -> Result<Outcome, Error> {
+    // Named as they were in the listing the run started with, keys created since are saved
+    // under their own
+    let Some(local_key) = names.get(key) else {
+        return Ok(overwrite::skip(&context.events, bucket, key));
+    };
+    let outcome = process_object(client, bucket, key, None, local_key, None, context).await?;
+    if outcome != Outcome::Downloaded {
+        return Ok(outcome);
+    }
+    info!("Downloaded: {}", key);
+    context.delete_source.downloaded(key);
+    let Some(upload) = upload else {
+        return Ok(outcome);
+    };
+    // Wherever process_object saved it
+    let local_path = PathBuf::from(format!("{}/", context.download.download_path))
+        .join(context.paths.path(bucket, local_key));
+    upload_object(
+        &upload.client,
+        &upload.bucket,
+        key,
+        local_path,
+        upload.options,
+        context,
+    )
+    .await?;
+    Ok(Outcome::Uploaded)
+}
+
+/// Keys of the objects created in `bucket` according to an S3 event notification, which may
+/// have come through SNS first. Anything else, e.g. the test event S3 sends when the
+/// notification is set up, has none
+fn created_keys(body: &str, bucket: &str) -> Vec<String> {
+    let Ok(mut event) = serde_json::from_str::<Value>(body) else {
+        return Vec::new();
+    };
+    if event["Type"] == "Notification" {
+        match event["Message"].as_str().map(serde_json::from_str) {
+            Some(Ok(message)) => event = message,
+            _ => return Vec::new(),
+        }
+    }
+    let Some(records) = event["Records"].as_array() else {
+        return Vec::new();
+    };
+    records
+        .iter()
+        .filter(|r| {
+            r["eventName"]
+                .as_str()
+                .is_some_and(|n| n.starts_with("ObjectCreated:"))
+        })
+        .filter(|r| {
+            let name = r["s3"]["bucket"]["name"].as_str().unwrap_or_default();
+            if name != bucket {
+                debug!("Skipping an event for {}, it's not for {}", name, bucket);
+            }
+            name == bucket
+        })
+        .filter_map(|r| r["s3"]["object"]["key"].as_str())
+        .map(decode_key)
+        .collect()
+}
+
+// Keys in event notifications are URL encoded, with spaces as +
+fn decode_key(key: &str) -> String {
+    let key = key.replace('+', " ");
+    percent_encoding::percent_decode_str(&key)
+        .decode_utf8_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(name: &str, key: &str) -> String {
+        json!({"Records": [{
+            "eventName": "ObjectCreated:Put",
+            "s3": {"bucket": {"name": name}, "object": {"key": key}},
+        }]})
+        .to_string()
+    }
+
+    #[test]
+    fn events_for_the_bucket() {
+        let body = event("my-bucket", "logs/a+b%2B.txt");
+        assert_eq!(created_keys(&body, "my-bucket"), ["logs/a b+.txt"]);
+        assert!(created_keys(&body, "other-bucket").is_empty());
+    }
+}
@@ -0,0 +1,109 @@
+use crate::bucket::{arn_region, S3Url};
+use crate::client::{create_client, ConnectionArgs};
+use crate::concurrency::{Concurrency, ConcurrencyArgs};
+use crate::events::OutputFormat;
+use crate::Error;
+use aws_sdk_s3::primitives::DateTimeFormat;
+use aws_sdk_s3::types::ChecksumMode;
+use clap::Args;
+use serde_json::{json, Map, Value};
+
+#[derive(Args)]
+pub struct StatArgs {
+    url: S3Url, // s3://<bucket>/<key>
+    #[arg(short, long)]
+    profile: Option<String>,
+    #[arg(short, long)]
+    region: Option<String>,
+    #[arg(long)]
+    version_id: Option<String>, // Rather than the latest version
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+}
+
+/// Prints everything HeadObject says about the object, its checksums and user metadata included
+pub async fn stat(args: StatArgs) -> Result<(), Error> {
+    let concurrency = Concurrency::new(&args.concurrency);
+    let region = args.region.or_else(|| arn_region(&args.url.bucket));
+    let client = create_client(region, args.profile, &args.connection, &concurrency).await;
+    let head = client
+        .head_object()
+        .bucket(&args.url.bucket)
+        .key(&args.url.key)
+        .set_version_id(args.version_id)
+        .checksum_mode(ChecksumMode::Enabled)
+        .send()
+        .await?;
+
+    // In the order they're printed, leaving out what the object doesn't have
+    let fields = [
+        ("key", Some(json!(args.url.key))),
+        ("size", head.content_length().map(|s| json!(s))),
+        (
+            "last_modified",
+            head.last_modified()
+                .and_then(|t| t.fmt(DateTimeFormat::DateTime).ok())
+                .map(|t| json!(t)),
+        ),
+        ("etag", head.e_tag().map(|e| json!(e.trim_matches('"')))),
+        ("version_id", head.version_id().map(|v| json!(v))),
+        (
+            "storage_class",
+            Some(json!(head
+                .storage_class()
+                .map(|c| c.as_str())
+                .unwrap_or("STANDARD"))),
+        ),
+        ("content_type", head.content_type().map(|c| json!(c))),
+        (
+            "content_encoding",
+            head.content_encoding().map(|c| json!(c)),
+        ),
+        ("cache_control", head.cache_control().map(|c| json!(c))),
+        (
+            "server_side_encryption",
+            head.server_side_encryption().map(|s| json!(s.as_str())),
+        ),
+        ("ssekms_key_id", head.ssekms_key_id().map(|k| json!(k))),
+        ("checksum_crc32", head.checksum_crc32().map(|c| json!(c))),
+        ("checksum_crc32c", head.checksum_crc32_c().map(|c| json!(c))),
+        (
+            "checksum_crc64nvme",
+            head.checksum_crc64_nvme().map(|c| json!(c)),
+        ),
+        ("checksum_sha1", head.checksum_sha1().map(|c| json!(c))),
+        ("checksum_sha256", head.checksum_sha256().map(|c| json!(c))),
+        ("restore", head.restore().map(|r| json!(r))),
+        (
+            "replication_status",
+            head.replication_status().map(|r| json!(r.as_str())),
+        ),
+        ("metadata", head.metadata().map(|m| json!(m))),
+    ];
+    let fields: Map<String, Value> = fields
+        .into_iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value?)))
+        .collect();
+
+    if args.output == OutputFormat::Json {
+        println!("{}", Value::Object(fields));
+        return Ok(());
+    }
+    for (name, value) in &fields {
+        match value {
+            Value::Object(metadata) => {
+                println!("{name}:");
+                for (key, value) in metadata {
+                    println!("  {key}: {}", value.as_str().unwrap_or_default());
+                }
+            }
+            Value::String(value) => println!("{name:<23} {value}"),
+            value => println!("{name:<23} {value}"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use std::ops::Sub;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Running totals since the process started, a single run's share is the difference between
+/// a snapshot taken before and after it. Global rather than in a run's Context, /metrics serves
+/// them as counters that must keep going up from one run to the next
+static TOTALS: Counters = Counters {
+    objects_listed: AtomicU64::new(0),
+    objects_downloaded: AtomicU64::new(0),
+    bytes_downloaded: AtomicU64::new(0),
+    objects_uploaded: AtomicU64::new(0),
+    bytes_uploaded: AtomicU64::new(0),
+    objects_skipped: AtomicU64::new(0),
+    failures: AtomicU64::new(0),
+};
+
+// Gauges rather than totals
+static IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+static LAST_SUCCESS: AtomicU64 = AtomicU64::new(0); // Seconds since the epoch, 0 for never
+
+struct Counters {
+    objects_listed: AtomicU64,
+    objects_downloaded: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    objects_uploaded: AtomicU64,
+    bytes_uploaded: AtomicU64,
+    objects_skipped: AtomicU64,
+    failures: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Snapshot {
+    pub objects_listed: u64,
+    pub objects_downloaded: u64,
+    pub bytes_downloaded: u64,
+    pub objects_uploaded: u64,
+    pub bytes_uploaded: u64,
+    pub objects_skipped: u64, // Already up to date, a delete marker or still in an archive
+    pub failures: u64,        // Objects that couldn't be transferred
+}
+
+pub fn listed(objects: u64) {
+    TOTALS.objects_listed.fetch_add(objects, Ordering::Relaxed);
+}
+
+pub fn downloaded(bytes: u64) {
+    TOTALS.objects_downloaded.fetch_add(1, Ordering::Relaxed);
+    TOTALS.bytes_downloaded.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn uploaded(bytes: u64) {
+    TOTALS.objects_uploaded.fetch_add(1, Ordering::Relaxed);
+    TOTALS.bytes_uploaded.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn skipped(objects: u64) {
+    TOTALS.objects_skipped.fetch_add(objects, Ordering::Relaxed);
+}
+
+pub fn failed(objects: u64) {
+    TOTALS.failures.fetch_add(objects, Ordering::Relaxed);
+}
+
+pub fn transfer_started() {
+    IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn transfer_finished() {
+    IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub fn in_flight() -> u64 {
+    IN_FLIGHT.load(Ordering::Relaxed)
+}
+
+pub fn sync_succeeded() {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    LAST_SUCCESS.store(now.as_secs(), Ordering::Relaxed);
+}
+
+/// When the last sync without errors finished, in seconds since the epoch
+pub fn last_success() -> u64 {
+    LAST_SUCCESS.load(Ordering::Relaxed)
+}
+
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        objects_listed: TOTALS.objects_listed.load(Ordering::Relaxed),
+        objects_downloaded: TOTALS.objects_downloaded.load(Ordering::Relaxed),
+        bytes_downloaded: TOTALS.bytes_downloaded.load(Ordering::Relaxed),
+        objects_uploaded: TOTALS.objects_uploaded.load(Ordering::Relaxed),
+        bytes_uploaded: TOTALS.bytes_uploaded.load(Ordering::Relaxed),
+        objects_skipped: TOTALS.objects_skipped.load(Ordering::Relaxed),
+        failures: TOTALS.failures.load(Ordering::Relaxed),
+    }
+}
+
+impl Sub for Snapshot {
+    type Output = Snapshot;
+
+    fn sub(self, earlier: Snapshot) -> Snapshot {
+        Snapshot {
+            objects_listed: self.objects_listed - earlier.objects_listed,
+            objects_downloaded: self.objects_downloaded - earlier.objects_downloaded,
+            bytes_downloaded: self.bytes_downloaded - earlier.bytes_downloaded,
+            objects_uploaded: self.objects_uploaded - earlier.objects_uploaded,
+            bytes_uploaded: self.bytes_uploaded - earlier.bytes_uploaded,
+            objects_skipped: self.objects_skipped - earlier.objects_skipped,
+            failures: self.failures - earlier.failures,
+        }
+    }
+}
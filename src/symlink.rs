@@ -0,0 +1,118 @@
+use crate::shutdown::{PartialFile, PartialFiles};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+/// x-amz-meta-symlink-target, what the link pointed to. The object's body is the same, so it
+/// still makes sense to anything that doesn't know about the marker
+pub const METADATA_KEY: &str = "symlink-target";
+
+// Metadata has to be printable ASCII, anything else in the target is percent encoded
+const NOT_PRINTABLE: &AsciiSet = &CONTROLS.add(b'%').add(b' ');
+
+/// The target of the link an object stands for, None for an ordinary object
+pub fn target(metadata: Option<&HashMap<String, String>>) -> Option<String> {
+    let target = metadata?.get(METADATA_KEY)?;
+    Some(percent_decode_str(target).decode_utf8_lossy().to_string())
+}
+
+/// What `path` points to if it's a link itself, without following it
+pub async fn read(path: &Path) -> std::io::Result<Option<PathBuf>> {
+    if !tokio::fs::symlink_metadata(path).await?.is_symlink() {
+        return Ok(None);
+    }
+    Ok(Some(tokio::fs::read_link(path).await?))
+}
+
+/// The metadata value to upload a link to `target` with
+pub fn metadata_value(target: &Path) -> String {
+    utf8_percent_encode(&target.to_string_lossy(), NOT_PRINTABLE).to_string()
+}
+
+/// Files found through a linked directory, which gets uploaded as a link instead
+pub fn inside_link(root: &Path, path: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(root) else {
+        return false;
+    };
+    let mut current = root.to_path_buf();
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        current.push(component);
+        if components.peek().is_some() && current.is_symlink() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Whether a link at `path` to `target` would point outside `root`, so whatever is downloaded
+/// under the link later would be written there. Relative targets are fine so long as they go up
+/// no further than `root` and any ".." come first, after a link ".." is wherever it points. The
+/// directory `path` is in has to be there already
+pub async fn escapes(root: &Path, path: &Path, target: &str) -> std::io::Result<bool> {
+    let mut up = 0;
+    let mut down = false;
+    for component in Path::new(target).components() {
+        match component {
+            Component::ParentDir if !down => up += 1,
+            Component::Normal(_) => down = true,
+            Component::CurDir => {}
+            // Absolute, or a ".." after a name
+            _ => return Ok(true),
+        }
+    }
+    let root = tokio::fs::canonicalize(root).await?;
+    let parent = tokio::fs::canonicalize(path.parent().unwrap_or(Path::new("."))).await?;
+    Ok(match parent.strip_prefix(&root) {
+        Ok(inside) => up > inside.components().count(),
+        Err(_) => true,
+    })
+}
+
+/// Whether `path` would be written outside `root` by way of a link on the way to it, e.g. one
+/// from before links were checked
+pub async fn through_link(root: &Path, path: &Path) -> std::io::Result<bool> {
+    let root = match tokio::fs::canonicalize(root).await {
+        Ok(root) => root,
+        // Nothing's been downloaded yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    // The nearest directory that's there already, the rest is created in it
+    for ancestor in path.ancestors().skip(1) {
+        match tokio::fs::canonicalize(ancestor).await {
+            Ok(real) => return Ok(!real.starts_with(&root)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+/// Replaces whatever is at `path` with a link to `target`, the same way downloads are renamed
+/// into place
+pub async fn create(
+    target: &str,
+    path: &Path,
+    sync: bool,
+    partial_files: &PartialFiles,
+) -> std::io::Result<()> {
+    let partial = PartialFile::new(path, partial_files);
+    // Unlike a file, a link left over from before can't be written over
+    let _ = tokio::fs::remove_file(partial.part_path()).await;
+    symlink(target, partial.part_path()).await?;
+    partial.finish(sync).await
+}
+
+#[cfg(unix)]
+async fn symlink(target: &str, link: &Path) -> std::io::Result<()> {
+    tokio::fs::symlink(target, link).await
+}
+
+#[cfg(not(unix))]
+async fn symlink(_target: &str, _link: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "symlinks are only supported on Unix",
+    ))
+}
@@ -0,0 +1,88 @@
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::fmt;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// Logs to stdout (stderr for a command whose output goes there, --output json among them),
+/// and when OTEL_EXPORTER_OTLP_ENDPOINT is set also sends spans there over OTLP/HTTP. RUST_LOG
+/// picks what gets logged, our info messages by default. The provider has to be shut down before
+/// exiting to send the last spans
+pub fn init(stdout_taken: bool) -> Option<SdkTracerProvider> {
+    // The SDK logs a lot at info, only ours are wanted by default
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("warn,rust_s3_downloader=info"));
+    let writer = if stdout_taken {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let messages = tracing_subscriber::fmt::layer()
+        .event_format(Plain)
+        .with_writer(writer);
+
+    let provider = match std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(_) => match SpanExporter::builder().with_http().build() {
+            Ok(exporter) => Some(
+                SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(
+                        Resource::builder()
+                            .with_service_name(env!("CARGO_PKG_NAME"))
+                            .build(),
+                    )
+                    .build(),
+            ),
+            Err(e) => {
+                eprintln!("Not exporting traces, got an error setting up OTLP: {}", e);
+                None
+            }
+        },
+        Err(_) => None,
+    };
+    let otel = provider
+        .as_ref()
+        .map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer(env!("CARGO_PKG_NAME"))));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(messages)
+        .with(otel)
+        .init();
+    provider
+}
+
+pub fn shutdown(provider: Option<SdkTracerProvider>) {
+    if let Some(provider) = provider {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("Got an error sending the last traces: {}", e);
+        }
+    }
+}
+
+/// Just the message, the same as the output has always looked, spans are for tracing
+struct Plain;
+
+impl<S, N> FormatEvent<S, N> for Plain
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
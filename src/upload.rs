@@ -0,0 +1,239 @@
+use crate::bucket::arn_region;
+use crate::client::{create_client, ConnectionArgs};
+use crate::compress::Compression;
+use crate::concurrency::{Concurrency, ConcurrencyArgs};
+use crate::Error;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::Bytes;
+use clap::Args;
+use futures::stream::{self, TryStreamExt};
+use md5::{Digest, Md5};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc;
+use tokio_util::io::StreamReader;
+use tracing::{info, warn};
+
+// Parts start at this size and double every PARTS_PER_SIZE parts, so the most S3 allows (10,000)
+// holds a good deal more than anything likely to be piped in, without knowing its size up front
+const FIRST_PART_SIZE: usize = 8 * 1024 * 1024;
+const PARTS_PER_SIZE: i32 = 1000;
+
+// Parts uploaded at once, each one is held in memory until it's sent
+const CONCURRENT_PARTS: usize = 4;
+
+#[derive(Args)]
+pub struct UploadArgs {
+    #[arg(short, long)]
+    bucket: String,
+    #[arg(short, long)]
+    key: String,
+    file: PathBuf, // "-" reads stdin, e.g. pg_dump | rust-s3-downloader upload ... -
+    #[arg(long)]
+    tar: bool, // The file is a directory, sent as one tar archive of everything in it
+    #[arg(long, value_enum)]
+    compress: Option<Compression>, // Compress it on the way, e.g. --tar --compress zstd -k backup.tar.zst
+    #[arg(short, long)]
+    profile: Option<String>,
+    #[arg(short, long)]
+    region: Option<String>,
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+}
+
+/// Sends the input as it's read, in one PutObject if it fits in a part and a multipart upload
+/// otherwise, so it never has to be all in memory or on disk
+pub async fn upload(args: UploadArgs) -> Result<(), Error> {
+    let concurrency = Concurrency::new(&args.concurrency);
+    let region = args.region.or_else(|| arn_region(&args.bucket));
+    let client = create_client(region, args.profile, &args.connection, &concurrency).await;
+    let mut input: Box<dyn AsyncRead + Unpin + Send> = if args.tar {
+        if !args.file.is_dir() {
+            return Err(Error::IOError(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} isn't a directory", args.file.display()),
+            )));
+        }
+        tar_directory(args.file.clone())
+    } else if args.file.as_os_str() == "-" {
+        Box::new(tokio::io::stdin())
+    } else {
+        Box::new(tokio::fs::File::open(&args.file).await?)
+    };
+    if let Some(compression) = args.compress {
+        input = compression.reader(input);
+    }
+
+    let first = read_part(&mut input, part_size(1)).await?;
+    if first.len() < part_size(1) {
+        let bytes = first.len();
+        client
+            .put_object()
+            .bucket(&args.bucket)
+            .key(&args.key)
+            .content_md5(content_md5(&first))
+            .body(first.into())
+            .send()
+            .await?;
+        info!("Uploaded {} bytes to {}", bytes, args.key);
+        return Ok(());
+    }
+
+    let upload_id = client
+        .create_multipart_upload()
+        .bucket(&args.bucket)
+        .key(&args.key)
+        .send()
+        .await?
+        .upload_id
+        .unwrap_or_default();
+    match upload_parts(&client, &args.bucket, &args.key, &upload_id, first, input).await {
+        Ok(bytes) => {
+            info!("Uploaded {} bytes to {}", bytes, args.key);
+            Ok(())
+        }
+        Err(e) => {
+            // Otherwise the parts sent so far are kept (and charged for) until a lifecycle rule
+            // clears them up
+            if let Err(abort_error) = client
+                .abort_multipart_upload()
+                .bucket(&args.bucket)
+                .key(&args.key)
+                .upload_id(&upload_id)
+                .send()
+                .await
+            {
+                warn!(
+                    "Couldn't abort the upload of {}, its parts are still there: {}",
+                    args.key, abort_error
+                );
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn upload_parts(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    first: Bytes,
+    input: Box<dyn AsyncRead + Unpin + Send>,
+) -> Result<u64, Error> {
+    // Each part is read once the one before it has been, then up to CONCURRENT_PARTS of them
+    // are sent at a time
+    let parts = stream::try_unfold(
+        (input, Some(first), 1),
+        |(mut input, first, number)| async move {
+            let data = match first {
+                Some(first) => first,
+                None => read_part(&mut input, part_size(number)).await?,
+            };
+            if data.is_empty() {
+                return Ok::<_, Error>(None);
+            }
+            Ok(Some(((number, data), (input, None, number + 1))))
+        },
+    );
+    let completed: Vec<(CompletedPart, usize)> = parts
+        .map_ok(|(number, data)| async move {
+            let output = client
+                .upload_part()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(number)
+                .content_md5(content_md5(&data))
+                .body(data.clone().into())
+                .send()
+                .await?;
+            let part = CompletedPart::builder()
+                .part_number(number)
+                .set_e_tag(output.e_tag)
+                .build();
+            Ok((part, data.len()))
+        })
+        .try_buffered(CONCURRENT_PARTS)
+        .try_collect()
+        .await?;
+
+    let bytes = completed.iter().map(|(_, len)| *len as u64).sum();
+    let parts = completed.into_iter().map(|(part, _)| part).collect();
+    client
+        .complete_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .upload_id(upload_id)
+        .multipart_upload(
+            CompletedMultipartUpload::builder()
+                .set_parts(Some(parts))
+                .build(),
+        )
+        .send()
+        .await?;
+    Ok(bytes)
+}
+
+/// A tar archive of everything under `dir`, written as it's read so it's never all on disk or in
+/// memory. Paths in it are relative to `dir`, and links are archived as links
+fn tar_directory(dir: PathBuf) -> Box<dyn AsyncRead + Unpin + Send> {
+    let (sender, mut receiver) = mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        let errors = sender.clone();
+        let result = (|| {
+            let writer = BufWriter::with_capacity(1024 * 1024, ChannelWriter(sender));
+            let mut builder = tar::Builder::new(writer);
+            builder.follow_symlinks(false);
+            builder.append_dir_all(".", &dir)?;
+            builder.into_inner()?.flush()
+        })();
+        // Failing part way has to fail the upload, rather than look like the end of the archive
+        if let Err(e) = result {
+            let _ = errors.blocking_send(Err(e));
+        }
+    });
+    Box::new(StreamReader::new(stream::poll_fn(move |cx| {
+        receiver.poll_recv(cx)
+    })))
+}
+
+// Hands what the tar builder writes to the upload, waiting while it's behind
+struct ChannelWriter(mpsc::Sender<std::io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "the upload stopped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn part_size(number: i32) -> usize {
+    FIRST_PART_SIZE << ((number - 1) / PARTS_PER_SIZE)
+}
+
+// Up to `size` bytes, fewer only at the end of the input
+async fn read_part(
+    input: &mut (dyn AsyncRead + Unpin + Send),
+    size: usize,
+) -> Result<Bytes, Error> {
+    let mut data = Vec::with_capacity(size);
+    input.take(size as u64).read_to_end(&mut data).await?;
+    Ok(Bytes::from(data))
+}
+
+// S3 rejects a part if what arrives doesn't hash to this
+fn content_md5(data: &[u8]) -> String {
+    BASE64_STANDARD.encode(Md5::digest(data))
+}
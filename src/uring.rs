@@ -0,0 +1,75 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tokio::sync::{mpsc, oneshot};
+use tokio_uring::buf::IoBuf;
+
+// Started by the first write, tokio-uring needs a runtime of its own
+static WRITER: OnceLock<mpsc::UnboundedSender<Job>> = OnceLock::new();
+
+struct Job {
+    path: PathBuf,
+    data: Vec<u8>,
+    done: oneshot::Sender<(Vec<u8>, std::io::Result<()>)>,
+}
+
+fn writer() -> &'static mpsc::UnboundedSender<Job> {
+    WRITER.get_or_init(|| {
+        let (sender, mut jobs) = mpsc::unbounded_channel::<Job>();
+        std::thread::spawn(move || {
+            tokio_uring::start(async move {
+                while let Some(job) = jobs.recv().await {
+                    tokio_uring::spawn(async move {
+                        let (data, written) = write_file(&job.path, job.data).await;
+                        let _ = job.done.send((data, written));
+                    });
+                }
+            })
+        });
+        sender
+    })
+}
+
+/// Writes `data` to a new file at `path` with io_uring rather than tokio's blocking threads, the
+/// writes of every transfer going through one ring. Handed back along with the file, reopened
+/// for what's done to it afterwards
+pub async fn write(path: &Path, data: Vec<u8>) -> (Vec<u8>, std::io::Result<tokio::fs::File>) {
+    let (done, written) = oneshot::channel();
+    let job = Job {
+        path: path.to_path_buf(),
+        data,
+        done,
+    };
+    // Either end going away means the ring couldn't be set up, e.g. a kernel older than 5.10 or
+    // a seccomp profile that blocks it
+    let unavailable = || std::io::Error::other("io_uring isn't available");
+    if let Err(mpsc::error::SendError(job)) = writer().send(job) {
+        return (job.data, Err(unavailable()));
+    }
+    match written.await {
+        Ok((data, Ok(()))) => {
+            let file = tokio::fs::OpenOptions::new().write(true).open(path).await;
+            (data, file)
+        }
+        Ok((data, Err(e))) => (data, Err(e)),
+        Err(_) => (Vec::new(), Err(unavailable())),
+    }
+}
+
+async fn write_file(path: &Path, mut data: Vec<u8>) -> (Vec<u8>, std::io::Result<()>) {
+    let file = match tokio_uring::fs::File::create(path).await {
+        Ok(file) => file,
+        Err(e) => return (data, Err(e)),
+    };
+    let mut written = 0;
+    while written < data.len() {
+        let (result, slice) = file.write_at(data.slice(written..), written as u64).await;
+        data = slice.into_inner();
+        match result {
+            Ok(0) => return (data, Err(std::io::ErrorKind::WriteZero.into())),
+            Ok(n) => written += n,
+            Err(e) => return (data, Err(e)),
+        }
+    }
+    let closed = file.close().await;
+    (data, closed)
+}
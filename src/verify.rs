@@ -0,0 +1,230 @@
+use crate::bucket::local_bucket_dir;
+use crate::client::{create_client, ConnectionArgs};
+use crate::concurrency::{Concurrency, ConcurrencyArgs};
+use crate::local_names::LocalNames;
+use crate::{
+    etag, list_prefixes, shutdown, sidecar, symlink, Cli, DownloadArgs, Error, ListOptions,
+    SourceArgs,
+};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory};
+use glob::glob;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    #[command(flatten)]
+    download: DownloadArgs,
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+    #[arg(long)]
+    checksums: bool, // Also compare each file with its object's ETag, reading every file
+}
+
+impl VerifyArgs {
+    /// Files are looked for under their keys, anything saved under another name or with other
+    /// contents would show up as both missing and extra. --flatten is worked out the same way as
+    /// when downloading
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        // Every file past the limit would be taken for one without an object
+        if self.source.limit.is_some() {
+            return Err(Cli::command().error(
+                ErrorKind::ArgumentConflict,
+                "--limit can't be used with verify, the bucket has to be listed in full",
+            ));
+        }
+        match self.download.reshaping_args().find(|arg| *arg != "--flatten") {
+            Some(arg) => Err(Cli::command().error(
+                ErrorKind::ArgumentConflict,
+                format!("{arg} can't be used with verify, files are compared under their keys as they are"),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Nothing is transferred, the differences are printed and it fails if there are any
+pub async fn verify(args: VerifyArgs) -> Result<(), Error> {
+    let VerifyArgs {
+        source,
+        download,
+        connection,
+        concurrency,
+        checksums,
+    } = args;
+
+    info!("Setting up AWS download client...");
+    let concurrency = Concurrency::new(&concurrency);
+    let client = create_client(
+        source.client_region(),
+        source.profile.clone(),
+        &connection,
+        &concurrency,
+    )
+    .await;
+
+    info!("Obtaining list of {} objects...", source.bucket);
+    let objects = list_prefixes(
+        &client,
+        &source.bucket,
+        &source.prefix,
+        source.list_options(),
+    )
+    .await?;
+    let names = LocalNames::find(
+        &objects,
+        download.flatten,
+        download.case_collisions,
+        download.prefix_conflicts,
+    )?;
+    // Directory markers have nothing to compare with
+    let remote: BTreeMap<String, (u64, Option<String>)> = objects
+        .into_iter()
+        .filter_map(|o| {
+            let key = o.key?;
+            let size = o.size.unwrap_or_default().max(0) as u64;
+            (!key.ends_with('/')).then_some((key, (size, o.e_tag)))
+        })
+        .collect();
+    info!("Found {} objects", remote.len());
+
+    let dir = PathBuf::from(&download.download_path).join(local_bucket_dir(&source.bucket));
+    info!("Walking {}...", dir.display());
+    let local = local_files(&dir, &source.prefix, source.list_options(), &download)?;
+    info!("Found {} local files", local.len());
+
+    let mut missing = 0;
+    let mut mismatched = 0;
+    // What each key was saved as, those left out of the download aren't looked for
+    let expected: BTreeMap<&str, &str> = remote
+        .keys()
+        .filter_map(|key| Some((key.as_str(), names.get(key)?)))
+        .collect();
+    for (key, (size, etag)) in &remote {
+        let Some(name) = expected.get(key.as_str()) else {
+            continue;
+        };
+        let Some(local_size) = local.get(*name) else {
+            warn!("Missing: {}", key);
+            missing += 1;
+            continue;
+        };
+        if local_size != size {
+            warn!(
+                "Size mismatch: {} ({} bytes in the bucket, {} locally)",
+                key, size, local_size
+            );
+            mismatched += 1;
+            continue;
+        }
+        let Some(etag) = etag.as_deref().map(|e| e.trim_matches('"')) else {
+            continue;
+        };
+        // A link's object holds its target, which the size already covers
+        if !checksums || (download.symlinks && dir.join(name).is_symlink()) {
+            continue;
+        }
+        let part_size = match etag::parts(etag) {
+            Some(_) => match etag::part_size(&client, &source.bucket, key).await {
+                Ok(part_size) => Some(part_size),
+                Err(e) => {
+                    error!("Got an error finding the part size of {}: {}", key, e);
+                    mismatched += 1;
+                    continue;
+                }
+            },
+            None => None,
+        };
+        match etag::local_etag(&dir.join(name), part_size).await {
+            Ok(local_etag) if local_etag == etag => {}
+            Ok(local_etag) => {
+                warn!(
+                    "Checksum mismatch: {} (ETag {}, local {})",
+                    key, etag, local_etag
+                );
+                mismatched += 1;
+            }
+            Err(e) => {
+                error!("Got an error reading {}: {}", key, e);
+                mismatched += 1;
+            }
+        }
+    }
+    let names: HashSet<&str> = expected.into_values().collect();
+    let extra: Vec<&String> = local
+        .keys()
+        .filter(|name| !names.contains(name.as_str()))
+        .collect();
+    for name in &extra {
+        if shutdown::is_part_file(Path::new(name)) {
+            warn!("Extra: {} (left by a download that didn't finish)", name);
+        } else {
+            warn!("Extra: {}", name);
+        }
+    }
+
+    info!(
+        "{} objects checked: {} missing, {} extra, {} mismatched",
+        remote.len(),
+        missing,
+        extra.len(),
+        mismatched
+    );
+    match missing + extra.len() + mismatched {
+        0 => Ok(()),
+        differences => Err(Error::VerifyFailed(differences)),
+    }
+}
+
+/// Every file under `dir` by its path from there, with "/" between directories like keys, and
+/// its size. Only those listing `prefixes` with `options` would have an object for
+pub fn local_files(
+    dir: &Path,
+    prefixes: &[String],
+    options: ListOptions,
+    download: &DownloadArgs,
+) -> Result<BTreeMap<String, u64>, Error> {
+    let mut files = BTreeMap::new();
+    if !dir.exists() {
+        return Ok(files);
+    }
+    let pattern = format!("{}/**/*", glob::Pattern::escape(&dir.to_string_lossy()));
+    let paths = glob(&pattern).map_err(|e| Error::IOError(std::io::Error::other(e)))?;
+    for path in paths {
+        // Rather than leaving out what's in a directory that can't be read, which would make it
+        // all look deleted
+        let path = path.map_err(|e| Error::IOError(e.into()))?;
+        if download.symlinks && symlink::inside_link(dir, &path) {
+            continue;
+        }
+        let size = match path.read_link() {
+            // The object for a link holds its target
+            Ok(target) if download.symlinks => target.as_os_str().len() as u64,
+            _ => {
+                let Ok(metadata) = path.metadata() else {
+                    continue;
+                };
+                if !metadata.is_file() || (download.metadata_sidecars && sidecar::is_sidecar(&path))
+                {
+                    continue;
+                }
+                metadata.len()
+            }
+        };
+        let Some(name) = path.strip_prefix(dir).ok().and_then(|k| k.to_str()) else {
+            continue;
+        };
+        let name = name.replace(std::path::MAIN_SEPARATOR, "/");
+        if !options.covers(prefixes, &name) {
+            continue;
+        }
+        files.insert(name, size);
+    }
+    Ok(files)
+}
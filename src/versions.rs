@@ -0,0 +1,446 @@
+use crate::{
+    bucket::is_directory_bucket,
+    check_stopped,
+    client::{create_client, ConnectionArgs},
+    concurrency::{Concurrency, ConcurrencyArgs},
+    context::Context,
+    decoded_key,
+    events::{Direction, Events},
+    local_names::LocalNames,
+    process_object, shutdown, successful_outcomes, DownloadArgs, Error, ListOptions, Outcome,
+    SourceArgs,
+};
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
+use aws_sdk_s3::types::{DeleteMarkerEntry, Object, ObjectVersion};
+use aws_sdk_s3::Client;
+use clap::Args;
+use futures::future::join_all;
+use std::collections::{HashMap, HashSet};
+use tracing::{info, instrument, Instrument};
+
+#[derive(Args)]
+pub struct RestoreArgs {
+    #[arg(long, value_parser = parse_timestamp)]
+    as_of: DateTime, // RFC 3339, e.g. 2024-05-01T09:30:00Z, or just a date for midnight UTC
+    #[command(flatten)]
+    source: SourceArgs,
+    #[command(flatten)]
+    download: DownloadArgs,
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    concurrency: ConcurrencyArgs,
+}
+
+#[derive(Debug, Default)]
+pub struct VersionListing {
+    pub versions: Vec<ObjectVersion>,
+    pub delete_markers: Vec<DeleteMarkerEntry>,
+}
+
+fn parse_timestamp(s: &str) -> Result<DateTime, String> {
+    DateTime::from_str(s, DateTimeFormat::DateTime)
+        .or_else(|_| DateTime::from_str(&format!("{s}T00:00:00Z"), DateTimeFormat::DateTime))
+        .map_err(|e| format!("expected an RFC 3339 timestamp or date: {e}"))
+}
+
+/// Local name for a specific version of a key, e.g. `logs/a.txt@3HL4kqtJlcpXroDTDmJ+rmSpXd3dIbrHY`
+pub fn versioned_key(key: &str, version_id: &str) -> String {
+    format!("{key}@{version_id}")
+}
+
+#[instrument(skip_all, fields(bucket = %args.source.bucket))]
+pub async fn restore(args: RestoreArgs, events: Events) -> Result<(), Error> {
+    let RestoreArgs {
+        as_of,
+        source,
+        download,
+        connection,
+        concurrency,
+    } = args;
+
+    if is_directory_bucket(&source.bucket) {
+        info!("Directory buckets don't support versioning, there is no history to restore");
+        return Ok(());
+    }
+
+    info!("Setting up AWS download client...");
+    let concurrency = Concurrency::new(&concurrency);
+    let context = Context::new(download, &source, concurrency.clone(), events)?;
+    shutdown::handle_signals(concurrency.clone(), context.partial_files.clone());
+    let client = create_client(
+        source.client_region(),
+        source.profile.clone(),
+        &connection,
+        &concurrency,
+    )
+    .await;
+
+    info!("Obtaining list of {} object versions...", source.bucket);
+    let listing = list_prefix_versions(
+        &client,
+        &source.bucket,
+        &source.prefix,
+        source.list_options(),
+    )
+    .await?;
+    info!(
+        "Found {} object versions and {} delete markers",
+        listing.versions.len(),
+        listing.delete_markers.len()
+    );
+
+    let as_of_str = as_of
+        .fmt(DateTimeFormat::DateTime)
+        .unwrap_or_else(|_| as_of.to_string());
+    info!("Working out the state of the bucket at {}...", as_of_str);
+    let snapshot = versions_as_of(&listing, as_of);
+    info!("{} objects existed at {}", snapshot.len(), as_of_str);
+
+    let targets = restore_targets(&listing, snapshot, &context.download)?;
+    let result = download_versions(&client, &source.bucket, targets, &context).await;
+    context.finish();
+    result
+}
+
+/// The `(key, version id, local key)` of each version in `snapshot`, named and ordered the same
+/// way as when downloading the objects. Those left out by --prefix-conflicts skip aren't included
+fn restore_targets(
+    listing: &VersionListing,
+    mut snapshot: HashMap<String, String>,
+    download: &DownloadArgs,
+) -> Result<Vec<(String, String, String)>, Error> {
+    let mut objects: Vec<Object> = listing
+        .versions
+        .iter()
+        .filter(|v| {
+            let version_id = v.version_id().unwrap_or("null");
+            v.key()
+                .is_some_and(|key| snapshot.get(key).is_some_and(|id| id == version_id))
+        })
+        .map(|v| {
+            Object::builder()
+                .set_key(v.key.clone())
+                .set_size(v.size)
+                .build()
+        })
+        .collect();
+    let names = LocalNames::find(
+        &objects,
+        download.flatten,
+        download.case_collisions,
+        download.prefix_conflicts,
+    )?;
+    download.order.sort_objects(&mut objects);
+    Ok(objects
+        .into_iter()
+        .filter_map(|o| {
+            let key = o.key?;
+            let local_key = names.get(&key)?.to_string();
+            // A tie listed twice only goes once
+            let version_id = snapshot.remove(&key)?;
+            Some((key, version_id, local_key))
+        })
+        .collect())
+}
+
+/// For each key, the id of the version that was current at `as_of`. Keys that didn't exist
+/// yet, or whose most recent change before `as_of` was a delete, are left out
+fn versions_as_of(listing: &VersionListing, as_of: DateTime) -> HashMap<String, String> {
+    // key -> (last modified, version id, is a delete marker)
+    let mut latest: HashMap<&str, (DateTime, &str, bool)> = HashMap::new();
+
+    let versions = listing
+        .versions
+        .iter()
+        .map(|v| (v.key(), v.version_id(), v.last_modified(), false));
+    let delete_markers = listing
+        .delete_markers
+        .iter()
+        .map(|m| (m.key(), m.version_id(), m.last_modified(), true));
+
+    for (key, version_id, last_modified, is_delete) in versions.chain(delete_markers) {
+        let (Some(key), Some(last_modified)) = (key, last_modified) else {
+            continue;
+        };
+        if *last_modified > as_of {
+            continue;
+        }
+        let version_id = version_id.unwrap_or("null");
+        // Versions are listed newest first so on a tie the first one seen wins
+        match latest.get(key) {
+            Some((seen, _, _)) if seen >= last_modified => {}
+            _ => {
+                latest.insert(key, (*last_modified, version_id, is_delete));
+            }
+        }
+    }
+
+    latest
+        .into_iter()
+        .filter(|(_, (_, _, is_delete))| !is_delete)
+        .map(|(key, (_, version_id, _))| (key.to_string(), version_id.to_string()))
+        .collect()
+}
+
+pub async fn list_prefix_versions(
+    client: &Client,
+    bucket: &str,
+    prefixes: &[String],
+    options: ListOptions,
+) -> Result<VersionListing, Error> {
+    if prefixes.is_empty() {
+        return list_all_versions(client, bucket, None, options).await;
+    }
+
+    let mut seen = HashSet::new();
+    let mut listing = VersionListing::default();
+    for prefix in prefixes {
+        let remaining = options
+            .limit
+            .map(|l| l.saturating_sub(listing.versions.len()));
+        if remaining == Some(0) {
+            break;
+        }
+        info!("Listing versions in {}/{}...", bucket, prefix);
+        let prefix_options = ListOptions {
+            limit: remaining,
+            ..options
+        };
+        let prefix_listing =
+            list_all_versions(client, bucket, Some(prefix.clone()), prefix_options).await?;
+        for version in prefix_listing.versions {
+            if seen.insert((version.key.clone(), version.version_id.clone())) {
+                listing.versions.push(version);
+            }
+        }
+        for marker in prefix_listing.delete_markers {
+            if seen.insert((marker.key.clone(), marker.version_id.clone())) {
+                listing.delete_markers.push(marker);
+            }
+        }
+    }
+
+    Ok(listing)
+}
+
+async fn list_all_versions(
+    client: &Client,
+    bucket: &str,
+    prefix: Option<String>,
+    options: ListOptions,
+) -> Result<VersionListing, Error> {
+    let mut key_marker: Option<String> = None;
+    let mut version_id_marker: Option<String> = None;
+    let mut listing = VersionListing::default();
+
+    loop {
+        let resp = client
+            .list_object_versions()
+            .bucket(bucket)
+            .set_prefix(prefix.clone())
+            .set_max_keys(options.page_size(listing.versions.len()))
+            .set_delimiter(options.top_level.then(|| "/".to_string()))
+            .set_key_marker(key_marker)
+            .set_version_id_marker(version_id_marker)
+            .send()
+            .await?;
+
+        let prefix = prefix.as_deref().unwrap_or_default();
+        for version in resp.versions() {
+            if options.within_depth(prefix, version.key().unwrap_or_default()) {
+                listing.versions.push(version.clone())
+            }
+        }
+        for marker in resp.delete_markers() {
+            if options.within_depth(prefix, marker.key().unwrap_or_default()) {
+                listing.delete_markers.push(marker.clone())
+            }
+        }
+
+        if let Some(limit) = options.limit {
+            if listing.versions.len() >= limit {
+                listing.versions.truncate(limit);
+                break;
+            }
+        }
+
+        if resp.is_truncated.unwrap_or_default() {
+            key_marker = resp.next_key_marker().map(|s| s.to_string());
+            version_id_marker = resp.next_version_id_marker().map(|s| s.to_string());
+            continue;
+        }
+        break;
+    }
+
+    Ok(listing)
+}
+
+pub async fn download_all_versions(
+    client: &Client,
+    bucket: &str,
+    versions: Vec<ObjectVersion>,
+    context: &Context,
+) -> Result<(), Error> {
+    let targets = versions
+        .into_iter()
+        .map(|version| {
+            // Unversioned objects in a bucket that later had versioning enabled have the version "null"
+            let key = version.key.unwrap_or_default();
+            let version_id = version.version_id.unwrap_or_else(|| "null".to_string());
+            let local_key = versioned_key(&key, &version_id);
+            (key, version_id, local_key)
+        })
+        .collect();
+    download_versions(client, bucket, targets, context).await
+}
+
+/// Downloads each `(key, version id, local key)`
+async fn download_versions(
+    client: &Client,
+    bucket: &str,
+    targets: Vec<(String, String, String)>,
+    context: &Context,
+) -> Result<(), Error> {
+    let local_keys: Vec<&str> = targets
+        .iter()
+        .map(|(_, _, local_key)| decoded_key(&context.download, local_key))
+        .collect();
+    context.paths.sanitizer().reserve(&local_keys);
+    let mut tasks = Vec::new();
+
+    for (key, version_id, local_key) in targets {
+        let client = client.clone();
+        let bucket = bucket.to_string();
+        let context = context.clone();
+        tasks.push(tokio::spawn(
+            async move {
+                let Some(_permit) = context.concurrency.acquire(Direction::Download).await else {
+                    return Ok(Outcome::NotAttempted);
+                };
+                process_object(
+                    &client,
+                    &bucket,
+                    &key,
+                    Some(&version_id),
+                    &local_key,
+                    None,
+                    &context,
+                )
+                .await
+            }
+            .in_current_span(),
+        ));
+    }
+    let outcomes = successful_outcomes(join_all(tasks).await);
+    check_stopped(&context.concurrency, &outcomes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(key: &str, version_id: Option<&str>, secs: i64) -> ObjectVersion {
+        ObjectVersion::builder()
+            .key(key)
+            .set_version_id(version_id.map(str::to_string))
+            .last_modified(DateTime::from_secs(secs))
+            .build()
+    }
+
+    fn sized(key: &str, version_id: &str, size: i64) -> ObjectVersion {
+        ObjectVersion::builder()
+            .key(key)
+            .version_id(version_id)
+            .size(size)
+            .last_modified(DateTime::from_secs(100))
+            .build()
+    }
+
+    fn delete_marker(key: &str, version_id: &str, secs: i64) -> DeleteMarkerEntry {
+        DeleteMarkerEntry::builder()
+            .key(key)
+            .version_id(version_id)
+            .last_modified(DateTime::from_secs(secs))
+            .build()
+    }
+
+    #[test]
+    fn versions_have_their_own_names() {
+        assert_eq!(versioned_key("logs/a.txt", "3HL4kq"), "logs/a.txt@3HL4kq");
+        assert_eq!(versioned_key("a", "null"), "a@null");
+    }
+
+    #[test]
+    fn versions_current_at_a_time() {
+        let listing = VersionListing {
+            versions: vec![
+                version("a", Some("a2"), 200),
+                version("a", Some("a1"), 100),
+                version("b", None, 100),
+                version("c", Some("c1"), 300),
+                version("d", Some("d2"), 150),
+                version("d", Some("d1"), 150),
+            ],
+            delete_markers: vec![delete_marker("b", "b-deleted", 120)],
+        };
+        let at = |secs| versions_as_of(&listing, DateTime::from_secs(secs));
+
+        let state = at(150);
+        assert_eq!(state.get("a").map(String::as_str), Some("a1"));
+        // Deleted before then, and not created yet
+        assert!(!state.contains_key("b"));
+        assert!(!state.contains_key("c"));
+        // Listed newest first, so on a tie that's the one
+        assert_eq!(state.get("d").map(String::as_str), Some("d2"));
+
+        let state = at(110);
+        assert_eq!(state.get("b").map(String::as_str), Some("null"));
+        assert_eq!(at(1000).get("a").map(String::as_str), Some("a2"));
+        assert!(at(50).is_empty());
+    }
+
+    #[test]
+    fn restored_versions_are_named_and_ordered_like_downloads() {
+        use crate::Cli;
+        use clap::Parser;
+
+        let listing = VersionListing {
+            versions: vec![
+                sized("a/b", "b2", 30),
+                sized("a/b", "b1", 5),
+                sized("a/b/c", "c1", 10),
+                sized("d", "d1", 20),
+            ],
+            delete_markers: Vec::new(),
+        };
+        let snapshot: HashMap<String, String> = [("a/b", "b2"), ("a/b/c", "c1"), ("d", "d1")]
+            .map(|(key, id)| (key.to_string(), id.to_string()))
+            .into();
+        let download = |args: &[&str]| {
+            let cli = Cli::parse_from([&["s3dl", "-b", "bucket"], args].concat());
+            cli.download
+        };
+
+        let targets = restore_targets(
+            &listing,
+            snapshot.clone(),
+            &download(&["--order", "smallest-first"]),
+        )
+        .unwrap();
+        let keys: Vec<&str> = targets.iter().map(|(key, _, _)| key.as_str()).collect();
+        assert_eq!(keys, ["a/b/c", "d", "a/b"]);
+        // a/b is also the directory a/b/c is in
+        let (_, version_id, local_key) = &targets[2];
+        assert_eq!(version_id, "b2");
+        assert_ne!(local_key, "a/b");
+
+        let targets = restore_targets(
+            &listing,
+            snapshot,
+            &download(&["--prefix-conflicts", "skip"]),
+        )
+        .unwrap();
+        assert!(targets.iter().all(|(key, _, _)| key != "a/b"));
+    }
+}